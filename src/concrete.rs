@@ -9,7 +9,7 @@ use itertools::Itertools;
 use crate::{
     util::nd_index_iter,
     values::{
-        simplify::SimplificationContext, BuiltinOp, BuiltinType, LocalPtr, Statement, Value,
+        simplify::SimplificationContext, BuiltinOp, BuiltinType, Index, LocalPtr, Statement, Value,
         ValuePtr,
     },
 };
@@ -27,27 +27,95 @@ pub enum AxisBroadcastBehavior {
 
 pub type BroadcastBehavior = Vec<AxisBroadcastBehavior>;
 
+/// What to do when an index computed at runtime is outside the dimension it
+/// indexes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutOfBoundsPolicy {
+    /// Use the first or last element, whichever is closer.
+    Clamp,
+    /// Use the index modulo the size of the dimension, so that -1 is the last
+    /// element.
+    Wrap,
+}
+
 #[derive(Clone, Debug)]
 pub enum ConcreteValue {
     IntLiteral(i32),
     FloatLiteral(f32),
     BoolLiteral(bool),
-    Unvectorize(ConcreteValuePtr, u8),
-    Vectorize([ConcreteValuePtr; 8]),
+    /// The scalar in one lane of a vector.
+    Unvectorize(ConcreteValuePtr, usize),
+    /// A vector made of one scalar for each lane.
+    Vectorize(Vec<ConcreteValuePtr>),
     VectorizeByDuplication(ConcreteValuePtr),
-    InputScalar { input: usize, position: usize },
-    InputVector { input: usize, position: usize },
+    InputScalar {
+        input: usize,
+        position: usize,
+    },
+    InputVector {
+        input: usize,
+        position: usize,
+    },
     UnaryOp(UnaryOp, ConcreteValuePtr),
     BinaryOp(BinaryOp, ConcreteValuePtr, ConcreteValuePtr),
+    /// Brings an Int (or vector of Ints) into the range `0..size` according
+    /// to the policy.
+    BoundIndex(ConcreteValuePtr, usize, OutOfBoundsPolicy),
+    /// Reads the scalar at a flattened position in `source` given by an Int
+    /// which is known to be in bounds.
+    DynamicLoad {
+        source: ConcreteMultiValue,
+        index: ConcreteValuePtr,
+    },
+    /// Like `DynamicLoad`, but reads a whole vector of scalars at once using a
+    /// vector of positions.
+    Gather {
+        source: ConcreteMultiValue,
+        indices: ConcreteValuePtr,
+    },
+    /// Chooses `if_true` where the condition is true and `if_false` elsewhere.
+    /// Works on scalars or on vectors, lane by lane.
+    Select {
+        condition: ConcreteValuePtr,
+        if_true: ConcreteValuePtr,
+        if_false: ConcreteValuePtr,
+    },
+    /// Stands for `iterations` consecutive vectors, where vector k is `body`
+    /// evaluated on iteration k. Used instead of one component per vector when
+    /// an elementwise operation covers a large array.
+    Loop {
+        iterations: usize,
+        body: ConcreteValuePtr,
+    },
+    /// Inside the body of a loop, the vector another loop produces on iteration
+    /// `offset + k` when this loop is on iteration k.
+    LoopElement {
+        source: ConcreteValuePtr,
+        offset: usize,
+    },
+    /// Inside the body of a loop, the vector of an input starting at position
+    /// `lanes * k` on iteration k.
+    LoopInputVector(usize),
+    /// The vector a loop produces on one of its iterations.
+    Unloop(ConcreteValuePtr, usize),
 }
 
 impl ConcreteValue {
     pub fn is_vector(&self) -> bool {
         match self {
-            Self::Vectorize(..) => true,
+            Self::Vectorize(..) | Self::VectorizeByDuplication(..) => true,
             Self::InputVector { .. } => true,
+            Self::Loop { .. } | Self::LoopElement { .. } | Self::LoopInputVector(..) => true,
+            Self::Unloop(..) => true,
             Self::UnaryOp(_, rhs) => rhs.0.is_vector(),
             Self::BinaryOp(_, lhs, rhs) => lhs.0.is_vector() || rhs.0.is_vector(),
+            Self::BoundIndex(index, ..) => index.0.is_vector(),
+            Self::Gather { .. } => true,
+            Self::Select {
+                condition,
+                if_true,
+                if_false,
+            } => condition.0.is_vector() || if_true.0.is_vector() || if_false.0.is_vector(),
             _ => false,
         }
     }
@@ -55,6 +123,16 @@ impl ConcreteValue {
     pub fn is_scalar(&self) -> bool {
         !self.is_vector()
     }
+
+    /// How many scalars this value is made of when it is a component of a
+    /// `ConcreteMultiValue` whose vectors have the given number of lanes.
+    pub fn width(&self, lanes: usize) -> usize {
+        match self {
+            Self::Loop { iterations, .. } => lanes * iterations,
+            _ if self.is_vector() => lanes,
+            _ => 1,
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -114,6 +192,22 @@ impl Add for ConcreteScalarType {
 pub struct ConcreteProgram {
     inputs: Vec<ConcreteType>,
     outputs: Vec<ConcreteMultiValue>,
+    lanes: usize,
+}
+
+impl ConcreteProgram {
+    /// How many scalars each vector in the program is made of.
+    pub fn lanes(&self) -> usize {
+        self.lanes
+    }
+
+    pub fn inputs(&self) -> &[ConcreteType] {
+        &self.inputs
+    }
+
+    pub fn outputs(&self) -> &[ConcreteMultiValue] {
+        &self.outputs
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -122,6 +216,7 @@ pub enum UnaryOp {
     BoolToInt,
     BoolToFloat,
     Not,
+    Neg,
     Noop,
 }
 
@@ -132,6 +227,7 @@ pub enum BinaryOp {
     Mul,
     Div,
     Rem,
+    Pow,
     Gt,
     Lt,
     Gte,
@@ -160,7 +256,7 @@ impl Deref for ConcreteValuePtr {
     type Target = ConcreteValue;
 
     fn deref(&self) -> &Self::Target {
-        &*self.0
+        &self.0
     }
 }
 
@@ -168,50 +264,162 @@ impl Deref for ConcreteValuePtr {
 pub struct ConcreteMultiValue {
     typee: ConcreteType,
     components: Vec<ConcreteValuePtr>,
+    /// How many scalars each vector component is made of.
+    lanes: usize,
 }
 
 impl ConcreteMultiValue {
+    pub fn typee(&self) -> &ConcreteType {
+        &self.typee
+    }
+
+    pub fn components(&self) -> &[ConcreteValuePtr] {
+        &self.components
+    }
+
     pub fn get_scalar(&self, mut position: usize) -> ConcreteValuePtr {
+        let lanes = self.lanes;
         for component in &self.components {
-            if component.is_scalar() {
-                if position == 0 {
-                    return component.ptr_clone();
-                } else {
-                    position -= 1;
-                }
-            } else {
-                if position < 8 {
-                    return ConcreteValuePtr::new(ConcreteValue::Unvectorize(
-                        component.ptr_clone(),
-                        position as _,
-                    ));
-                } else {
-                    position -= 8;
-                }
+            let width = component.width(lanes);
+            if position >= width {
+                position -= width;
+                continue;
             }
+            return match &**component {
+                _ if width == 1 => component.ptr_clone(),
+                ConcreteValue::Vectorize(lanes) => lanes[position].ptr_clone(),
+                ConcreteValue::Loop { .. } => ConcreteValuePtr::new(ConcreteValue::Unvectorize(
+                    unloop(component, position / lanes),
+                    position % lanes,
+                )),
+                _ => ConcreteValuePtr::new(ConcreteValue::Unvectorize(
+                    component.ptr_clone(),
+                    position,
+                )),
+            };
         }
         panic!("Position {} is out of bounds.", position)
     }
 
     pub fn get_vector(&self, mut position: usize) -> Option<ConcreteValuePtr> {
+        let lanes = self.lanes;
         for component in &self.components {
-            if component.is_scalar() {
-                if position == 0 {
-                    return None;
-                } else {
-                    position -= 1;
+            let width = component.width(lanes);
+            if position >= width {
+                position -= width;
+                continue;
+            }
+            return match &**component {
+                _ if width == 1 => None,
+                ConcreteValue::Loop { .. } if position % lanes == 0 => {
+                    Some(unloop(component, position / lanes))
+                }
+                _ if position == 0 => Some(component.ptr_clone()),
+                _ => None,
+            };
+        }
+        panic!("Position {} is out of bounds.", position)
+    }
+
+    /// Returns what the body of a loop should use to read the `iterations`
+    /// vectors starting at `position`, if they are all produced by one loop.
+    fn loop_element_at(&self, mut position: usize, iterations: usize) -> Option<ConcreteValuePtr> {
+        let lanes = self.lanes;
+        for component in &self.components {
+            let width = component.width(lanes);
+            if position >= width {
+                position -= width;
+                continue;
+            }
+            return match &**component {
+                ConcreteValue::Loop { .. }
+                    if position % lanes == 0 && position + lanes * iterations <= width =>
+                {
+                    Some(loop_element(component, position / lanes))
                 }
+                _ => None,
+            };
+        }
+        None
+    }
+
+    /// Returns a value of the given type whose scalars are all zero.
+    fn zeros(typee: ConcreteType, lanes: usize) -> Self {
+        let zero = ConcreteValuePtr::new(match typee.base {
+            ConcreteScalarType::Int => ConcreteValue::IntLiteral(0),
+            ConcreteScalarType::Float => ConcreteValue::FloatLiteral(0.0),
+            ConcreteScalarType::Bool => ConcreteValue::BoolLiteral(false),
+        });
+        let size = typee.size();
+        let mut components = Vec::new();
+        let vectors = vectors_in(size, lanes);
+        for _ in 0..vectors {
+            components.push(vectorize(vec![zero.ptr_clone(); lanes]));
+        }
+        for _ in vectors * lanes..size {
+            components.push(zero.ptr_clone());
+        }
+        Self {
+            typee,
+            components,
+            lanes,
+        }
+    }
+
+    /// Returns a copy of this value with the scalars starting at `offset`
+    /// replaced. Vector components which are completely overwritten remain
+    /// vectors.
+    fn with_scalars_replaced(&self, offset: usize, scalars: &[ConcreteValuePtr]) -> Self {
+        let written = offset..offset + scalars.len();
+        let mut components = Vec::new();
+        let mut position = 0;
+        for component in self.components_split_at(&written) {
+            let width = component.width(self.lanes);
+            let range = position..position + width;
+            if range.end <= written.start || range.start >= written.end {
+                components.push(component.ptr_clone());
+            } else if component.is_vector()
+                && written.start <= range.start
+                && range.end <= written.end
+            {
+                let lanes = scalars[range.start - offset..range.end - offset].to_vec();
+                components.push(vectorize_lanes(lanes));
             } else {
-                if position == 0 {
-                    return Some(component.ptr_clone());
-                } else if position < 8 {
-                    return None;
-                } else {
-                    position -= 8;
+                for position in range {
+                    components.push(if written.contains(&position) {
+                        scalars[position - offset].ptr_clone()
+                    } else {
+                        self.get_scalar(position)
+                    });
                 }
             }
+            position += width;
+        }
+        Self {
+            typee: self.typee.clone(),
+            components,
+            lanes: self.lanes,
         }
-        panic!("Position {} is out of bounds.", position)
+    }
+
+    /// Returns the components of this value, with any loops that overlap the
+    /// range broken up into their individual vectors.
+    fn components_split_at(&self, range: &std::ops::Range<usize>) -> Vec<ConcreteValuePtr> {
+        let mut components = Vec::new();
+        let mut position = 0;
+        for component in &self.components {
+            let width = component.width(self.lanes);
+            match &**component {
+                ConcreteValue::Loop { iterations, .. }
+                    if position < range.end && range.start < position + width =>
+                {
+                    components.extend((0..*iterations).map(|k| unloop(component, k)));
+                }
+                _ => components.push(component.ptr_clone()),
+            }
+            position += width;
+        }
+        components
     }
 }
 
@@ -219,6 +427,93 @@ impl ConcreteMultiValue {
 pub struct SolidificationContext {
     pub inputs: Vec<(LocalPtr, ConcreteType)>,
     pub converted: HashMap<*const (), ConcreteMultiValue>,
+    /// How indices which are only known at runtime are kept in bounds.
+    pub out_of_bounds_policy: OutOfBoundsPolicy,
+    /// Elementwise operations producing at least this many vectors are
+    /// computed with a `ConcreteValue::Loop` instead of being unrolled.
+    pub loop_threshold: usize,
+    /// How many scalars each vector is made of, which should match the SIMD
+    /// registers of the target. When this is 1 no vectors (or loops) are
+    /// made, and every component is a scalar.
+    pub lanes: usize,
+}
+
+/// The default value of `SolidificationContext::loop_threshold`.
+pub const DEFAULT_LOOP_THRESHOLD: usize = 16;
+
+/// The default value of `SolidificationContext::lanes`.
+pub const DEFAULT_LANES: usize = 8;
+
+impl Default for SolidificationContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn broadcast_dims(left: &[usize], right: &[usize]) -> Vec<usize> {
+    let len = left.len().max(right.len());
+    (0..len)
+        .map(|axis| {
+            let left = left.get(axis).copied().unwrap_or(1);
+            let right = right.get(axis).copied().unwrap_or(1);
+            assert!(left == right || left == 1 || right == 1);
+            left.max(right)
+        })
+        .collect()
+}
+
+/// How many whole vectors fit in `size` scalars. Single scalars are never
+/// made into vectors, so there are none when there is only one lane.
+fn vectors_in(size: usize, lanes: usize) -> usize {
+    if lanes > 1 {
+        size / lanes
+    } else {
+        0
+    }
+}
+
+fn vectorize(scalars: Vec<ConcreteValuePtr>) -> ConcreteValuePtr {
+    ConcreteValuePtr::new(ConcreteValue::Vectorize(scalars))
+}
+
+/// Like `vectorize`, but if the scalars are the lanes of an existing vector in
+/// order then that vector is used instead.
+fn vectorize_lanes(scalars: Vec<ConcreteValuePtr>) -> ConcreteValuePtr {
+    if let ConcreteValue::Unvectorize(source, _) = &*scalars[0] {
+        let is_source_lane = |(lane, scalar): (usize, &ConcreteValuePtr)| {
+            matches!(&**scalar, ConcreteValue::Unvectorize(other, other_lane)
+                if Rc::ptr_eq(&other.0, &source.0) && *other_lane == lane)
+        };
+        if scalars.iter().enumerate().all(is_source_lane) {
+            return source.ptr_clone();
+        }
+    }
+    vectorize(scalars)
+}
+
+fn loop_element(source: &ConcreteValuePtr, offset: usize) -> ConcreteValuePtr {
+    ConcreteValuePtr::new(ConcreteValue::LoopElement {
+        source: source.ptr_clone(),
+        offset,
+    })
+}
+
+fn unloop(source: &ConcreteValuePtr, iteration: usize) -> ConcreteValuePtr {
+    ConcreteValuePtr::new(ConcreteValue::Unloop(source.ptr_clone(), iteration))
+}
+
+/// Applies a unary operation to a component. Loops get a new loop which
+/// applies the operation to each of their vectors.
+fn map_component(component: ConcreteValuePtr, op: UnaryOp) -> ConcreteValuePtr {
+    if let ConcreteValue::Loop { iterations, .. } = &*component {
+        let element = loop_element(&component, 0);
+        ConcreteValuePtr::new(ConcreteValue::Loop {
+            iterations: *iterations,
+            body: ConcreteValuePtr::new(ConcreteValue::UnaryOp(op, element)),
+        })
+    } else {
+        ConcreteValuePtr::new(ConcreteValue::UnaryOp(op, component))
+    }
 }
 
 fn cast(
@@ -228,19 +523,33 @@ fn cast(
 ) -> ConcreteValuePtr {
     match (from, to) {
         (ConcreteScalarType::Bool, ConcreteScalarType::Int) => {
-            ConcreteValuePtr::new(ConcreteValue::UnaryOp(UnaryOp::BoolToInt, source))
+            map_component(source, UnaryOp::BoolToInt)
         }
         (ConcreteScalarType::Bool, ConcreteScalarType::Float) => {
-            ConcreteValuePtr::new(ConcreteValue::UnaryOp(UnaryOp::BoolToFloat, source))
+            map_component(source, UnaryOp::BoolToFloat)
         }
         (ConcreteScalarType::Int, ConcreteScalarType::Float) => {
-            ConcreteValuePtr::new(ConcreteValue::UnaryOp(UnaryOp::IntToFloat, source))
+            map_component(source, UnaryOp::IntToFloat)
         }
         (from, to) if from == to => source,
         _ => panic!("Invalid cast from {:?} to {:?}", from, to),
     }
 }
 
+/// Returns a vector of the scalars at one position per lane in the value, if
+/// that can be done without assembling it from individual scalars.
+fn vector_at(value: &ConcreteMultiValue, positions: &[usize]) -> Option<ConcreteValuePtr> {
+    if sequence_monotonically_increases(positions) {
+        value.get_vector(positions[0])
+    } else if all_identical(positions) {
+        Some(ConcreteValuePtr::new(
+            ConcreteValue::VectorizeByDuplication(value.get_scalar(positions[0])),
+        ))
+    } else {
+        None
+    }
+}
+
 /// Returns true if the sequence follows a pattern like 5, 6, 7, 8 or -23, -22,
 /// -21.
 pub fn sequence_monotonically_increases(sequence: &[usize]) -> bool {
@@ -269,6 +578,88 @@ pub fn all_identical(sequence: &[usize]) -> bool {
 }
 
 impl SolidificationContext {
+    pub fn new() -> Self {
+        Self {
+            inputs: Vec::new(),
+            converted: HashMap::new(),
+            out_of_bounds_policy: OutOfBoundsPolicy::Clamp,
+            loop_threshold: DEFAULT_LOOP_THRESHOLD,
+            lanes: DEFAULT_LANES,
+        }
+    }
+
+    /// How many whole vectors fit in `size` scalars.
+    fn vectors_in(&self, size: usize) -> usize {
+        vectors_in(size, self.lanes)
+    }
+
+    /// Builds a multi-value out of components made for this context.
+    fn multi_value(
+        &self,
+        typee: ConcreteType,
+        components: Vec<ConcreteValuePtr>,
+    ) -> ConcreteMultiValue {
+        ConcreteMultiValue {
+            typee,
+            components,
+            lanes: self.lanes,
+        }
+    }
+
+    /// Whether a run of this many vectors should be computed with a loop.
+    fn should_loop(&self, iterations: usize) -> bool {
+        iterations > 0 && iterations >= self.loop_threshold
+    }
+
+    /// Builds an elementwise operation producing `size` scalars as a loop,
+    /// followed by scalars for whatever doesn't fill a whole vector. `positions`
+    /// holds the position in each operand of every element of the result.
+    /// `build` is given one vector or scalar from each operand. Returns `None`
+    /// if the result is too small to be worth looping over, or if an operand
+    /// isn't either a single scalar or laid out by a loop in the same order as
+    /// the result.
+    fn elementwise_loop(
+        &self,
+        operands: &[&ConcreteMultiValue],
+        positions: &[Vec<usize>],
+        size: usize,
+        build: impl Fn(Vec<ConcreteValuePtr>) -> ConcreteValuePtr,
+    ) -> Option<Vec<ConcreteValuePtr>> {
+        let lanes = self.lanes;
+        let iterations = self.vectors_in(size);
+        if !self.should_loop(iterations) {
+            return None;
+        }
+        let looped = &positions
+            .iter()
+            .map(|p| &p[..lanes * iterations])
+            .collect_vec();
+        let mut in_body = Vec::new();
+        for (operand, positions) in operands.iter().zip(looped) {
+            in_body.push(if all_identical(positions) {
+                let scalar = operand.get_scalar(positions[0]);
+                ConcreteValuePtr::new(ConcreteValue::VectorizeByDuplication(scalar))
+            } else if sequence_monotonically_increases(positions) {
+                operand.loop_element_at(positions[0], iterations)?
+            } else {
+                return None;
+            });
+        }
+        let mut components = vec![ConcreteValuePtr::new(ConcreteValue::Loop {
+            iterations,
+            body: build(in_body),
+        })];
+        for next in lanes * iterations..size {
+            let scalars = operands
+                .iter()
+                .zip(positions)
+                .map(|(operand, positions)| operand.get_scalar(positions[next]))
+                .collect();
+            components.push(build(scalars));
+        }
+        Some(components)
+    }
+
     pub fn solidify_type(&mut self, typee: Value) -> ConcreteType {
         let typee = ValuePtr::new(typee);
         typee.check_and_simplify(&mut SimplificationContext::new());
@@ -320,27 +711,27 @@ impl SolidificationContext {
             Value::BuiltinType(_) => panic!("Types are not available at runtime."),
             Value::BuiltinOp(_) => panic!("Functions are not available at runtime."),
             Value::Malformed => panic!("Tried to take the value of a malformed expression."),
-            &Value::FloatLiteral(value) => ConcreteMultiValue {
-                typee: ConcreteType {
+            &Value::FloatLiteral(value) => self.multi_value(
+                ConcreteType {
                     base: ConcreteScalarType::Float,
                     dims: vec![],
                 },
-                components: vec![ConcreteValuePtr::new(ConcreteValue::FloatLiteral(value))],
-            },
-            &Value::IntLiteral(value) => ConcreteMultiValue {
-                typee: ConcreteType {
+                vec![ConcreteValuePtr::new(ConcreteValue::FloatLiteral(value))],
+            ),
+            &Value::IntLiteral(value) => self.multi_value(
+                ConcreteType {
                     base: ConcreteScalarType::Int,
                     dims: vec![],
                 },
-                components: vec![ConcreteValuePtr::new(ConcreteValue::IntLiteral(value))],
-            },
-            &Value::BoolLiteral(value) => ConcreteMultiValue {
-                typee: ConcreteType {
+                vec![ConcreteValuePtr::new(ConcreteValue::IntLiteral(value))],
+            ),
+            &Value::BoolLiteral(value) => self.multi_value(
+                ConcreteType {
                     base: ConcreteScalarType::Bool,
                     dims: vec![],
                 },
-                components: vec![ConcreteValuePtr::new(ConcreteValue::BoolLiteral(value))],
-            },
+                vec![ConcreteValuePtr::new(ConcreteValue::BoolLiteral(value))],
+            ),
             Value::ArrayLiteral { elements, dims } => {
                 let mut new_dims = Vec::new();
                 for dim in dims {
@@ -355,13 +746,16 @@ impl SolidificationContext {
                 }
                 let mut components = Vec::new();
                 let num_elements = elements.len();
-                let first_nonvectorized_element = num_elements - num_elements % 8;
+                let lanes = self.lanes;
+                let first_nonvectorized_element = self.vectors_in(num_elements) * lanes;
                 let mut element_type = ConcreteScalarType::Bool;
                 for element in elements {
                     element_type = element_type + self.solidify_value(element).typee.base;
                 }
                 let mut convert_and_expect_scalar = |element: &ValuePtr| {
-                    let ConcreteMultiValue { typee, components } = self.solidify_value(element);
+                    let ConcreteMultiValue {
+                        typee, components, ..
+                    } = self.solidify_value(element);
                     assert!(
                         components.len() == 1,
                         "Array literals must be composed of scalars."
@@ -373,53 +767,52 @@ impl SolidificationContext {
                     );
                     cast(component, typee.base, element_type)
                 };
-                for idx in 0..first_nonvectorized_element / 8 {
-                    let scalars = [
-                        convert_and_expect_scalar(&elements[8 * idx + 0]),
-                        convert_and_expect_scalar(&elements[8 * idx + 1]),
-                        convert_and_expect_scalar(&elements[8 * idx + 2]),
-                        convert_and_expect_scalar(&elements[8 * idx + 3]),
-                        convert_and_expect_scalar(&elements[8 * idx + 4]),
-                        convert_and_expect_scalar(&elements[8 * idx + 5]),
-                        convert_and_expect_scalar(&elements[8 * idx + 6]),
-                        convert_and_expect_scalar(&elements[8 * idx + 7]),
-                    ];
-                    components.push(ConcreteValuePtr::new(ConcreteValue::Vectorize(scalars)));
+                for chunk in elements[..first_nonvectorized_element].chunks(lanes) {
+                    let scalars = chunk.iter().map(&mut convert_and_expect_scalar).collect();
+                    components.push(vectorize(scalars));
                 }
-                for idx in first_nonvectorized_element..elements.len() {
-                    components.push(convert_and_expect_scalar(&elements[idx]));
+                for element in &elements[first_nonvectorized_element..] {
+                    components.push(convert_and_expect_scalar(element));
                 }
-                ConcreteMultiValue {
-                    typee: ConcreteType {
+                self.multi_value(
+                    ConcreteType {
                         base: element_type,
                         dims: new_dims,
                     },
                     components,
-                }
+                )
             }
             Value::Local(local) => {
                 let mut result = None;
                 for (input_index, input) in self.inputs.iter().enumerate() {
                     if &input.0 == local {
                         let mut components = Vec::new();
-                        let vector_components = input.1.size() / 8;
-                        let scalar_components = input.1.size() % 8;
-                        for position in 0..vector_components {
-                            components.push(ConcreteValuePtr::new(ConcreteValue::InputVector {
-                                input: input_index,
-                                position: position * 8,
+                        let lanes = self.lanes;
+                        let vector_components = self.vectors_in(input.1.size());
+                        let scalar_components = input.1.size() - vector_components * lanes;
+                        if self.should_loop(vector_components) {
+                            let body = ConcreteValue::LoopInputVector(input_index);
+                            components.push(ConcreteValuePtr::new(ConcreteValue::Loop {
+                                iterations: vector_components,
+                                body: ConcreteValuePtr::new(body),
                             }));
+                        } else {
+                            for position in 0..vector_components {
+                                components.push(ConcreteValuePtr::new(
+                                    ConcreteValue::InputVector {
+                                        input: input_index,
+                                        position: position * lanes,
+                                    },
+                                ));
+                            }
                         }
                         for position in 0..scalar_components {
                             components.push(ConcreteValuePtr::new(ConcreteValue::InputScalar {
                                 input: input_index,
-                                position: vector_components * 8 + position,
+                                position: vector_components * lanes + position,
                             }));
                         }
-                        result = Some(ConcreteMultiValue {
-                            components,
-                            typee: input.1.clone(),
-                        })
+                        result = Some(self.multi_value(input.1.clone(), components))
                     }
                 }
                 result.expect("Tried to take the value of a local that isn't an input.")
@@ -429,22 +822,22 @@ impl SolidificationContext {
                 assert_eq!(result, &0);
                 if args.len() == 2 {
                     let rhs = self.solidify_value(&args[1]);
-                    if &*base.borrow() == &Value::BuiltinOp(BuiltinOp::Cast) {
+                    if *base.borrow() == Value::BuiltinOp(BuiltinOp::Cast) {
                         let new_type = self.solidify_type(args[0].borrow().clone());
                         let rhs_type = self.solidify_type(args[1].typee());
                         let mut components = Vec::new();
                         for component in rhs.components {
                             components.push(cast(component, rhs_type.base, new_type.base));
                         }
-                        ConcreteMultiValue {
-                            components,
+                        self.multi_value(
                             // Specifically use the dims of the rhs because we don't broadcast those
                             // until we encounter an operation that requires it.
-                            typee: ConcreteType {
+                            ConcreteType {
                                 base: new_type.base,
                                 dims: rhs_type.dims,
                             },
-                        }
+                            components,
+                        )
                     } else {
                         let lhs = self.solidify_value(&args[0]);
                         let result_type = self.solidify_type(value.typee());
@@ -453,7 +846,10 @@ impl SolidificationContext {
                             Value::BuiltinOp(BuiltinOp::Sub) => BinaryOp::Sub,
                             Value::BuiltinOp(BuiltinOp::Mul) => BinaryOp::Mul,
                             Value::BuiltinOp(BuiltinOp::Div) => BinaryOp::Div,
+                            // Type checking guarantees both operands are integers.
+                            Value::BuiltinOp(BuiltinOp::IntDiv) => BinaryOp::Div,
                             Value::BuiltinOp(BuiltinOp::Rem) => BinaryOp::Rem,
+                            Value::BuiltinOp(BuiltinOp::Pow) => BinaryOp::Pow,
                             Value::BuiltinOp(BuiltinOp::Gt) => BinaryOp::Gt,
                             Value::BuiltinOp(BuiltinOp::Lt) => BinaryOp::Lt,
                             Value::BuiltinOp(BuiltinOp::Gte) => BinaryOp::Gte,
@@ -474,71 +870,357 @@ impl SolidificationContext {
                                 .map(|idx| rhs.typee.flatten_index(&idx))
                                 .collect_vec();
                         assert_eq!(lhs_indexes.len(), rhs_indexes.len());
-                        let mut next_index = 0;
-                        let mut components = Vec::new();
-                        while next_index < lhs_indexes.len() {
-                            if lhs_indexes.len() - next_index >= 8 {
-                                let indexes_in_question = next_index..next_index + 8;
-                                let lhs_vector = if sequence_monotonically_increases(
-                                    &lhs_indexes[indexes_in_question.clone()],
-                                ) {
-                                    lhs.get_vector(lhs_indexes[next_index])
-                                } else if all_identical(&lhs_indexes[indexes_in_question.clone()]) {
-                                    Some(ConcreteValuePtr::new(
-                                        ConcreteValue::VectorizeByDuplication(
-                                            lhs.get_scalar(lhs_indexes[next_index]),
-                                        ),
-                                    ))
-                                } else {
-                                    None
-                                };
-                                let rhs_vector = if sequence_monotonically_increases(
-                                    &rhs_indexes[indexes_in_question.clone()],
-                                ) {
-                                    rhs.get_vector(rhs_indexes[next_index])
-                                } else if all_identical(&rhs_indexes[indexes_in_question.clone()]) {
-                                    Some(ConcreteValuePtr::new(
-                                        ConcreteValue::VectorizeByDuplication(
-                                            rhs.get_scalar(rhs_indexes[next_index]),
-                                        ),
-                                    ))
-                                } else {
-                                    None
-                                };
-                                if let (Some(lhs_vector), Some(rhs_vector)) =
-                                    (lhs_vector, rhs_vector)
-                                {
-                                    components.push(ConcreteValuePtr::new(
-                                        ConcreteValue::BinaryOp(op, lhs_vector, rhs_vector),
-                                    ));
-                                    next_index += 8;
-                                    continue;
+                        let looped = self.elementwise_loop(
+                            &[&lhs, &rhs],
+                            &[lhs_indexes.clone(), rhs_indexes.clone()],
+                            result_type.size(),
+                            |mut operands| {
+                                let rhs = operands.pop().unwrap();
+                                let lhs = operands.pop().unwrap();
+                                ConcreteValuePtr::new(ConcreteValue::BinaryOp(op, lhs, rhs))
+                            },
+                        );
+                        let components = if let Some(components) = looped {
+                            components
+                        } else {
+                            let mut next_index = 0;
+                            let mut components = Vec::new();
+                            while next_index < lhs_indexes.len() {
+                                if self.vectors_in(lhs_indexes.len() - next_index) > 0 {
+                                    let indexes_in_question = next_index..next_index + self.lanes;
+                                    let lhs_vector =
+                                        vector_at(&lhs, &lhs_indexes[indexes_in_question.clone()]);
+                                    let rhs_vector =
+                                        vector_at(&rhs, &rhs_indexes[indexes_in_question]);
+                                    if let (Some(lhs_vector), Some(rhs_vector)) =
+                                        (lhs_vector, rhs_vector)
+                                    {
+                                        components.push(ConcreteValuePtr::new(
+                                            ConcreteValue::BinaryOp(op, lhs_vector, rhs_vector),
+                                        ));
+                                        next_index += self.lanes;
+                                        continue;
+                                    }
                                 }
+                                components.push(ConcreteValuePtr::new(ConcreteValue::BinaryOp(
+                                    op,
+                                    lhs.get_scalar(lhs_indexes[next_index]),
+                                    rhs.get_scalar(rhs_indexes[next_index]),
+                                )));
+                                next_index += 1;
                             }
-                            components.push(ConcreteValuePtr::new(ConcreteValue::BinaryOp(
-                                op,
-                                lhs.get_scalar(next_index),
-                                rhs.get_scalar(next_index),
-                            )));
-                            next_index += 1;
-                        }
-                        println!("{:#?} {:#?}", lhs_indexes, rhs_indexes);
-                        ConcreteMultiValue {
-                            components,
-                            typee: result_type,
-                        }
+                            components
+                        };
+                        self.multi_value(result_type, components)
                     }
+                } else if args.len() == 1 {
+                    let op = match &*base.borrow() {
+                        Value::BuiltinOp(BuiltinOp::Neg) => UnaryOp::Neg,
+                        Value::BuiltinOp(BuiltinOp::Not) => UnaryOp::Not,
+                        _ => panic!("Invalid unary operation."),
+                    };
+                    let operand = self.solidify_value(&args[0]);
+                    // Unary operators work on each scalar separately, so
+                    // vectors can be operated on directly.
+                    let components = operand
+                        .components
+                        .iter()
+                        .map(|component| map_component(component.ptr_clone(), op))
+                        .collect();
+                    self.multi_value(operand.typee, components)
                 } else {
-                    todo!()
+                    unreachable!(
+                        "Simplification reports calls to operators with the wrong number \
+                        of arguments, so every operator left takes one or two."
+                    )
                 }
             }
+            Value::ElementRead { base, indices } => self.solidify_element_read(base, indices),
+            Value::Select {
+                condition,
+                if_true,
+                if_false,
+            } => {
+                let result_type = self.solidify_type(value.typee());
+                self.solidify_select(result_type, condition, if_true, if_false)
+            }
         };
         self.converted.insert(value.as_ptr(), multi_value.clone());
         multi_value
     }
+
+    fn solidify_element_read(
+        &mut self,
+        base: &ValuePtr,
+        indices: &[ValuePtr],
+    ) -> ConcreteMultiValue {
+        let base = self.solidify_value(base);
+        let mut static_indices = Vec::new();
+        for index in indices {
+            if let &Value::IntLiteral(index) = &*index.borrow() {
+                static_indices.push(index as usize);
+            } else {
+                return self.solidify_dynamic_element_read(base, indices);
+            }
+        }
+        let remaining = base.typee.dims.len() - static_indices.len();
+        let typee = ConcreteType {
+            base: base.typee.base,
+            dims: base.typee.dims[..remaining].to_vec(),
+        };
+        // The element or sub-array is a contiguous run of components starting
+        // at the flattened index of the first element it contains.
+        let mut first_element = static_indices;
+        first_element.resize(base.typee.dims.len(), 0);
+        let offset = base.typee.flatten_index(&first_element);
+        let size = typee.size();
+        let mut components = Vec::new();
+        let mut position = 0;
+        let iterations = self.vectors_in(size);
+        if self.should_loop(iterations) {
+            if let Some(body) = base.loop_element_at(offset, iterations) {
+                components.push(ConcreteValuePtr::new(ConcreteValue::Loop {
+                    iterations,
+                    body,
+                }));
+                position = iterations * self.lanes;
+            }
+        }
+        while position < size {
+            if self.vectors_in(size - position) > 0 {
+                if let Some(vector) = base.get_vector(offset + position) {
+                    components.push(vector);
+                    position += self.lanes;
+                    continue;
+                }
+            }
+            components.push(base.get_scalar(offset + position));
+            position += 1;
+        }
+        self.multi_value(typee, components)
+    }
+
+    fn solidify_select(
+        &mut self,
+        result_type: ConcreteType,
+        condition: &ValuePtr,
+        if_true: &ValuePtr,
+        if_false: &ValuePtr,
+    ) -> ConcreteMultiValue {
+        let operands = [condition, if_true, if_false]
+            .iter()
+            .map(|operand| self.solidify_value(operand))
+            .collect_vec();
+        // The position in each operand of every element of the result, which
+        // the operands are broadcast to.
+        let positions = operands
+            .iter()
+            .map(|operand| {
+                nd_index_iter(result_type.dims.iter().copied().rev().collect())
+                    .map(|index| operand.typee.flatten_index(&index))
+                    .collect_vec()
+            })
+            .collect_vec();
+        let cast_branch = |branch: &ConcreteMultiValue, component| {
+            cast(component, branch.typee.base, result_type.base)
+        };
+        let size = result_type.size();
+        let operand_refs = operands.iter().collect_vec();
+        let looped = self.elementwise_loop(&operand_refs, &positions, size, |selected| {
+            ConcreteValuePtr::new(ConcreteValue::Select {
+                condition: selected[0].ptr_clone(),
+                if_true: cast_branch(&operands[1], selected[1].ptr_clone()),
+                if_false: cast_branch(&operands[2], selected[2].ptr_clone()),
+            })
+        });
+        if let Some(components) = looped {
+            return self.multi_value(result_type, components);
+        }
+        let mut components = Vec::new();
+        let mut next = 0;
+        while next < size {
+            if self.vectors_in(size - next) > 0 {
+                let window = next..next + self.lanes;
+                let vectors: Option<Vec<_>> = operands
+                    .iter()
+                    .zip(&positions)
+                    .map(|(operand, positions)| vector_at(operand, &positions[window.clone()]))
+                    .collect();
+                if let Some(vectors) = vectors {
+                    let mut vectors = vectors.into_iter();
+                    components.push(ConcreteValuePtr::new(ConcreteValue::Select {
+                        condition: vectors.next().unwrap(),
+                        if_true: cast_branch(&operands[1], vectors.next().unwrap()),
+                        if_false: cast_branch(&operands[2], vectors.next().unwrap()),
+                    }));
+                    next += self.lanes;
+                    continue;
+                }
+            }
+            let scalar = |operand: usize| operands[operand].get_scalar(positions[operand][next]);
+            components.push(ConcreteValuePtr::new(ConcreteValue::Select {
+                condition: scalar(0),
+                if_true: cast_branch(&operands[1], scalar(1)),
+                if_false: cast_branch(&operands[2], scalar(2)),
+            }));
+            next += 1;
+        }
+        self.multi_value(result_type, components)
+    }
+
+    /// Writes the value of an indexed assignment into the value the target
+    /// held before it.
+    fn solidify_indexed_assignment(
+        &mut self,
+        current: ConcreteMultiValue,
+        base: &ValuePtr,
+        index: &Index,
+    ) -> ConcreteMultiValue {
+        let static_indices = index
+            .indices
+            .iter()
+            .map(|index| match &*index.borrow() {
+                &Value::IntLiteral(index) => index as usize,
+                _ => panic!("Indexed assignments must use indices known at compile time."),
+            })
+            .collect_vec();
+        let offset = current.typee.flatten_index(&static_indices);
+        let base = self.solidify_value(base);
+        let scalars = (0..index.width)
+            .map(|lane| {
+                // A single value is repeated across every lane.
+                let scalar = base.get_scalar(lane % base.typee.size());
+                cast(scalar, base.typee.base, current.typee.base)
+            })
+            .collect_vec();
+        current.with_scalars_replaced(offset, &scalars)
+    }
+
+    fn solidify_dynamic_element_read(
+        &mut self,
+        base: ConcreteMultiValue,
+        indices: &[ValuePtr],
+    ) -> ConcreteMultiValue {
+        let indices = indices
+            .iter()
+            .map(|index| self.solidify_value(index))
+            .collect_vec();
+        let dims = &base.typee.dims;
+        let remaining = dims.len() - indices.len();
+        let sub_size: usize = dims[..remaining].iter().product();
+        let mut gather_dims = Vec::new();
+        for index in &indices {
+            gather_dims = broadcast_dims(&gather_dims, &index.typee.dims);
+        }
+        // Index k selects along dimension n - 1 - k, which is as far apart as
+        // all the dimensions inside it.
+        let strides = (0..indices.len())
+            .map(|k| dims[..dims.len() - 1 - k].iter().product::<usize>())
+            .collect_vec();
+        let sizes = (0..indices.len())
+            .map(|k| dims[dims.len() - 1 - k])
+            .collect_vec();
+        let gather_positions =
+            nd_index_iter(gather_dims.iter().copied().rev().collect()).collect_vec();
+        let typee = ConcreteType {
+            base: base.typee.base,
+            dims: [&dims[..remaining], &gather_dims[..]].concat(),
+        };
+
+        let policy = self.out_of_bounds_policy;
+        let mut components = Vec::new();
+        let mut next = 0;
+        while next < gather_positions.len() {
+            if sub_size == 1 && self.vectors_in(gather_positions.len() - next) > 0 {
+                let window = &gather_positions[next..next + self.lanes];
+                let mut offsets = None;
+                for (k, index) in indices.iter().enumerate() {
+                    let positions = window
+                        .iter()
+                        .map(|position| index.typee.flatten_index(position))
+                        .collect_vec();
+                    let vector = if sequence_monotonically_increases(&positions) {
+                        index.get_vector(positions[0])
+                    } else if all_identical(&positions) {
+                        Some(ConcreteValuePtr::new(
+                            ConcreteValue::VectorizeByDuplication(index.get_scalar(positions[0])),
+                        ))
+                    } else {
+                        None
+                    };
+                    let vector = vector.unwrap_or_else(|| {
+                        vectorize(positions.iter().map(|&p| index.get_scalar(p)).collect())
+                    });
+                    let vector = cast(vector, index.typee.base, ConcreteScalarType::Int);
+                    let bounded =
+                        ConcreteValuePtr::new(ConcreteValue::BoundIndex(vector, sizes[k], policy));
+                    let stride = ConcreteValuePtr::new(ConcreteValue::VectorizeByDuplication(
+                        ConcreteValuePtr::new(ConcreteValue::IntLiteral(strides[k] as i32)),
+                    ));
+                    let term = ConcreteValuePtr::new(ConcreteValue::BinaryOp(
+                        BinaryOp::Mul,
+                        bounded,
+                        stride,
+                    ));
+                    offsets = Some(add_offsets(offsets, term));
+                }
+                components.push(ConcreteValuePtr::new(ConcreteValue::Gather {
+                    source: base.clone(),
+                    indices: offsets.unwrap(),
+                }));
+                next += self.lanes;
+                continue;
+            }
+            let mut offset = None;
+            for (k, index) in indices.iter().enumerate() {
+                let position = index.typee.flatten_index(&gather_positions[next]);
+                let scalar = cast(
+                    index.get_scalar(position),
+                    index.typee.base,
+                    ConcreteScalarType::Int,
+                );
+                let bounded =
+                    ConcreteValuePtr::new(ConcreteValue::BoundIndex(scalar, sizes[k], policy));
+                let stride = ConcreteValuePtr::new(ConcreteValue::IntLiteral(strides[k] as i32));
+                let term =
+                    ConcreteValuePtr::new(ConcreteValue::BinaryOp(BinaryOp::Mul, bounded, stride));
+                offset = Some(add_offsets(offset, term));
+            }
+            let offset = offset.unwrap();
+            for element in 0..sub_size {
+                let index = if element == 0 {
+                    offset.ptr_clone()
+                } else {
+                    let element = ConcreteValuePtr::new(ConcreteValue::IntLiteral(element as i32));
+                    add_offsets(Some(offset.ptr_clone()), element)
+                };
+                components.push(ConcreteValuePtr::new(ConcreteValue::DynamicLoad {
+                    source: base.clone(),
+                    index,
+                }));
+            }
+            next += 1;
+        }
+        self.multi_value(typee, components)
+    }
+}
+
+fn add_offsets(previous: Option<ConcreteValuePtr>, term: ConcreteValuePtr) -> ConcreteValuePtr {
+    if let Some(previous) = previous {
+        ConcreteValuePtr::new(ConcreteValue::BinaryOp(BinaryOp::Add, previous, term))
+    } else {
+        term
+    }
 }
 
 pub fn solidify(function: ValuePtr) -> ConcreteProgram {
+    solidify_with(function, SolidificationContext::new())
+}
+
+/// Like `solidify`, but uses the settings (such as the out of bounds policy)
+/// of the given context.
+pub fn solidify_with(function: ValuePtr, mut ctx: SolidificationContext) -> ConcreteProgram {
+    assert!(ctx.lanes > 0, "Vectors must have at least one lane.");
     if let Value::Function {
         inputs,
         outputs: liquid_outputs,
@@ -546,34 +1228,48 @@ pub fn solidify(function: ValuePtr) -> ConcreteProgram {
         ..
     } = &*function.borrow()
     {
-        let mut ctx = SolidificationContext {
-            inputs: Vec::new(),
-            converted: HashMap::new(),
-        };
         for input in inputs {
             let typee = ctx.solidify_type(input.typee.borrow().clone());
             ctx.inputs.push((input.ptr_clone(), typee))
         }
-        let mut outputs = Vec::new();
-        'next_output: for output in liquid_outputs {
-            for statement in body.iter().rev() {
-                if let Statement::Assignment {
-                    base,
-                    index,
-                    target,
-                } = statement
-                {
-                    if target == output && index.is_none() {
-                        outputs.push(ctx.solidify_value(base));
-                        continue 'next_output;
-                    }
-                }
+        // Outputs can be built up by several assignments which each write some
+        // of their elements.
+        let mut values: Vec<Option<ConcreteMultiValue>> = vec![None; liquid_outputs.len()];
+        for statement in body {
+            if let Statement::Assignment {
+                base,
+                index,
+                target,
+                ..
+            } = statement
+            {
+                let output = match liquid_outputs.iter().position(|output| output == target) {
+                    Some(output) => output,
+                    None => continue,
+                };
+                let value = if let Some(index) = index {
+                    let current = match values[output].take() {
+                        Some(current) => current,
+                        None => ConcreteMultiValue::zeros(
+                            ctx.solidify_type(target.typee.borrow().clone()),
+                            ctx.lanes,
+                        ),
+                    };
+                    ctx.solidify_indexed_assignment(current, base, index)
+                } else {
+                    ctx.solidify_value(base)
+                };
+                values[output] = Some(value);
             }
-            panic!("Output not assigned a value!");
         }
+        let outputs = values
+            .into_iter()
+            .map(|value| value.expect("Output not assigned a value!"))
+            .collect();
         ConcreteProgram {
             inputs: ctx.inputs.into_iter().map(|x| x.1).collect(),
             outputs,
+            lanes: ctx.lanes,
         }
     } else {
         panic!("Not a function.")
@@ -0,0 +1,350 @@
+//! Turns a `ConcreteProgram` into source code for other compilers.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::concrete::{
+    BinaryOp, ConcreteMultiValue, ConcreteProgram, ConcreteScalarType, ConcreteValue,
+    ConcreteValuePtr, UnaryOp,
+};
+
+pub mod c;
+pub mod rust;
+
+/// How many scalars a scheduled value is made of.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Shape {
+    Scalar,
+    /// One scalar per lane, as many as `Schedule::lanes`.
+    Vector,
+    /// The vectors a `ConcreteValue::Loop` produces, one per iteration.
+    Loop {
+        iterations: usize,
+    },
+    /// A multi-value written to an array so that it can be indexed at
+    /// runtime.
+    Array {
+        size: usize,
+    },
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ValueInfo {
+    pub typee: ConcreteScalarType,
+    pub shape: Shape,
+}
+
+#[derive(Clone, Debug)]
+pub enum Step {
+    /// Computes `value` and gives it a name. `args` holds the ids of the
+    /// values it uses: its operands in order, with the source of a
+    /// `DynamicLoad` or `Gather` before the index and the loop a
+    /// `LoopElement` or `Unloop` reads from as its only argument.
+    Compute {
+        id: usize,
+        value: ConcreteValuePtr,
+        args: Vec<usize>,
+    },
+    /// Writes the scalars of the values in `components` into an array, one
+    /// after another.
+    Store { id: usize, components: Vec<usize> },
+    /// Starts a loop whose counter goes from zero to `iterations`. The steps
+    /// up to the matching `EndLoop` make up its body.
+    BeginLoop { id: usize, iterations: usize },
+    /// Ends the body of a loop, which produced `body` on this iteration.
+    EndLoop { id: usize, body: usize },
+}
+
+/// The order in which the values of a program are computed. Values are
+/// computed after the values they use. Values in the body of a loop which
+/// don't depend on the iteration are computed before the loop starts.
+#[derive(Clone, Debug)]
+pub struct Schedule {
+    /// How many scalars each vector is made of.
+    pub lanes: usize,
+    pub steps: Vec<Step>,
+    /// The type and shape of each id.
+    pub values: Vec<ValueInfo>,
+    /// The ids of the components of each output.
+    pub outputs: Vec<Vec<usize>>,
+}
+
+impl Schedule {
+    pub fn new(program: &ConcreteProgram) -> Self {
+        let mut scheduler = Scheduler {
+            program,
+            schedule: Schedule {
+                lanes: program.lanes(),
+                steps: Vec::new(),
+                values: Vec::new(),
+                outputs: Vec::new(),
+            },
+            scopes: vec![Scope::default()],
+            dependent: HashMap::new(),
+        };
+        for output in program.outputs() {
+            let components = output
+                .components()
+                .iter()
+                .map(|component| scheduler.schedule(component))
+                .collect();
+            scheduler.schedule.outputs.push(components);
+        }
+        scheduler.schedule
+    }
+
+    pub fn info(&self, id: usize) -> ValueInfo {
+        self.values[id]
+    }
+}
+
+/// A value used by another value.
+enum Child<'a> {
+    Value(&'a ConcreteValuePtr),
+    MultiValue(&'a ConcreteMultiValue),
+}
+
+fn children(value: &ConcreteValue) -> Vec<Child<'_>> {
+    match value {
+        ConcreteValue::Unvectorize(operand, _)
+        | ConcreteValue::VectorizeByDuplication(operand)
+        | ConcreteValue::UnaryOp(_, operand)
+        | ConcreteValue::BoundIndex(operand, ..)
+        | ConcreteValue::LoopElement {
+            source: operand, ..
+        }
+        | ConcreteValue::Unloop(operand, _) => vec![Child::Value(operand)],
+        ConcreteValue::Vectorize(lanes) => lanes.iter().map(Child::Value).collect(),
+        ConcreteValue::BinaryOp(_, lhs, rhs) => vec![Child::Value(lhs), Child::Value(rhs)],
+        ConcreteValue::DynamicLoad { source, index } => {
+            vec![Child::MultiValue(source), Child::Value(index)]
+        }
+        ConcreteValue::Gather { source, indices } => {
+            vec![Child::MultiValue(source), Child::Value(indices)]
+        }
+        ConcreteValue::Select {
+            condition,
+            if_true,
+            if_false,
+        } => vec![
+            Child::Value(condition),
+            Child::Value(if_true),
+            Child::Value(if_false),
+        ],
+        _ => Vec::new(),
+    }
+}
+
+fn key(value: &ConcreteValuePtr) -> *const ConcreteValue {
+    &**value as *const ConcreteValue
+}
+
+fn multi_value_key(value: &ConcreteMultiValue) -> Vec<*const ConcreteValue> {
+    value.components().iter().map(key).collect()
+}
+
+/// Ids which can be used in the block of code being scheduled.
+#[derive(Default)]
+struct Scope {
+    values: HashMap<*const ConcreteValue, usize>,
+    multi_values: HashMap<Vec<*const ConcreteValue>, usize>,
+}
+
+struct Scheduler<'a> {
+    program: &'a ConcreteProgram,
+    schedule: Schedule,
+    scopes: Vec<Scope>,
+    /// Whether each value depends on the iteration of the loop it is used in.
+    dependent: HashMap<*const ConcreteValue, bool>,
+}
+
+impl<'a> Scheduler<'a> {
+    fn is_dependent(&mut self, value: &ConcreteValuePtr) -> bool {
+        if let Some(dependent) = self.dependent.get(&key(value)) {
+            return *dependent;
+        }
+        let dependent = match &**value {
+            ConcreteValue::LoopElement { .. } | ConcreteValue::LoopInputVector(..) => true,
+            ConcreteValue::Loop { .. } | ConcreteValue::Unloop(..) => false,
+            other => children(other).into_iter().any(|child| match child {
+                Child::Value(value) => self.is_dependent(value),
+                Child::MultiValue(value) => value
+                    .components()
+                    .iter()
+                    .any(|component| self.is_dependent(component)),
+            }),
+        };
+        self.dependent.insert(key(value), dependent);
+        dependent
+    }
+
+    fn add(&mut self, info: ValueInfo) -> usize {
+        self.schedule.values.push(info);
+        self.schedule.values.len() - 1
+    }
+
+    fn info(&self, id: usize) -> ValueInfo {
+        self.schedule.values[id]
+    }
+
+    /// Schedules everything in the body of a loop which doesn't depend on
+    /// the iteration, so that it is computed before the loop starts.
+    fn hoist(&mut self, value: &ConcreteValuePtr, visited: &mut HashSet<*const ConcreteValue>) {
+        if !visited.insert(key(value)) {
+            return;
+        }
+        if !self.is_dependent(value) {
+            self.schedule(value);
+            return;
+        }
+        for child in children(value) {
+            match child {
+                Child::Value(value) => self.hoist(value, visited),
+                Child::MultiValue(value) => {
+                    for component in value.components() {
+                        self.hoist(component, visited);
+                    }
+                }
+            }
+        }
+    }
+
+    fn schedule(&mut self, value: &ConcreteValuePtr) -> usize {
+        let found = self
+            .scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.values.get(&key(value)).copied());
+        if let Some(id) = found {
+            return id;
+        }
+        let id = if let ConcreteValue::Loop { iterations, body } = &**value {
+            self.hoist(body, &mut HashSet::new());
+            let id = self.add(ValueInfo {
+                typee: ConcreteScalarType::Bool,
+                shape: Shape::Loop {
+                    iterations: *iterations,
+                },
+            });
+            self.schedule.steps.push(Step::BeginLoop {
+                id,
+                iterations: *iterations,
+            });
+            self.scopes.push(Scope::default());
+            let body = self.schedule(body);
+            self.scopes.pop();
+            self.schedule.values[id].typee = self.info(body).typee;
+            self.schedule.steps.push(Step::EndLoop { id, body });
+            id
+        } else {
+            let args: Vec<_> = children(value)
+                .into_iter()
+                .map(|child| match child {
+                    Child::Value(value) => self.schedule(value),
+                    Child::MultiValue(value) => self.schedule_multi_value(value),
+                })
+                .collect();
+            let info = self.info_for(value, &args);
+            let id = self.add(info);
+            self.schedule.steps.push(Step::Compute {
+                id,
+                value: value.ptr_clone(),
+                args,
+            });
+            id
+        };
+        let scope = self.scopes.last_mut().unwrap();
+        scope.values.insert(key(value), id);
+        id
+    }
+
+    fn schedule_multi_value(&mut self, value: &ConcreteMultiValue) -> usize {
+        let key = multi_value_key(value);
+        let found = self
+            .scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.multi_values.get(&key).copied());
+        if let Some(id) = found {
+            return id;
+        }
+        let components = value
+            .components()
+            .iter()
+            .map(|component| self.schedule(component))
+            .collect();
+        let id = self.add(ValueInfo {
+            typee: value.typee().base,
+            shape: Shape::Array {
+                size: value.typee().size(),
+            },
+        });
+        self.schedule.steps.push(Step::Store { id, components });
+        let scope = self.scopes.last_mut().unwrap();
+        scope.multi_values.insert(key, id);
+        id
+    }
+
+    /// Works out the type and shape of a value from those of its arguments.
+    fn info_for(&self, value: &ConcreteValue, args: &[usize]) -> ValueInfo {
+        use ConcreteScalarType as T;
+        let arg_shape = || {
+            if args
+                .iter()
+                .any(|&arg| self.info(arg).shape == Shape::Vector)
+            {
+                Shape::Vector
+            } else {
+                Shape::Scalar
+            }
+        };
+        let arg_type = |index: usize| self.info(args[index]).typee;
+        let input_type = |input: usize| self.program.inputs()[input].base;
+        let (typee, shape) = match value {
+            ConcreteValue::IntLiteral(..) => (T::Int, Shape::Scalar),
+            ConcreteValue::FloatLiteral(..) => (T::Float, Shape::Scalar),
+            ConcreteValue::BoolLiteral(..) => (T::Bool, Shape::Scalar),
+            ConcreteValue::Unvectorize(..) => (arg_type(0), Shape::Scalar),
+            ConcreteValue::Vectorize(..) => {
+                let typee = (0..args.len()).fold(T::Bool, |typee, lane| typee + arg_type(lane));
+                (typee, Shape::Vector)
+            }
+            ConcreteValue::VectorizeByDuplication(..) => (arg_type(0), Shape::Vector),
+            ConcreteValue::InputScalar { input, .. } => (input_type(*input), Shape::Scalar),
+            ConcreteValue::InputVector { input, .. } | ConcreteValue::LoopInputVector(input) => {
+                (input_type(*input), Shape::Vector)
+            }
+            ConcreteValue::UnaryOp(op, _) => {
+                let typee = match op {
+                    UnaryOp::IntToFloat | UnaryOp::BoolToFloat => T::Float,
+                    UnaryOp::BoolToInt => T::Int,
+                    UnaryOp::Not | UnaryOp::Neg | UnaryOp::Noop => arg_type(0),
+                };
+                (typee, arg_shape())
+            }
+            ConcreteValue::BinaryOp(op, ..) => {
+                let typee = if is_comparison(*op) {
+                    T::Bool
+                } else {
+                    arg_type(0) + arg_type(1)
+                };
+                (typee, arg_shape())
+            }
+            ConcreteValue::BoundIndex(..) => (T::Int, arg_shape()),
+            ConcreteValue::DynamicLoad { .. } => (arg_type(0), Shape::Scalar),
+            ConcreteValue::Gather { .. } => (arg_type(0), Shape::Vector),
+            ConcreteValue::Select { .. } => (arg_type(1) + arg_type(2), arg_shape()),
+            ConcreteValue::Loop { .. } => unreachable!(),
+            ConcreteValue::LoopElement { .. } | ConcreteValue::Unloop(..) => {
+                (arg_type(0), Shape::Vector)
+            }
+        };
+        ValueInfo { typee, shape }
+    }
+}
+
+pub fn is_comparison(op: BinaryOp) -> bool {
+    matches!(
+        op,
+        BinaryOp::Gt | BinaryOp::Lt | BinaryOp::Gte | BinaryOp::Lte | BinaryOp::Eq | BinaryOp::Neq
+    )
+}
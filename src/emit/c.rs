@@ -0,0 +1,558 @@
+//! Emits a program as a C99 function along with a header declaring it.
+//! Inputs are passed as `const T name[N]` and outputs as `T name[N]`, where
+//! `T` is `int32_t`, `float` or `bool` and the scalars are in the order given
+//! by `ConcreteType::flatten_index`. Vectors become arrays with one element
+//! per lane, computed by loops over their lanes. Integer arithmetic wraps and division
+//! by zero gives zero, so the results are exactly those `interpreter::execute`
+//! gives as long as the code is compiled without floating point contraction
+//! or fast math.
+
+use std::collections::HashSet;
+use std::fmt::Write;
+
+use super::{is_comparison, Schedule, Shape, Step};
+use crate::concrete::{
+    BinaryOp, ConcreteProgram, ConcreteScalarType, ConcreteType, ConcreteValue, OutOfBoundsPolicy,
+    UnaryOp,
+};
+
+/// The two files making up an emitted program.
+#[derive(Clone, Debug)]
+pub struct CSource {
+    /// Declares the function and documents the layout of its parameters.
+    pub header: String,
+    /// Defines the function. It includes the header as `"<name>.h"` and uses
+    /// `<math.h>`, so it must be linked with the math library.
+    pub source: String,
+}
+
+fn type_name(typee: ConcreteScalarType) -> &'static str {
+    match typee {
+        ConcreteScalarType::Int => "int32_t",
+        ConcreteScalarType::Float => "float",
+        ConcreteScalarType::Bool => "bool",
+    }
+}
+
+fn language_type_name(typee: ConcreteScalarType) -> &'static str {
+    match typee {
+        ConcreteScalarType::Int => "Int",
+        ConcreteScalarType::Float => "Float",
+        ConcreteScalarType::Bool => "Bool",
+    }
+}
+
+fn int_literal(value: i32) -> String {
+    if value == i32::MIN {
+        // -2147483648 is the negation of a literal which doesn't fit in an
+        // int.
+        "INT32_MIN".to_owned()
+    } else {
+        format!("{}", value)
+    }
+}
+
+fn float_literal(value: f32) -> String {
+    if value.is_nan() {
+        "NAN".to_owned()
+    } else if value == f32::INFINITY {
+        "INFINITY".to_owned()
+    } else if value == f32::NEG_INFINITY {
+        "-INFINITY".to_owned()
+    } else {
+        format!("{:?}f", value)
+    }
+}
+
+/// Converts an expression to a type at least as general as its own.
+fn promote(expression: String, from: ConcreteScalarType, to: ConcreteScalarType) -> String {
+    if from == to {
+        expression
+    } else {
+        format!("({}){}", type_name(to), expression)
+    }
+}
+
+/// Returns an expression adding a constant position to an index.
+fn offset(position: usize, index: &str) -> String {
+    if position == 0 {
+        index.to_owned()
+    } else {
+        format!("{} + {}", position, index)
+    }
+}
+
+/// Functions the emitted code can call, in the order they are defined. Signed
+/// overflow is undefined in C, so integer arithmetic is done on unsigned
+/// values.
+const HELPERS: &[(&str, &str)] = &[
+    (
+        "nodespeak_add",
+        "static inline int32_t nodespeak_add(int32_t lhs, int32_t rhs) {
+    return (int32_t)((uint32_t)lhs + (uint32_t)rhs);
+}",
+    ),
+    (
+        "nodespeak_sub",
+        "static inline int32_t nodespeak_sub(int32_t lhs, int32_t rhs) {
+    return (int32_t)((uint32_t)lhs - (uint32_t)rhs);
+}",
+    ),
+    (
+        "nodespeak_mul",
+        "static inline int32_t nodespeak_mul(int32_t lhs, int32_t rhs) {
+    return (int32_t)((uint32_t)lhs * (uint32_t)rhs);
+}",
+    ),
+    (
+        "nodespeak_neg",
+        "static inline int32_t nodespeak_neg(int32_t operand) {
+    return (int32_t)(0u - (uint32_t)operand);
+}",
+    ),
+    (
+        "nodespeak_div",
+        "static inline int32_t nodespeak_div(int32_t lhs, int32_t rhs) {
+    if (rhs == 0) {
+        return 0;
+    }
+    if (lhs == INT32_MIN && rhs == -1) {
+        return INT32_MIN;
+    }
+    return lhs / rhs;
+}",
+    ),
+    (
+        "nodespeak_rem",
+        "static inline int32_t nodespeak_rem(int32_t lhs, int32_t rhs) {
+    if (rhs == 0 || rhs == -1) {
+        return 0;
+    }
+    return lhs % rhs;
+}",
+    ),
+    (
+        "nodespeak_pow",
+        "static inline int32_t nodespeak_pow(int32_t lhs, int32_t rhs) {
+    uint32_t base = (uint32_t)lhs;
+    uint32_t exponent = rhs < 0 ? 0u - (uint32_t)rhs : (uint32_t)rhs;
+    uint32_t power = 1;
+    while (exponent != 0) {
+        if (exponent & 1) {
+            power *= base;
+        }
+        base *= base;
+        exponent >>= 1;
+    }
+    if (rhs >= 0) {
+        return (int32_t)power;
+    }
+    return power == 0 ? 0 : 1 / (int32_t)power;
+}",
+    ),
+    (
+        "nodespeak_clamp",
+        "static inline int32_t nodespeak_clamp(int32_t index, int32_t max) {
+    return index < 0 ? 0 : index > max ? max : index;
+}",
+    ),
+    (
+        "nodespeak_wrap",
+        "static inline int32_t nodespeak_wrap(int32_t index, int32_t size) {
+    int32_t wrapped = index % size;
+    return wrapped < 0 ? wrapped + size : wrapped;
+}",
+    ),
+];
+
+/// Describes how the scalars of a parameter are laid out, e.g.
+/// `Array(Int, 8, 3), laid out as int32_t[3][8]`.
+fn layout(typee: &ConcreteType) -> String {
+    let name = language_type_name(typee.base);
+    if typee.dims.is_empty() {
+        return format!("{}, laid out as {}[1]", name, type_name(typee.base));
+    }
+    let dims: Vec<_> = typee.dims.iter().map(|dim| format!("{}", dim)).collect();
+    let c_dims: String = typee
+        .dims
+        .iter()
+        .rev()
+        .map(|dim| format!("[{}]", dim))
+        .collect();
+    format!(
+        "Array({}, {}), laid out as {}{}",
+        name,
+        dims.join(", "),
+        type_name(typee.base),
+        c_dims
+    )
+}
+
+struct Emitter<'a> {
+    schedule: &'a Schedule,
+    code: String,
+    indent: usize,
+    helpers: HashSet<&'static str>,
+}
+
+impl<'a> Emitter<'a> {
+    /// The start of a loop over the lanes of a vector.
+    fn lane_loop(&self) -> String {
+        format!("for (int l = 0; l < {}; l++) {{", self.schedule.lanes)
+    }
+
+    fn line(&mut self, text: &str) {
+        for _ in 0..self.indent {
+            self.code.push_str("    ");
+        }
+        self.code.push_str(text);
+        self.code.push('\n');
+    }
+
+    fn call(&mut self, helper: &'static str, args: &[&str]) -> String {
+        self.helpers.insert(helper);
+        format!("{}({})", helper, args.join(", "))
+    }
+
+    fn name(&self, id: usize) -> String {
+        match self.schedule.info(id).shape {
+            Shape::Loop { .. } => format!("l{}", id),
+            Shape::Array { .. } => format!("m{}", id),
+            _ => format!("t{}", id),
+        }
+    }
+
+    /// Returns an expression for one lane of a value, which is the value
+    /// itself if it is a scalar.
+    fn lane(&self, id: usize, lane: &str) -> String {
+        match self.schedule.info(id).shape {
+            Shape::Vector => format!("t{}[{}]", id, lane),
+            _ => self.name(id),
+        }
+    }
+
+    /// Like `lane`, but converts the result to the given type.
+    fn lane_as(&self, id: usize, lane: &str, typee: ConcreteScalarType) -> String {
+        promote(self.lane(id, lane), self.schedule.info(id).typee, typee)
+    }
+
+    fn binary_expression(
+        &mut self,
+        op: BinaryOp,
+        typee: ConcreteScalarType,
+        lhs: &str,
+        rhs: &str,
+    ) -> String {
+        use ConcreteScalarType as T;
+        if is_comparison(op) {
+            let symbol = match op {
+                BinaryOp::Gt => ">",
+                BinaryOp::Lt => "<",
+                BinaryOp::Gte => ">=",
+                BinaryOp::Lte => "<=",
+                BinaryOp::Eq => "==",
+                _ => "!=",
+            };
+            return format!("{} {} {}", lhs, symbol, rhs);
+        }
+        match (typee, op) {
+            (T::Int, BinaryOp::Add) => self.call("nodespeak_add", &[lhs, rhs]),
+            (T::Int, BinaryOp::Sub) => self.call("nodespeak_sub", &[lhs, rhs]),
+            (T::Int, BinaryOp::Mul) => self.call("nodespeak_mul", &[lhs, rhs]),
+            (T::Int, BinaryOp::Div) => self.call("nodespeak_div", &[lhs, rhs]),
+            (T::Int, BinaryOp::Rem) => self.call("nodespeak_rem", &[lhs, rhs]),
+            (T::Int, BinaryOp::Pow) => self.call("nodespeak_pow", &[lhs, rhs]),
+            (T::Float, BinaryOp::Add) => format!("{} + {}", lhs, rhs),
+            (T::Float, BinaryOp::Sub) => format!("{} - {}", lhs, rhs),
+            (T::Float, BinaryOp::Mul) => format!("{} * {}", lhs, rhs),
+            (T::Float, BinaryOp::Div) => format!("{} / {}", lhs, rhs),
+            (T::Float, BinaryOp::Rem) => format!("fmodf({}, {})", lhs, rhs),
+            (T::Float, BinaryOp::Pow) => format!("powf({}, {})", lhs, rhs),
+            (T::Int | T::Bool, BinaryOp::And) => format!("{} & {}", lhs, rhs),
+            (T::Int | T::Bool, BinaryOp::Or) => format!("{} | {}", lhs, rhs),
+            (T::Int | T::Bool, BinaryOp::Xor) => format!("{} ^ {}", lhs, rhs),
+            _ => panic!("{:?} cannot be applied to {:?}s.", op, typee),
+        }
+    }
+
+    /// Returns an expression computing one lane of an elementwise value.
+    fn expression(&mut self, value: &ConcreteValue, args: &[usize], lane: &str) -> String {
+        use ConcreteScalarType as T;
+        let schedule = self.schedule;
+        let arg_type = |index: usize| schedule.info(args[index]).typee;
+        match value {
+            ConcreteValue::IntLiteral(value) => int_literal(*value),
+            ConcreteValue::FloatLiteral(value) => float_literal(*value),
+            ConcreteValue::BoolLiteral(value) => format!("{}", value),
+            ConcreteValue::Unvectorize(_, index) => format!("t{}[{}]", args[0], index),
+            ConcreteValue::VectorizeByDuplication(..) => self.name(args[0]),
+            ConcreteValue::InputScalar { input, position } => {
+                format!("input_{}[{}]", input, position)
+            }
+            ConcreteValue::InputVector { input, position } => {
+                format!("input_{}[{}]", input, offset(*position, lane))
+            }
+            ConcreteValue::LoopInputVector(input) => {
+                format!("input_{}[{} * k + {}]", input, self.schedule.lanes, lane)
+            }
+            ConcreteValue::UnaryOp(op, _) => {
+                let operand = self.lane(args[0], lane);
+                match (op, arg_type(0)) {
+                    (UnaryOp::IntToFloat, _) => promote(operand, T::Int, T::Float),
+                    (UnaryOp::BoolToInt, _) => promote(operand, T::Bool, T::Int),
+                    (UnaryOp::BoolToFloat, _) => promote(operand, T::Bool, T::Float),
+                    (UnaryOp::Not, T::Bool) => format!("!{}", operand),
+                    (UnaryOp::Not, _) => format!("~{}", operand),
+                    (UnaryOp::Neg, T::Int) => self.call("nodespeak_neg", &[&operand]),
+                    (UnaryOp::Neg, _) => format!("-{}", operand),
+                    (UnaryOp::Noop, _) => operand,
+                }
+            }
+            ConcreteValue::BinaryOp(op, ..) => {
+                let typee = arg_type(0) + arg_type(1);
+                let lhs = self.lane_as(args[0], lane, typee);
+                let rhs = self.lane_as(args[1], lane, typee);
+                self.binary_expression(*op, typee, &lhs, &rhs)
+            }
+            ConcreteValue::BoundIndex(_, size, policy) => {
+                let index = self.lane(args[0], lane);
+                match policy {
+                    OutOfBoundsPolicy::Clamp => {
+                        self.call("nodespeak_clamp", &[&index, &format!("{}", size - 1)])
+                    }
+                    OutOfBoundsPolicy::Wrap => {
+                        self.call("nodespeak_wrap", &[&index, &format!("{}", size)])
+                    }
+                }
+            }
+            ConcreteValue::DynamicLoad { .. } | ConcreteValue::Gather { .. } => {
+                format!("m{}[{}]", args[0], self.lane(args[1], lane))
+            }
+            ConcreteValue::Select { .. } => {
+                let typee = arg_type(1) + arg_type(2);
+                format!(
+                    "{} ? {} : {}",
+                    self.lane(args[0], lane),
+                    self.lane_as(args[1], lane, typee),
+                    self.lane_as(args[2], lane, typee)
+                )
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn compute(&mut self, id: usize, value: &ConcreteValue, args: &[usize]) {
+        let info = self.schedule.info(id);
+        let typee = type_name(info.typee);
+        match value {
+            ConcreteValue::Vectorize(..) => {
+                let lanes: Vec<_> = args
+                    .iter()
+                    .map(|&arg| self.lane_as(arg, "", info.typee))
+                    .collect();
+                self.line(&format!(
+                    "{} t{}[{}] = {{{}}};",
+                    typee,
+                    id,
+                    lanes.len(),
+                    lanes.join(", ")
+                ));
+            }
+            // Rows of a loop are used where they are rather than copied.
+            ConcreteValue::LoopElement { offset, .. } => {
+                let index = if *offset == 0 {
+                    "k".to_owned()
+                } else {
+                    format!("k + {}", offset)
+                };
+                self.line(&format!(
+                    "const {} *t{} = l{}[{}];",
+                    typee, id, args[0], index
+                ));
+            }
+            ConcreteValue::Unloop(_, iteration) => {
+                self.line(&format!(
+                    "const {} *t{} = l{}[{}];",
+                    typee, id, args[0], iteration
+                ));
+            }
+            _ if info.shape == Shape::Vector => {
+                self.line(&format!("{} t{}[{}];", typee, id, self.schedule.lanes));
+                self.line(&self.lane_loop());
+                self.indent += 1;
+                let expression = self.expression(value, args, "l");
+                self.line(&format!("t{}[l] = {};", id, expression));
+                self.indent -= 1;
+                self.line("}");
+            }
+            _ => {
+                let expression = self.expression(value, args, "");
+                self.line(&format!("{} t{} = {};", typee, id, expression));
+            }
+        }
+    }
+
+    /// Writes the scalars of each component one after another into an array.
+    fn store(&mut self, target: &str, typee: ConcreteScalarType, components: &[usize]) {
+        let lanes = self.schedule.lanes;
+        let mut position = 0;
+        for &component in components {
+            let info = self.schedule.info(component);
+            match info.shape {
+                Shape::Scalar => {
+                    let value = self.lane_as(component, "", typee);
+                    self.line(&format!("{}[{}] = {};", target, position, value));
+                    position += 1;
+                }
+                Shape::Vector => {
+                    let value = self.lane_as(component, "l", typee);
+                    self.line(&self.lane_loop());
+                    let index = offset(position, "l");
+                    self.line(&format!("    {}[{}] = {};", target, index, value));
+                    self.line("}");
+                    position += lanes;
+                }
+                Shape::Loop { iterations } => {
+                    let value = promote(format!("l{}[k][l]", component), info.typee, typee);
+                    self.line(&format!("for (int k = 0; k < {}; k++) {{", iterations));
+                    self.line(&format!("    {}", self.lane_loop()));
+                    let index = offset(position, &format!("{} * k + l", lanes));
+                    self.line(&format!("        {}[{}] = {};", target, index, value));
+                    self.line("    }");
+                    self.line("}");
+                    position += lanes * iterations;
+                }
+                Shape::Array { .. } => unreachable!(),
+            }
+        }
+    }
+
+    fn step(&mut self, step: &Step) {
+        match step {
+            Step::Compute { id, value, args } => self.compute(*id, value, args),
+            Step::Store { id, components } => {
+                let info = self.schedule.info(*id);
+                let size = match info.shape {
+                    Shape::Array { size } => size,
+                    _ => unreachable!(),
+                };
+                self.line(&format!("{} m{}[{}];", type_name(info.typee), id, size));
+                self.store(&format!("m{}", id), info.typee, components);
+            }
+            Step::BeginLoop { id, iterations } => {
+                let typee = self.schedule.info(*id).typee;
+                self.line(&format!(
+                    "{} l{}[{}][{}];",
+                    type_name(typee),
+                    id,
+                    iterations,
+                    self.schedule.lanes
+                ));
+                self.line(&format!("for (int k = 0; k < {}; k++) {{", iterations));
+                self.indent += 1;
+            }
+            Step::EndLoop { id, body } => {
+                let body = self.lane(*body, "l");
+                self.line(&self.lane_loop());
+                self.line(&format!("    l{}[k][l] = {};", id, body));
+                self.line("}");
+                self.indent -= 1;
+                self.line("}");
+            }
+        }
+    }
+}
+
+/// Returns a header and source file for a C function with the given name
+/// which runs the program. Its parameters are the inputs followed by the
+/// outputs, named `input_0`, `input_1`, ... and `output_0`, `output_1`, ...
+pub fn emit(program: &ConcreteProgram, name: &str) -> CSource {
+    let schedule = Schedule::new(program);
+    let mut emitter = Emitter {
+        schedule: &schedule,
+        code: String::new(),
+        indent: 1,
+        helpers: HashSet::new(),
+    };
+    for step in &schedule.steps {
+        emitter.step(step);
+    }
+    for (index, (output, components)) in program
+        .outputs()
+        .iter()
+        .zip(schedule.outputs.iter())
+        .enumerate()
+    {
+        emitter.store(
+            &format!("output_{}", index),
+            output.typee().base,
+            components,
+        );
+    }
+
+    let mut parameters = Vec::new();
+    let mut layouts = Vec::new();
+    for (index, input) in program.inputs().iter().enumerate() {
+        parameters.push(format!(
+            "const {} input_{}[{}]",
+            type_name(input.base),
+            index,
+            input.size()
+        ));
+        layouts.push(format!("input_{}: {}", index, layout(input)));
+    }
+    for (index, output) in program.outputs().iter().enumerate() {
+        let typee = output.typee();
+        parameters.push(format!(
+            "{} output_{}[{}]",
+            type_name(typee.base),
+            index,
+            typee.size()
+        ));
+        layouts.push(format!("output_{}: {}", index, layout(typee)));
+    }
+    let signature = format!("void {}({})", name, parameters.join(", "));
+
+    let guard = format!("{}_H", name.to_uppercase());
+    let mut header = String::new();
+    writeln!(header, "#ifndef {}", guard).unwrap();
+    writeln!(header, "#define {}", guard).unwrap();
+    writeln!(header).unwrap();
+    writeln!(header, "#include <stdbool.h>").unwrap();
+    writeln!(header, "#include <stdint.h>").unwrap();
+    writeln!(header).unwrap();
+    writeln!(header, "/*").unwrap();
+    writeln!(
+        header,
+        " * Runs the program. The parameters are the inputs followed by the outputs.\n \
+        * Each one holds every scalar of a value, laid out like a C array whose\n \
+        * dimensions are those of the value's type in reverse: the outermost\n \
+        * dimension comes first and the last index varies fastest. The scalar at\n \
+        * x(i, j) of an Array(T, 8, 3) is at index i * 8 + j. Int is int32_t, Float\n \
+        * is float and Bool is bool, which must be one byte holding 0 or 1.\n \
+        *"
+    )
+    .unwrap();
+    for layout in &layouts {
+        writeln!(header, " * {}", layout).unwrap();
+    }
+    writeln!(header, " */").unwrap();
+    writeln!(header, "{};", signature).unwrap();
+    writeln!(header).unwrap();
+    writeln!(header, "#endif").unwrap();
+
+    let mut source = String::new();
+    writeln!(source, "#include \"{}.h\"", name).unwrap();
+    writeln!(source).unwrap();
+    writeln!(source, "#include <math.h>").unwrap();
+    writeln!(source).unwrap();
+    for (helper, definition) in HELPERS {
+        if emitter.helpers.contains(helper) {
+            writeln!(source, "{}\n", definition).unwrap();
+        }
+    }
+    writeln!(source, "{} {{", signature).unwrap();
+    source.push_str(&emitter.code);
+    source.push_str("}\n");
+    CSource { header, source }
+}
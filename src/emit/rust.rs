@@ -0,0 +1,454 @@
+//! Emits a program as a Rust function. Inputs are passed as `&[T; N]` and
+//! outputs as `&mut [T; N]`, where `T` is `i32`, `f32` or `bool` and the
+//! scalars are in the order given by `ConcreteType::flatten_index`. Vectors
+//! become arrays with one element per lane, computed by loops over their
+//! lanes, which the optimizer can turn into SIMD instructions. The results are exactly those
+//! `interpreter::execute` gives.
+
+use std::fmt::Write;
+
+use super::{is_comparison, Schedule, Shape, Step};
+use crate::concrete::{
+    BinaryOp, ConcreteProgram, ConcreteScalarType, ConcreteValue, OutOfBoundsPolicy, UnaryOp,
+};
+
+fn type_name(typee: ConcreteScalarType) -> &'static str {
+    match typee {
+        ConcreteScalarType::Int => "i32",
+        ConcreteScalarType::Float => "f32",
+        ConcreteScalarType::Bool => "bool",
+    }
+}
+
+fn zero(typee: ConcreteScalarType) -> &'static str {
+    match typee {
+        ConcreteScalarType::Int => "0",
+        ConcreteScalarType::Float => "0.0",
+        ConcreteScalarType::Bool => "false",
+    }
+}
+
+fn float_literal(value: f32) -> String {
+    if value.is_nan() {
+        "f32::NAN".to_owned()
+    } else if value == f32::INFINITY {
+        "f32::INFINITY".to_owned()
+    } else if value == f32::NEG_INFINITY {
+        "f32::NEG_INFINITY".to_owned()
+    } else {
+        format!("{:?}", value)
+    }
+}
+
+/// Converts an expression to a type at least as general as its own.
+fn promote(expression: String, from: ConcreteScalarType, to: ConcreteScalarType) -> String {
+    use ConcreteScalarType as T;
+    match (from, to) {
+        (T::Bool, T::Int) => format!("{} as i32", expression),
+        (T::Bool, T::Float) => format!("{} as i32 as f32", expression),
+        (T::Int, T::Float) => format!("{} as f32", expression),
+        _ => expression,
+    }
+}
+
+/// Puts parentheses around a cast so that it can be used as an operand.
+fn operand(expression: &str) -> String {
+    if expression.contains(" as ") {
+        format!("({})", expression)
+    } else {
+        expression.to_owned()
+    }
+}
+
+fn binary_expression(op: BinaryOp, typee: ConcreteScalarType, lhs: &str, rhs: &str) -> String {
+    use ConcreteScalarType as T;
+    if (typee, op) == (T::Int, BinaryOp::Pow) {
+        return format!("pow_i32({}, {})", lhs, rhs);
+    }
+    let (lhs, rhs) = (operand(lhs), operand(rhs));
+    if is_comparison(op) {
+        let symbol = match op {
+            BinaryOp::Gt => ">",
+            BinaryOp::Lt => "<",
+            BinaryOp::Gte => ">=",
+            BinaryOp::Lte => "<=",
+            BinaryOp::Eq => "==",
+            _ => "!=",
+        };
+        return format!("{} {} {}", lhs, symbol, rhs);
+    }
+    match (typee, op) {
+        (T::Int, BinaryOp::Add) => format!("{}.wrapping_add({})", lhs, rhs),
+        (T::Int, BinaryOp::Sub) => format!("{}.wrapping_sub({})", lhs, rhs),
+        (T::Int, BinaryOp::Mul) => format!("{}.wrapping_mul({})", lhs, rhs),
+        (T::Int, BinaryOp::Div) => format!(
+            "if {1} == 0 {{ 0 }} else {{ {0}.wrapping_div({1}) }}",
+            lhs, rhs
+        ),
+        (T::Int, BinaryOp::Rem) => format!(
+            "if {1} == 0 {{ 0 }} else {{ {0}.wrapping_rem({1}) }}",
+            lhs, rhs
+        ),
+        (T::Float, BinaryOp::Add) => format!("{} + {}", lhs, rhs),
+        (T::Float, BinaryOp::Sub) => format!("{} - {}", lhs, rhs),
+        (T::Float, BinaryOp::Mul) => format!("{} * {}", lhs, rhs),
+        (T::Float, BinaryOp::Div) => format!("{} / {}", lhs, rhs),
+        (T::Float, BinaryOp::Rem) => format!("{} % {}", lhs, rhs),
+        (T::Float, BinaryOp::Pow) => format!("{}.powf({})", lhs, rhs),
+        (T::Int | T::Bool, BinaryOp::And) => format!("{} & {}", lhs, rhs),
+        (T::Int | T::Bool, BinaryOp::Or) => format!("{} | {}", lhs, rhs),
+        (T::Int | T::Bool, BinaryOp::Xor) => format!("{} ^ {}", lhs, rhs),
+        _ => panic!("{:?} cannot be applied to {:?}s.", op, typee),
+    }
+}
+
+/// Matches `interpreter::execute`, which never divides by zero.
+const POW_I32: &str = "fn pow_i32(lhs: i32, rhs: i32) -> i32 {
+    if rhs >= 0 {
+        lhs.wrapping_pow(rhs as u32)
+    } else {
+        match lhs.wrapping_pow(rhs.unsigned_abs()) {
+            0 => 0,
+            power => 1 / power,
+        }
+    }
+}";
+
+struct Emitter<'a> {
+    schedule: &'a Schedule,
+    code: String,
+    indent: usize,
+    uses_pow: bool,
+}
+
+impl<'a> Emitter<'a> {
+    fn line(&mut self, text: &str) {
+        for _ in 0..self.indent {
+            self.code.push_str("    ");
+        }
+        self.code.push_str(text);
+        self.code.push('\n');
+    }
+
+    fn name(&self, id: usize) -> String {
+        match self.schedule.info(id).shape {
+            Shape::Loop { .. } => format!("l{}", id),
+            Shape::Array { .. } => format!("m{}", id),
+            _ => format!("t{}", id),
+        }
+    }
+
+    /// Returns an expression for one lane of a value, which is the value
+    /// itself if it is a scalar.
+    fn lane(&self, id: usize, lane: &str) -> String {
+        match self.schedule.info(id).shape {
+            Shape::Vector => format!("t{}[{}]", id, lane),
+            _ => self.name(id),
+        }
+    }
+
+    /// Like `lane`, but converts the result to the given type.
+    fn lane_as(&self, id: usize, lane: &str, typee: ConcreteScalarType) -> String {
+        promote(self.lane(id, lane), self.schedule.info(id).typee, typee)
+    }
+
+    /// Returns an expression computing one lane of an elementwise value.
+    fn expression(&mut self, value: &ConcreteValue, args: &[usize], lane: &str) -> String {
+        use ConcreteScalarType as T;
+        let schedule = self.schedule;
+        let arg_type = |index: usize| schedule.info(args[index]).typee;
+        match value {
+            ConcreteValue::IntLiteral(value) => format!("{}", value),
+            ConcreteValue::FloatLiteral(value) => float_literal(*value),
+            ConcreteValue::BoolLiteral(value) => format!("{}", value),
+            ConcreteValue::Unvectorize(_, index) => format!("t{}[{}]", args[0], index),
+            ConcreteValue::InputScalar { input, position } => {
+                format!("input_{}[{}]", input, position)
+            }
+            ConcreteValue::UnaryOp(op, _) => {
+                let operand = self.lane(args[0], lane);
+                match (op, arg_type(0)) {
+                    (UnaryOp::IntToFloat, _) => promote(operand, T::Int, T::Float),
+                    (UnaryOp::BoolToInt, _) => promote(operand, T::Bool, T::Int),
+                    (UnaryOp::BoolToFloat, _) => promote(operand, T::Bool, T::Float),
+                    (UnaryOp::Not, _) => format!("!{}", operand),
+                    (UnaryOp::Neg, T::Int) => format!("{}.wrapping_neg()", operand),
+                    (UnaryOp::Neg, _) => format!("-{}", operand),
+                    (UnaryOp::Noop, _) => operand,
+                }
+            }
+            ConcreteValue::BinaryOp(op, ..) => {
+                let typee = arg_type(0) + arg_type(1);
+                if *op == BinaryOp::Pow && typee == T::Int {
+                    self.uses_pow = true;
+                }
+                let lhs = self.lane_as(args[0], lane, typee);
+                let rhs = self.lane_as(args[1], lane, typee);
+                binary_expression(*op, typee, &lhs, &rhs)
+            }
+            ConcreteValue::BoundIndex(_, size, policy) => {
+                let index = self.lane(args[0], lane);
+                match policy {
+                    OutOfBoundsPolicy::Clamp => format!("{}.clamp(0, {})", index, size - 1),
+                    OutOfBoundsPolicy::Wrap => format!("{}.rem_euclid({})", index, size),
+                }
+            }
+            ConcreteValue::DynamicLoad { .. } | ConcreteValue::Gather { .. } => {
+                format!("m{}[{} as usize]", args[0], self.lane(args[1], lane))
+            }
+            ConcreteValue::Select { .. } => {
+                let typee = arg_type(1) + arg_type(2);
+                format!(
+                    "if {} {{ {} }} else {{ {} }}",
+                    self.lane(args[0], lane),
+                    self.lane_as(args[1], lane, typee),
+                    self.lane_as(args[2], lane, typee)
+                )
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn compute(&mut self, id: usize, value: &ConcreteValue, args: &[usize]) {
+        let info = self.schedule.info(id);
+        let typee = type_name(info.typee);
+        let lanes = self.schedule.lanes;
+        match value {
+            ConcreteValue::Vectorize(..) => {
+                let lanes: Vec<_> = args
+                    .iter()
+                    .map(|&arg| self.lane_as(arg, "", info.typee))
+                    .collect();
+                self.line(&format!(
+                    "let t{}: [{}; {}] = [{}];",
+                    id,
+                    typee,
+                    lanes.len(),
+                    lanes.join(", ")
+                ));
+            }
+            ConcreteValue::VectorizeByDuplication(..) => {
+                self.line(&format!(
+                    "let t{}: [{}; {}] = [t{}; {}];",
+                    id, typee, lanes, args[0], lanes
+                ));
+            }
+            ConcreteValue::InputVector { input, position } => {
+                self.line(&format!(
+                    "let mut t{} = [{}; {}];",
+                    id,
+                    zero(info.typee),
+                    lanes
+                ));
+                self.line(&format!(
+                    "t{}.copy_from_slice(&input_{}[{}..{}]);",
+                    id,
+                    input,
+                    position,
+                    position + lanes
+                ));
+            }
+            ConcreteValue::LoopInputVector(input) => {
+                self.line(&format!(
+                    "let mut t{} = [{}; {}];",
+                    id,
+                    zero(info.typee),
+                    lanes
+                ));
+                self.line(&format!(
+                    "t{0}.copy_from_slice(&input_{1}[{2} * k..{2} * k + {2}]);",
+                    id, input, lanes
+                ));
+            }
+            ConcreteValue::LoopElement { offset, .. } => {
+                let index = if *offset == 0 {
+                    "k".to_owned()
+                } else {
+                    format!("k + {}", offset)
+                };
+                self.line(&format!(
+                    "let t{}: [{}; {}] = l{}[{}];",
+                    id, typee, lanes, args[0], index
+                ));
+            }
+            ConcreteValue::Unloop(_, iteration) => {
+                self.line(&format!(
+                    "let t{}: [{}; {}] = l{}[{}];",
+                    id, typee, lanes, args[0], iteration
+                ));
+            }
+            _ if info.shape == Shape::Vector => {
+                self.line(&format!(
+                    "let mut t{} = [{}; {}];",
+                    id,
+                    zero(info.typee),
+                    lanes
+                ));
+                self.line(&format!("for l in 0..{} {{", lanes));
+                self.indent += 1;
+                let expression = self.expression(value, args, "l");
+                self.line(&format!("t{}[l] = {};", id, expression));
+                self.indent -= 1;
+                self.line("}");
+            }
+            _ => {
+                let expression = self.expression(value, args, "");
+                self.line(&format!("let t{}: {} = {};", id, typee, expression));
+            }
+        }
+    }
+
+    /// Writes the scalars of each component one after another into an array.
+    fn store(&mut self, target: &str, typee: ConcreteScalarType, components: &[usize]) {
+        let lanes = self.schedule.lanes;
+        let mut position = 0;
+        for &component in components {
+            let info = self.schedule.info(component);
+            let same_type = info.typee == typee;
+            match info.shape {
+                Shape::Scalar => {
+                    let value = self.lane_as(component, "", typee);
+                    self.line(&format!("{}[{}] = {};", target, position, value));
+                    position += 1;
+                }
+                Shape::Vector if same_type => {
+                    self.line(&format!(
+                        "{}[{}..{}].copy_from_slice(&t{});",
+                        target,
+                        position,
+                        position + lanes,
+                        component
+                    ));
+                    position += lanes;
+                }
+                Shape::Vector => {
+                    self.line(&format!("for l in 0..{} {{", lanes));
+                    let value = self.lane_as(component, "l", typee);
+                    self.line(&format!("    {}[{} + l] = {};", target, position, value));
+                    self.line("}");
+                    position += lanes;
+                }
+                Shape::Loop { iterations } => {
+                    let start = if position == 0 {
+                        format!("{} * k", lanes)
+                    } else {
+                        format!("{} + {} * k", position, lanes)
+                    };
+                    self.line(&format!("for k in 0..{} {{", iterations));
+                    if same_type {
+                        self.line(&format!(
+                            "    {}[{}..{} + {}].copy_from_slice(&l{}[k]);",
+                            target, start, start, lanes, component
+                        ));
+                    } else {
+                        let value = promote(format!("l{}[k][l]", component), info.typee, typee);
+                        self.line(&format!("    for l in 0..{} {{", lanes));
+                        self.line(&format!("        {}[{} + l] = {};", target, start, value));
+                        self.line("    }");
+                    }
+                    self.line("}");
+                    position += lanes * iterations;
+                }
+                Shape::Array { .. } => unreachable!(),
+            }
+        }
+    }
+
+    fn step(&mut self, step: &Step) {
+        match step {
+            Step::Compute { id, value, args } => self.compute(*id, value, args),
+            Step::Store { id, components } => {
+                let info = self.schedule.info(*id);
+                let size = match info.shape {
+                    Shape::Array { size } => size,
+                    _ => unreachable!(),
+                };
+                self.line(&format!(
+                    "let mut m{} = [{}; {}];",
+                    id,
+                    zero(info.typee),
+                    size
+                ));
+                self.store(&format!("m{}", id), info.typee, components);
+            }
+            Step::BeginLoop { id, iterations } => {
+                let typee = self.schedule.info(*id).typee;
+                self.line(&format!(
+                    "let mut l{} = [[{}; {}]; {}];",
+                    id,
+                    zero(typee),
+                    self.schedule.lanes,
+                    iterations
+                ));
+                self.line(&format!("for k in 0..{} {{", iterations));
+                self.indent += 1;
+            }
+            Step::EndLoop { id, body } => {
+                let body = match self.schedule.info(*body).shape {
+                    Shape::Vector => format!("t{}", body),
+                    _ => format!("[t{}; {}]", body, self.schedule.lanes),
+                };
+                self.line(&format!("l{}[k] = {};", id, body));
+                self.indent -= 1;
+                self.line("}");
+            }
+        }
+    }
+}
+
+/// Returns the source of a Rust function with the given name which runs the
+/// program. Its parameters are the inputs followed by the outputs, named
+/// `input_0`, `input_1`, ... and `output_0`, `output_1`, ...
+pub fn emit(program: &ConcreteProgram, name: &str) -> String {
+    let schedule = Schedule::new(program);
+    let mut emitter = Emitter {
+        schedule: &schedule,
+        code: String::new(),
+        indent: 1,
+        uses_pow: false,
+    };
+    for step in &schedule.steps {
+        emitter.step(step);
+    }
+    for (index, (output, components)) in program
+        .outputs()
+        .iter()
+        .zip(schedule.outputs.iter())
+        .enumerate()
+    {
+        emitter.store(
+            &format!("output_{}", index),
+            output.typee().base,
+            components,
+        );
+    }
+
+    let mut parameters = Vec::new();
+    for (index, input) in program.inputs().iter().enumerate() {
+        parameters.push(format!(
+            "input_{}: &[{}; {}]",
+            index,
+            type_name(input.base),
+            input.size()
+        ));
+    }
+    for (index, output) in program.outputs().iter().enumerate() {
+        let typee = output.typee();
+        parameters.push(format!(
+            "output_{}: &mut [{}; {}]",
+            index,
+            type_name(typee.base),
+            typee.size()
+        ));
+    }
+    let mut source = String::new();
+    writeln!(source, "pub fn {}({}) {{", name, parameters.join(", ")).unwrap();
+    if emitter.uses_pow {
+        for line in POW_I32.lines() {
+            writeln!(source, "    {}", line).unwrap();
+        }
+    }
+    source.push_str(&emitter.code);
+    source.push_str("}\n");
+    source
+}
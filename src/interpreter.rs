@@ -0,0 +1,447 @@
+use std::collections::HashMap;
+
+use crate::concrete::{
+    BinaryOp, ConcreteMultiValue, ConcreteProgram, ConcreteScalarType, ConcreteValue,
+    ConcreteValuePtr, OutOfBoundsPolicy, UnaryOp,
+};
+
+/// A single value read from an input or written to an output of a program.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scalar {
+    Int(i32),
+    Float(f32),
+    Bool(bool),
+}
+
+impl Scalar {
+    fn scalar_type(self) -> ConcreteScalarType {
+        match self {
+            Self::Int(..) => ConcreteScalarType::Int,
+            Self::Float(..) => ConcreteScalarType::Float,
+            Self::Bool(..) => ConcreteScalarType::Bool,
+        }
+    }
+
+    /// Converts this scalar to a type at least as general as its own, the
+    /// same way the operands of a binary operation are combined.
+    fn promote(self, to: ConcreteScalarType) -> Self {
+        match (self, to) {
+            (Self::Bool(value), ConcreteScalarType::Int) => Self::Int(value as i32),
+            (Self::Bool(value), ConcreteScalarType::Float) => Self::Float(value as i32 as f32),
+            (Self::Int(value), ConcreteScalarType::Float) => Self::Float(value as f32),
+            _ => self,
+        }
+    }
+
+    fn as_int(self) -> i32 {
+        match self {
+            Self::Int(value) => value,
+            _ => panic!("Expected an Int, got {:?}.", self),
+        }
+    }
+
+    fn as_bool(self) -> bool {
+        match self {
+            Self::Bool(value) => value,
+            _ => panic!("Expected a Bool, got {:?}.", self),
+        }
+    }
+}
+
+/// What a `ConcreteValue` evaluates to.
+#[derive(Clone, Debug)]
+enum Lanes {
+    Scalar(Scalar),
+    Vector(Vec<Scalar>),
+}
+
+impl Lanes {
+    /// Returns the scalar in the given lane. Scalars are broadcast to every
+    /// lane.
+    fn lane(&self, lane: usize) -> Scalar {
+        match self {
+            Self::Scalar(scalar) => *scalar,
+            Self::Vector(lanes) => lanes[lane],
+        }
+    }
+
+    fn scalar(&self) -> Scalar {
+        match self {
+            Self::Scalar(scalar) => *scalar,
+            Self::Vector(..) => panic!("Expected a scalar, got a vector."),
+        }
+    }
+
+    fn vector(&self) -> &[Scalar] {
+        match self {
+            Self::Vector(lanes) => lanes,
+            Self::Scalar(..) => panic!("Expected a vector, got a scalar."),
+        }
+    }
+
+    /// Applies `op` to the corresponding lanes of each operand. The result is
+    /// a vector if any operand is.
+    fn zip(operands: &[Lanes], mut op: impl FnMut(&[Scalar]) -> Scalar) -> Self {
+        let vector = operands.iter().find_map(|operand| match operand {
+            Self::Vector(lanes) => Some(lanes.len()),
+            Self::Scalar(..) => None,
+        });
+        if let Some(lanes) = vector {
+            let result = (0..lanes)
+                .map(|lane| {
+                    let scalars: Vec<_> =
+                        operands.iter().map(|operand| operand.lane(lane)).collect();
+                    op(&scalars)
+                })
+                .collect();
+            Self::Vector(result)
+        } else {
+            let scalars: Vec<_> = operands.iter().map(Self::scalar).collect();
+            Self::Scalar(op(&scalars))
+        }
+    }
+}
+
+fn unary_op(op: UnaryOp, operand: Scalar) -> Scalar {
+    match (op, operand) {
+        (UnaryOp::IntToFloat, Scalar::Int(value)) => Scalar::Float(value as f32),
+        (UnaryOp::BoolToInt, Scalar::Bool(value)) => Scalar::Int(value as i32),
+        (UnaryOp::BoolToFloat, Scalar::Bool(value)) => Scalar::Float(value as i32 as f32),
+        (UnaryOp::Not, Scalar::Int(value)) => Scalar::Int(!value),
+        (UnaryOp::Not, Scalar::Bool(value)) => Scalar::Bool(!value),
+        (UnaryOp::Neg, Scalar::Int(value)) => Scalar::Int(value.wrapping_neg()),
+        (UnaryOp::Neg, Scalar::Float(value)) => Scalar::Float(-value),
+        (UnaryOp::Noop, _) => operand,
+        _ => panic!("{:?} cannot be applied to {:?}.", op, operand),
+    }
+}
+
+/// Integer arithmetic wraps on overflow. Dividing by zero or taking the
+/// remainder of a division by zero gives zero.
+fn int_op(op: BinaryOp, lhs: i32, rhs: i32) -> Scalar {
+    match op {
+        BinaryOp::Add => Scalar::Int(lhs.wrapping_add(rhs)),
+        BinaryOp::Sub => Scalar::Int(lhs.wrapping_sub(rhs)),
+        BinaryOp::Mul => Scalar::Int(lhs.wrapping_mul(rhs)),
+        BinaryOp::Div if rhs == 0 => Scalar::Int(0),
+        BinaryOp::Div => Scalar::Int(lhs.wrapping_div(rhs)),
+        BinaryOp::Rem if rhs == 0 => Scalar::Int(0),
+        BinaryOp::Rem => Scalar::Int(lhs.wrapping_rem(rhs)),
+        BinaryOp::Pow => Scalar::Int(if rhs >= 0 {
+            lhs.wrapping_pow(rhs as u32)
+        } else {
+            1i32.checked_div(lhs.wrapping_pow(rhs.unsigned_abs()))
+                .unwrap_or(0)
+        }),
+
+        BinaryOp::Gt => Scalar::Bool(lhs > rhs),
+        BinaryOp::Lt => Scalar::Bool(lhs < rhs),
+        BinaryOp::Gte => Scalar::Bool(lhs >= rhs),
+        BinaryOp::Lte => Scalar::Bool(lhs <= rhs),
+        BinaryOp::Eq => Scalar::Bool(lhs == rhs),
+        BinaryOp::Neq => Scalar::Bool(lhs != rhs),
+
+        BinaryOp::And => Scalar::Int(lhs & rhs),
+        BinaryOp::Or => Scalar::Int(lhs | rhs),
+        BinaryOp::Xor => Scalar::Int(lhs ^ rhs),
+    }
+}
+
+fn float_op(op: BinaryOp, lhs: f32, rhs: f32) -> Scalar {
+    match op {
+        BinaryOp::Add => Scalar::Float(lhs + rhs),
+        BinaryOp::Sub => Scalar::Float(lhs - rhs),
+        BinaryOp::Mul => Scalar::Float(lhs * rhs),
+        BinaryOp::Div => Scalar::Float(lhs / rhs),
+        BinaryOp::Rem => Scalar::Float(lhs % rhs),
+        BinaryOp::Pow => Scalar::Float(lhs.powf(rhs)),
+
+        BinaryOp::Gt => Scalar::Bool(lhs > rhs),
+        BinaryOp::Lt => Scalar::Bool(lhs < rhs),
+        BinaryOp::Gte => Scalar::Bool(lhs >= rhs),
+        BinaryOp::Lte => Scalar::Bool(lhs <= rhs),
+        BinaryOp::Eq => Scalar::Bool(lhs == rhs),
+        BinaryOp::Neq => Scalar::Bool(lhs != rhs),
+
+        BinaryOp::And | BinaryOp::Or | BinaryOp::Xor => {
+            panic!("{:?} cannot be applied to Floats.", op)
+        }
+    }
+}
+
+fn bool_op(op: BinaryOp, lhs: bool, rhs: bool) -> Scalar {
+    match op {
+        BinaryOp::Eq => Scalar::Bool(lhs == rhs),
+        BinaryOp::Neq => Scalar::Bool(lhs != rhs),
+
+        BinaryOp::And => Scalar::Bool(lhs & rhs),
+        BinaryOp::Or => Scalar::Bool(lhs | rhs),
+        BinaryOp::Xor => Scalar::Bool(lhs ^ rhs),
+
+        _ => panic!("{:?} cannot be applied to Bools.", op),
+    }
+}
+
+/// Operands of different types are first converted to the more general of
+/// the two, in the order Bool, Int, Float.
+pub(crate) fn binary_op(op: BinaryOp, lhs: Scalar, rhs: Scalar) -> Scalar {
+    let typee = lhs.scalar_type() + rhs.scalar_type();
+    match (lhs.promote(typee), rhs.promote(typee)) {
+        (Scalar::Int(lhs), Scalar::Int(rhs)) => int_op(op, lhs, rhs),
+        (Scalar::Float(lhs), Scalar::Float(rhs)) => float_op(op, lhs, rhs),
+        (Scalar::Bool(lhs), Scalar::Bool(rhs)) => bool_op(op, lhs, rhs),
+        _ => unreachable!(),
+    }
+}
+
+fn bound_index(index: Scalar, size: usize, policy: OutOfBoundsPolicy) -> Scalar {
+    let index = index.as_int();
+    let size = size as i32;
+    Scalar::Int(match policy {
+        OutOfBoundsPolicy::Clamp => index.clamp(0, size - 1),
+        OutOfBoundsPolicy::Wrap => index.rem_euclid(size),
+    })
+}
+
+struct Interpreter<'a> {
+    inputs: &'a [&'a [Scalar]],
+    /// How many scalars each vector is made of.
+    lanes: usize,
+    /// The iteration of the loop whose body is being evaluated, if any.
+    iteration: Option<usize>,
+    /// Results of values which don't depend on the iteration of a loop.
+    invariant: HashMap<*const ConcreteValue, Lanes>,
+    /// Results of values which do, for each iteration they were evaluated on.
+    per_iteration: HashMap<(*const ConcreteValue, usize), Lanes>,
+    /// The scalars making up each multi-value that has been read from,
+    /// identified by its components.
+    flattened: HashMap<Vec<*const ConcreteValue>, (Vec<Scalar>, bool)>,
+}
+
+impl<'a> Interpreter<'a> {
+    fn new(inputs: &'a [&'a [Scalar]], lanes: usize) -> Self {
+        Self {
+            inputs,
+            lanes,
+            iteration: None,
+            invariant: HashMap::new(),
+            per_iteration: HashMap::new(),
+            flattened: HashMap::new(),
+        }
+    }
+
+    fn current_iteration(&self) -> usize {
+        self.iteration
+            .expect("Loop elements can only be used inside the body of a loop.")
+    }
+
+    /// Evaluates `body` as it would be on the given iteration of its loop.
+    fn evaluate_on_iteration(&mut self, body: &ConcreteValuePtr, iteration: usize) -> Lanes {
+        let outer = self.iteration.replace(iteration);
+        let (result, _) = self.evaluate(body);
+        self.iteration = outer;
+        result
+    }
+
+    fn loop_body(value: &ConcreteValuePtr) -> &ConcreteValuePtr {
+        match &**value {
+            ConcreteValue::Loop { body, .. } => body,
+            other => panic!("Expected a loop, got {:?}.", other),
+        }
+    }
+
+    /// Returns the value's result and whether it depends on the iteration of
+    /// the loop being evaluated.
+    fn evaluate(&mut self, value: &ConcreteValuePtr) -> (Lanes, bool) {
+        let key = &**value as *const ConcreteValue;
+        if let Some(result) = self.invariant.get(&key) {
+            return (result.clone(), false);
+        }
+        if let Some(iteration) = self.iteration {
+            if let Some(result) = self.per_iteration.get(&(key, iteration)) {
+                return (result.clone(), true);
+            }
+        }
+        let (result, dependent) = self.evaluate_uncached(value);
+        if dependent {
+            self.per_iteration
+                .insert((key, self.current_iteration()), result.clone());
+        } else {
+            self.invariant.insert(key, result.clone());
+        }
+        (result, dependent)
+    }
+
+    fn evaluate_all(&mut self, values: &[&ConcreteValuePtr]) -> (Vec<Lanes>, bool) {
+        let mut dependent = false;
+        let mut results = Vec::new();
+        for value in values {
+            let (result, value_dependent) = self.evaluate(value);
+            results.push(result);
+            dependent |= value_dependent;
+        }
+        (results, dependent)
+    }
+
+    fn evaluate_uncached(&mut self, value: &ConcreteValuePtr) -> (Lanes, bool) {
+        match &**value {
+            ConcreteValue::IntLiteral(value) => (Lanes::Scalar(Scalar::Int(*value)), false),
+            ConcreteValue::FloatLiteral(value) => (Lanes::Scalar(Scalar::Float(*value)), false),
+            ConcreteValue::BoolLiteral(value) => (Lanes::Scalar(Scalar::Bool(*value)), false),
+            ConcreteValue::Unvectorize(vector, lane) => {
+                let (vector, dependent) = self.evaluate(vector);
+                (Lanes::Scalar(vector.vector()[*lane]), dependent)
+            }
+            ConcreteValue::Vectorize(lanes) => {
+                let (lanes, dependent) = self.evaluate_all(&lanes.iter().collect::<Vec<_>>());
+                let result = lanes.iter().map(Lanes::scalar).collect();
+                (Lanes::Vector(result), dependent)
+            }
+            ConcreteValue::VectorizeByDuplication(scalar) => {
+                let (scalar, dependent) = self.evaluate(scalar);
+                (Lanes::Vector(vec![scalar.scalar(); self.lanes]), dependent)
+            }
+            ConcreteValue::InputScalar { input, position } => {
+                (Lanes::Scalar(self.inputs[*input][*position]), false)
+            }
+            ConcreteValue::InputVector { input, position } => {
+                (Lanes::Vector(self.input_vector(*input, *position)), false)
+            }
+            ConcreteValue::UnaryOp(op, operand) => {
+                let (operand, dependent) = self.evaluate(operand);
+                let result = Lanes::zip(&[operand], |operand| unary_op(*op, operand[0]));
+                (result, dependent)
+            }
+            ConcreteValue::BinaryOp(op, lhs, rhs) => {
+                let (operands, dependent) = self.evaluate_all(&[lhs, rhs]);
+                let result = Lanes::zip(&operands, |operands| {
+                    binary_op(*op, operands[0], operands[1])
+                });
+                (result, dependent)
+            }
+            ConcreteValue::BoundIndex(index, size, policy) => {
+                let (index, dependent) = self.evaluate(index);
+                let result = Lanes::zip(&[index], |index| bound_index(index[0], *size, *policy));
+                (result, dependent)
+            }
+            ConcreteValue::DynamicLoad { source, index } => {
+                let (index, index_dependent) = self.evaluate(index);
+                let (source, source_dependent) = self.flatten(source);
+                let result = source[index.scalar().as_int() as usize];
+                (Lanes::Scalar(result), index_dependent || source_dependent)
+            }
+            ConcreteValue::Gather { source, indices } => {
+                let (indices, indices_dependent) = self.evaluate(indices);
+                let (source, source_dependent) = self.flatten(source);
+                let result = (0..self.lanes)
+                    .map(|lane| source[indices.lane(lane).as_int() as usize])
+                    .collect();
+                (Lanes::Vector(result), indices_dependent || source_dependent)
+            }
+            ConcreteValue::Select {
+                condition,
+                if_true,
+                if_false,
+            } => {
+                let (operands, dependent) = self.evaluate_all(&[condition, if_true, if_false]);
+                let result = Lanes::zip(&operands, |operands| {
+                    if operands[0].as_bool() {
+                        operands[1]
+                    } else {
+                        operands[2]
+                    }
+                });
+                (result, dependent)
+            }
+            ConcreteValue::Loop { .. } => {
+                panic!("A loop stands for several vectors, so it can't be used as a value.")
+            }
+            ConcreteValue::LoopElement { source, offset } => {
+                let iteration = self.current_iteration() + offset;
+                let body = Self::loop_body(source);
+                (self.evaluate_on_iteration(body, iteration), true)
+            }
+            ConcreteValue::LoopInputVector(input) => {
+                let position = self.lanes * self.current_iteration();
+                (Lanes::Vector(self.input_vector(*input, position)), true)
+            }
+            ConcreteValue::Unloop(source, iteration) => {
+                let body = Self::loop_body(source);
+                (self.evaluate_on_iteration(body, *iteration), false)
+            }
+        }
+    }
+
+    fn input_vector(&self, input: usize, position: usize) -> Vec<Scalar> {
+        self.inputs[input][position..position + self.lanes].to_vec()
+    }
+
+    /// Returns every scalar of a multi-value in order, and whether any of
+    /// them depend on the iteration of the loop being evaluated.
+    fn flatten(&mut self, value: &ConcreteMultiValue) -> (Vec<Scalar>, bool) {
+        let key: Vec<_> = value
+            .components()
+            .iter()
+            .map(|component| &**component as *const ConcreteValue)
+            .collect();
+        if let Some((scalars, false)) = self.flattened.get(&key) {
+            return (scalars.clone(), false);
+        }
+        let mut scalars = Vec::new();
+        let mut dependent = false;
+        for component in value.components() {
+            if let ConcreteValue::Loop { iterations, body } = &**component {
+                for iteration in 0..*iterations {
+                    let vector = self.evaluate_on_iteration(body, iteration);
+                    scalars.extend_from_slice(vector.vector());
+                }
+                continue;
+            }
+            let (result, component_dependent) = self.evaluate(component);
+            dependent |= component_dependent;
+            match result {
+                Lanes::Scalar(scalar) => scalars.push(scalar),
+                Lanes::Vector(lanes) => scalars.extend_from_slice(&lanes),
+            }
+        }
+        if !dependent {
+            self.flattened.insert(key, (scalars.clone(), false));
+        }
+        (scalars, dependent)
+    }
+}
+
+/// Runs a program, returning the scalars of each output in the order given
+/// by `ConcreteType::flatten_index`. `inputs` holds the scalars of each input
+/// in the same order. Panics if the inputs don't match the types the program
+/// expects.
+pub fn execute(program: &ConcreteProgram, inputs: &[&[Scalar]]) -> Vec<Vec<Scalar>> {
+    assert_eq!(
+        inputs.len(),
+        program.inputs().len(),
+        "Wrong number of inputs."
+    );
+    for (index, (input, typee)) in inputs.iter().zip(program.inputs()).enumerate() {
+        assert_eq!(
+            input.len(),
+            typee.size(),
+            "Input {} has the wrong number of scalars.",
+            index
+        );
+        for scalar in input.iter() {
+            assert_eq!(
+                scalar.scalar_type(),
+                typee.base,
+                "Input {} should only hold {:?} values.",
+                index,
+                typee.base
+            );
+        }
+    }
+    let mut interpreter = Interpreter::new(inputs, program.lanes());
+    program
+        .outputs()
+        .iter()
+        .map(|output| interpreter.flatten(output).0)
+        .collect()
+}
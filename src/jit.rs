@@ -0,0 +1,1219 @@
+//! Compiles a `ConcreteProgram` to native code with Cranelift.
+//!
+//! The compiled function takes two arguments: a pointer to an array holding a
+//! pointer to the buffer of each input, and a pointer to an array holding a
+//! pointer to the buffer of each output. Each buffer holds the scalars of its
+//! value in the order given by `ConcreteType::flatten_index`. Ints are stored
+//! as `i32`, Floats as `f32` and Bools as one byte which is either 0 or 1.
+//!
+//! Vectors are kept in as many 4-lane SIMD registers as it takes to hold
+//! every lane, so programs must have one lane or a multiple of four.
+//! Operations which have no SIMD instruction are done one lane at a time. The
+//! results are exactly those `interpreter::execute` gives.
+
+use std::{collections::HashMap, convert::TryInto, fmt};
+
+use cranelift_codegen::{
+    ir::{
+        condcodes::{FloatCC, IntCC},
+        types, AbiParam, Block, Endianness, FuncRef, InstBuilder, MemFlags, Signature, StackSlot,
+        StackSlotData, StackSlotKind, Type, Value,
+    },
+    settings::{self, Configurable},
+    CodegenError,
+};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Linkage, Module, ModuleError};
+
+use crate::{
+    concrete::{
+        BinaryOp, ConcreteMultiValue, ConcreteProgram, ConcreteScalarType, ConcreteType,
+        ConcreteValue, ConcreteValuePtr, OutOfBoundsPolicy, UnaryOp,
+    },
+    interpreter::{binary_op, Scalar},
+};
+
+/// The signature of a compiled program. The first argument points to the
+/// input buffers and the second to the output buffers.
+pub type CompiledFunction = unsafe extern "C" fn(*const *const u8, *const *mut u8);
+
+#[derive(Debug)]
+pub enum JitError {
+    /// Cranelift can't generate code for the machine this is running on.
+    UnsupportedHost(String),
+    /// Vectors with this many lanes don't fit in 4-lane registers.
+    UnsupportedLanes(usize),
+    Codegen(CodegenError),
+    Module(Box<ModuleError>),
+}
+
+impl fmt::Display for JitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UnsupportedHost(reason) => write!(f, "unsupported host: {}", reason),
+            Self::UnsupportedLanes(lanes) => write!(f, "unsupported lane count: {}", lanes),
+            Self::Codegen(error) => write!(f, "{}", error),
+            Self::Module(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for JitError {}
+
+impl From<CodegenError> for JitError {
+    fn from(error: CodegenError) -> Self {
+        Self::Codegen(error)
+    }
+}
+
+impl From<ModuleError> for JitError {
+    fn from(error: ModuleError) -> Self {
+        Self::Module(Box::new(error))
+    }
+}
+
+/// A program compiled to native code. The code is freed when this is dropped.
+pub struct JitProgram {
+    module: Option<JITModule>,
+    function: CompiledFunction,
+    inputs: Vec<ConcreteType>,
+    outputs: Vec<ConcreteType>,
+}
+
+impl JitProgram {
+    /// Returns the compiled function. It must not be called after this
+    /// program is dropped.
+    pub fn function(&self) -> CompiledFunction {
+        self.function
+    }
+
+    /// Runs the program on buffers laid out as described in the module
+    /// documentation.
+    ///
+    /// # Safety
+    /// There must be a pointer for each input and output of the program, each
+    /// pointing to a buffer large enough to hold its value.
+    pub unsafe fn call(&self, inputs: &[*const u8], outputs: &[*mut u8]) {
+        assert_eq!(inputs.len(), self.inputs.len(), "Wrong number of inputs.");
+        assert_eq!(
+            outputs.len(),
+            self.outputs.len(),
+            "Wrong number of outputs."
+        );
+        (self.function)(inputs.as_ptr(), outputs.as_ptr())
+    }
+
+    /// Runs the program, taking and returning values the same way
+    /// `interpreter::execute` does.
+    pub fn run(&self, inputs: &[&[Scalar]]) -> Vec<Vec<Scalar>> {
+        assert_eq!(inputs.len(), self.inputs.len(), "Wrong number of inputs.");
+        let input_buffers: Vec<_> = inputs
+            .iter()
+            .zip(self.inputs.iter())
+            .enumerate()
+            .map(|(index, (input, typee))| {
+                assert_eq!(
+                    input.len(),
+                    typee.size(),
+                    "Input {} has the wrong number of scalars.",
+                    index
+                );
+                encode(input, typee.base)
+            })
+            .collect();
+        let mut output_buffers: Vec<_> = self
+            .outputs
+            .iter()
+            .map(|typee| vec![0; typee.size() * external_size(typee.base)])
+            .collect();
+        let input_pointers: Vec<_> = input_buffers.iter().map(|buffer| buffer.as_ptr()).collect();
+        let output_pointers: Vec<_> = output_buffers
+            .iter_mut()
+            .map(|buffer| buffer.as_mut_ptr())
+            .collect();
+        unsafe {
+            self.call(&input_pointers, &output_pointers);
+        }
+        output_buffers
+            .iter()
+            .zip(self.outputs.iter())
+            .map(|(buffer, typee)| decode(buffer, typee.base))
+            .collect()
+    }
+}
+
+impl Drop for JitProgram {
+    fn drop(&mut self) {
+        if let Some(module) = self.module.take() {
+            unsafe { module.free_memory() };
+        }
+    }
+}
+
+/// How many bytes a scalar takes up in an input or output buffer.
+fn external_size(typee: ConcreteScalarType) -> usize {
+    match typee {
+        ConcreteScalarType::Bool => 1,
+        _ => 4,
+    }
+}
+
+fn encode(scalars: &[Scalar], typee: ConcreteScalarType) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for &scalar in scalars {
+        match (scalar, typee) {
+            (Scalar::Int(value), ConcreteScalarType::Int) => {
+                bytes.extend_from_slice(&value.to_ne_bytes())
+            }
+            (Scalar::Float(value), ConcreteScalarType::Float) => {
+                bytes.extend_from_slice(&value.to_ne_bytes())
+            }
+            (Scalar::Bool(value), ConcreteScalarType::Bool) => bytes.push(value as u8),
+            _ => panic!("Expected a {:?}, got {:?}.", typee, scalar),
+        }
+    }
+    bytes
+}
+
+fn decode(bytes: &[u8], typee: ConcreteScalarType) -> Vec<Scalar> {
+    match typee {
+        ConcreteScalarType::Int => bytes
+            .chunks_exact(4)
+            .map(|chunk| Scalar::Int(i32::from_ne_bytes(chunk.try_into().unwrap())))
+            .collect(),
+        ConcreteScalarType::Float => bytes
+            .chunks_exact(4)
+            .map(|chunk| Scalar::Float(f32::from_ne_bytes(chunk.try_into().unwrap())))
+            .collect(),
+        ConcreteScalarType::Bool => bytes.iter().map(|&byte| Scalar::Bool(byte != 0)).collect(),
+    }
+}
+
+// Operations without a Cranelift instruction call back into the interpreter so
+// that they give the same results.
+extern "C" fn pow_int(lhs: i32, rhs: i32) -> i32 {
+    match binary_op(BinaryOp::Pow, Scalar::Int(lhs), Scalar::Int(rhs)) {
+        Scalar::Int(value) => value,
+        _ => unreachable!(),
+    }
+}
+
+extern "C" fn pow_float(lhs: f32, rhs: f32) -> f32 {
+    lhs.powf(rhs)
+}
+
+extern "C" fn rem_float(lhs: f32, rhs: f32) -> f32 {
+    lhs % rhs
+}
+
+/// Compiles a program for the machine this is running on.
+pub fn compile(program: &ConcreteProgram) -> Result<JitProgram, JitError> {
+    let lanes = program.lanes();
+    if lanes != 1 && lanes % 4 != 0 {
+        return Err(JitError::UnsupportedLanes(lanes));
+    }
+    let mut flags = settings::builder();
+    flags.set("opt_level", "speed").unwrap();
+    flags.set("use_colocated_libcalls", "false").unwrap();
+    flags.set("is_pic", "false").unwrap();
+    let isa = cranelift_native::builder()
+        .map_err(|reason| JitError::UnsupportedHost(reason.to_owned()))?
+        .finish(settings::Flags::new(flags))?;
+    let mut builder = JITBuilder::with_isa(isa, default_libcall_names());
+    builder.symbol("nodespeak_pow_int", pow_int as *const u8);
+    builder.symbol("nodespeak_pow_float", pow_float as *const u8);
+    builder.symbol("nodespeak_rem_float", rem_float as *const u8);
+    let mut module = JITModule::new(builder);
+
+    let pointer_type = module.target_config().pointer_type();
+    let mut ctx = module.make_context();
+    ctx.func.signature.params.push(AbiParam::new(pointer_type));
+    ctx.func.signature.params.push(AbiParam::new(pointer_type));
+
+    let mut helper = |name: &str, typee: Type| -> Result<FuncRef, JitError> {
+        let mut signature = Signature::new(module.target_config().default_call_conv);
+        signature.params.push(AbiParam::new(typee));
+        signature.params.push(AbiParam::new(typee));
+        signature.returns.push(AbiParam::new(typee));
+        let id = module.declare_function(name, Linkage::Import, &signature)?;
+        Ok(module.declare_func_in_func(id, &mut ctx.func))
+    };
+    let helpers = Helpers {
+        pow_int: helper("nodespeak_pow_int", types::I32)?,
+        pow_float: helper("nodespeak_pow_float", types::F32)?,
+        rem_float: helper("nodespeak_rem_float", types::F32)?,
+    };
+
+    let mut builder_ctx = FunctionBuilderContext::new();
+    let mut builder = FunctionBuilder::new(&mut ctx.func, &mut builder_ctx);
+    let entry = builder.create_block();
+    builder.append_block_params_for_function_params(entry);
+    builder.switch_to_block(entry);
+    let params = builder.block_params(entry).to_vec();
+    let pointer_size = pointer_type.bytes() as i32;
+    let mut load_pointers = |base: Value, count: usize| -> Vec<Value> {
+        (0..count)
+            .map(|index| {
+                let offset = index as i32 * pointer_size;
+                builder
+                    .ins()
+                    .load(pointer_type, MemFlags::trusted(), base, offset)
+            })
+            .collect()
+    };
+    let input_pointers = load_pointers(params[0], program.inputs().len());
+    let output_pointers = load_pointers(params[1], program.outputs().len());
+
+    let mut lowering = Lowering {
+        builder,
+        program,
+        lanes,
+        helpers,
+        pointer_type,
+        input_pointers,
+        iteration: None,
+        scopes: vec![Scope::default()],
+    };
+    for (output, &pointer) in program.outputs().iter().zip(output_pointers.iter()) {
+        lowering.store_output(output, pointer);
+    }
+    lowering.builder.ins().return_(&[]);
+    lowering.builder.seal_all_blocks();
+    lowering.builder.finalize();
+
+    let id = module.declare_function("main", Linkage::Export, &ctx.func.signature)?;
+    module.define_function(id, &mut ctx)?;
+    module.clear_context(&mut ctx);
+    module.finalize_definitions()?;
+    let code = module.get_finalized_function(id);
+    Ok(JitProgram {
+        module: Some(module),
+        function: unsafe { std::mem::transmute::<*const u8, CompiledFunction>(code) },
+        inputs: program.inputs().to_vec(),
+        outputs: program
+            .outputs()
+            .iter()
+            .map(|output| output.typee().clone())
+            .collect(),
+    })
+}
+
+struct Helpers {
+    pow_int: FuncRef,
+    pow_float: FuncRef,
+    rem_float: FuncRef,
+}
+
+/// What a `ConcreteValue` was lowered to. Ints are held in `I32`s, Floats in
+/// `F32`s and Bools in `I8`s which are 0 or 1. Vectors are held in 4-lane
+/// registers, with Bools as masks which are all ones where true.
+#[derive(Clone)]
+enum Lanes {
+    Scalar(Value),
+    Vector(Vec<Value>),
+}
+
+#[derive(Clone)]
+struct Lowered {
+    typee: ConcreteScalarType,
+    lanes: Lanes,
+}
+
+/// Results which can only be used inside the block of code they were
+/// computed in, such as the body of a loop.
+#[derive(Default)]
+struct Scope {
+    values: HashMap<*const ConcreteValue, Lowered>,
+    /// The vectors produced by each loop, four bytes per scalar.
+    loops: HashMap<*const ConcreteValue, (StackSlot, ConcreteScalarType)>,
+    /// Multi-values which have been written to memory so they can be indexed
+    /// at runtime, identified by their components.
+    multi_values: HashMap<Vec<*const ConcreteValue>, (StackSlot, ConcreteScalarType)>,
+}
+
+struct Lowering<'a, 'b> {
+    builder: FunctionBuilder<'b>,
+    program: &'a ConcreteProgram,
+    /// How many scalars each vector is made of.
+    lanes: usize,
+    helpers: Helpers,
+    pointer_type: Type,
+    input_pointers: Vec<Value>,
+    /// The iteration counter of the loop whose body is being lowered, if any.
+    iteration: Option<Value>,
+    scopes: Vec<Scope>,
+}
+
+fn key(value: &ConcreteValuePtr) -> *const ConcreteValue {
+    &**value as *const ConcreteValue
+}
+
+fn multi_value_key(value: &ConcreteMultiValue) -> Vec<*const ConcreteValue> {
+    value.components().iter().map(key).collect()
+}
+
+fn vector_type(typee: ConcreteScalarType) -> Type {
+    match typee {
+        ConcreteScalarType::Float => types::F32X4,
+        _ => types::I32X4,
+    }
+}
+
+fn loop_parts(value: &ConcreteValuePtr) -> (usize, &ConcreteValuePtr) {
+    match &**value {
+        ConcreteValue::Loop { iterations, body } => (*iterations, body),
+        other => panic!("Expected a loop, got {:?}.", other),
+    }
+}
+
+/// Lane order doesn't matter for the bitcasts done here, since the lanes of
+/// both types are the same size.
+fn lane_order() -> MemFlags {
+    MemFlags::new().with_endianness(Endianness::Little)
+}
+
+impl<'a, 'b> Lowering<'a, 'b> {
+    /// How many bytes a vector takes up in memory.
+    fn vector_bytes(&self) -> i64 {
+        4 * self.lanes as i64
+    }
+
+    /// How many registers a vector is held in.
+    fn registers(&self) -> usize {
+        self.lanes / 4
+    }
+
+    fn iconst(&mut self, value: i32) -> Value {
+        self.builder.ins().iconst(types::I32, value as i64)
+    }
+
+    /// Turns a Bool scalar into a 32-bit mask.
+    fn bool_to_mask(&mut self, value: Value) -> Value {
+        let extended = self.builder.ins().uextend(types::I32, value);
+        self.builder.ins().ineg(extended)
+    }
+
+    /// Returns the scalar in one lane of a vector.
+    fn extract(&mut self, typee: ConcreteScalarType, vector: &[Value], lane: usize) -> Value {
+        let scalar = self
+            .builder
+            .ins()
+            .extractlane(vector[lane / 4], (lane % 4) as u8);
+        if typee == ConcreteScalarType::Bool {
+            self.builder.ins().icmp_imm(IntCC::NotEqual, scalar, 0)
+        } else {
+            scalar
+        }
+    }
+
+    fn build_vector(&mut self, typee: ConcreteScalarType, lanes: &[Value]) -> Vec<Value> {
+        assert_eq!(lanes.len(), self.lanes);
+        let mut lanes = lanes.to_vec();
+        if typee == ConcreteScalarType::Bool {
+            for lane in &mut lanes {
+                *lane = self.bool_to_mask(*lane);
+            }
+        }
+        let mut registers = Vec::new();
+        for chunk in lanes.chunks(4) {
+            let mut vector = self.builder.ins().splat(vector_type(typee), chunk[0]);
+            for (lane, &scalar) in chunk.iter().enumerate().skip(1) {
+                vector = self.builder.ins().insertlane(vector, scalar, lane as u8);
+            }
+            registers.push(vector);
+        }
+        registers
+    }
+
+    /// Returns the value as a vector, duplicating it if it is a scalar.
+    fn broadcast(&mut self, value: &Lowered) -> Vec<Value> {
+        match &value.lanes {
+            Lanes::Vector(vector) => vector.clone(),
+            &Lanes::Scalar(mut scalar) => {
+                if value.typee == ConcreteScalarType::Bool {
+                    scalar = self.bool_to_mask(scalar);
+                }
+                let vector = self.builder.ins().splat(vector_type(value.typee), scalar);
+                vec![vector; self.registers()]
+            }
+        }
+    }
+
+    /// Applies `op` to each register of a vector, or to a scalar.
+    fn map(&mut self, lanes: Lanes, mut op: impl FnMut(&mut Self, Value) -> Value) -> Lanes {
+        match lanes {
+            Lanes::Scalar(scalar) => Lanes::Scalar(op(self, scalar)),
+            Lanes::Vector(registers) => Lanes::Vector(
+                registers
+                    .into_iter()
+                    .map(|register| op(self, register))
+                    .collect(),
+            ),
+        }
+    }
+
+    fn unary(&mut self, op: UnaryOp, operand: Lowered) -> Lowered {
+        use ConcreteScalarType as T;
+        let vector = matches!(operand.lanes, Lanes::Vector(..));
+        let (typee, lanes) = match (op, operand.typee) {
+            (UnaryOp::IntToFloat, T::Int) => (
+                T::Float,
+                self.map(operand.lanes, |s, x| {
+                    let typee = if vector { types::F32X4 } else { types::F32 };
+                    s.builder.ins().fcvt_from_sint(typee, x)
+                }),
+            ),
+            (UnaryOp::BoolToInt, T::Bool) => (
+                T::Int,
+                self.map(operand.lanes, |s, x| {
+                    if vector {
+                        s.builder.ins().ineg(x)
+                    } else {
+                        s.builder.ins().uextend(types::I32, x)
+                    }
+                }),
+            ),
+            (UnaryOp::BoolToFloat, T::Bool) => {
+                let int = self.unary(UnaryOp::BoolToInt, operand);
+                return self.unary(UnaryOp::IntToFloat, int);
+            }
+            (UnaryOp::Not, T::Int) => (
+                T::Int,
+                self.map(operand.lanes, |s, x| s.builder.ins().bnot(x)),
+            ),
+            (UnaryOp::Not, T::Bool) => (
+                T::Bool,
+                self.map(operand.lanes, |s, x| {
+                    if vector {
+                        s.builder.ins().bnot(x)
+                    } else {
+                        s.builder.ins().bxor_imm(x, 1)
+                    }
+                }),
+            ),
+            (UnaryOp::Neg, T::Int) => (
+                T::Int,
+                self.map(operand.lanes, |s, x| s.builder.ins().ineg(x)),
+            ),
+            (UnaryOp::Neg, T::Float) => (
+                T::Float,
+                self.map(operand.lanes, |s, x| s.builder.ins().fneg(x)),
+            ),
+            (UnaryOp::Noop, _) => return operand,
+            (op, typee) => panic!("{:?} cannot be applied to {:?}.", op, typee),
+        };
+        Lowered { typee, lanes }
+    }
+
+    /// Converts a value to a type at least as general as its own.
+    fn promote(&mut self, value: Lowered, to: ConcreteScalarType) -> Lowered {
+        use ConcreteScalarType as T;
+        match (value.typee, to) {
+            (T::Bool, T::Int) => self.unary(UnaryOp::BoolToInt, value),
+            (T::Bool, T::Float) => self.unary(UnaryOp::BoolToFloat, value),
+            (T::Int, T::Float) => self.unary(UnaryOp::IntToFloat, value),
+            _ => value,
+        }
+    }
+
+    fn call_helper(&mut self, helper: FuncRef, lhs: Value, rhs: Value) -> Value {
+        let call = self.builder.ins().call(helper, &[lhs, rhs]);
+        self.builder.inst_results(call)[0]
+    }
+
+    /// Integer division and remainder which give zero when dividing by zero
+    /// and wrap when dividing `i32::MIN` by -1, instead of trapping.
+    fn int_division(&mut self, op: BinaryOp, lhs: Value, rhs: Value) -> Value {
+        let by_zero = self.builder.ins().icmp_imm(IntCC::Equal, rhs, 0);
+        let min = self
+            .builder
+            .ins()
+            .icmp_imm(IntCC::Equal, lhs, i32::MIN as i64);
+        let minus_one = self.builder.ins().icmp_imm(IntCC::Equal, rhs, -1);
+        let overflows = self.builder.ins().band(min, minus_one);
+        let replaced = self.builder.ins().bor(by_zero, overflows);
+        let one = self.iconst(1);
+        let divisor = self.builder.ins().select(replaced, one, rhs);
+        let result = if op == BinaryOp::Div {
+            self.builder.ins().sdiv(lhs, divisor)
+        } else {
+            self.builder.ins().srem(lhs, divisor)
+        };
+        let zero = self.iconst(0);
+        self.builder.ins().select(by_zero, zero, result)
+    }
+
+    /// Applies a binary operation to two scalars of the same type.
+    fn scalar_binary(
+        &mut self,
+        op: BinaryOp,
+        typee: ConcreteScalarType,
+        lhs: Value,
+        rhs: Value,
+    ) -> Value {
+        use ConcreteScalarType as T;
+        match (typee, op) {
+            (T::Int, BinaryOp::Div | BinaryOp::Rem) => self.int_division(op, lhs, rhs),
+            (T::Int, BinaryOp::Pow) => self.call_helper(self.helpers.pow_int, lhs, rhs),
+            (T::Float, BinaryOp::Rem) => self.call_helper(self.helpers.rem_float, lhs, rhs),
+            (T::Float, BinaryOp::Pow) => self.call_helper(self.helpers.pow_float, lhs, rhs),
+            _ => self
+                .simd_binary(op, typee, lhs, rhs)
+                .unwrap_or_else(|| panic!("{:?} cannot be applied to {:?}s.", op, typee)),
+        }
+    }
+
+    /// Applies a binary operation using the instruction for it, if there is
+    /// one. Works on scalars or on four lanes at once.
+    fn simd_binary(
+        &mut self,
+        op: BinaryOp,
+        typee: ConcreteScalarType,
+        lhs: Value,
+        rhs: Value,
+    ) -> Option<Value> {
+        use ConcreteScalarType as T;
+        let ins = self.builder.ins();
+        Some(match (typee, op) {
+            (T::Int, BinaryOp::Add) => ins.iadd(lhs, rhs),
+            (T::Int, BinaryOp::Sub) => ins.isub(lhs, rhs),
+            (T::Int, BinaryOp::Mul) => ins.imul(lhs, rhs),
+            (T::Float, BinaryOp::Add) => ins.fadd(lhs, rhs),
+            (T::Float, BinaryOp::Sub) => ins.fsub(lhs, rhs),
+            (T::Float, BinaryOp::Mul) => ins.fmul(lhs, rhs),
+            (T::Float, BinaryOp::Div) => ins.fdiv(lhs, rhs),
+            (T::Float, _) if is_comparison(op) => ins.fcmp(float_condition(op), lhs, rhs),
+            (T::Int, _) | (T::Bool, BinaryOp::Eq | BinaryOp::Neq) if is_comparison(op) => {
+                ins.icmp(int_condition(op), lhs, rhs)
+            }
+            (T::Int | T::Bool, BinaryOp::And) => ins.band(lhs, rhs),
+            (T::Int | T::Bool, BinaryOp::Or) => ins.bor(lhs, rhs),
+            (T::Int | T::Bool, BinaryOp::Xor) => ins.bxor(lhs, rhs),
+            _ => return None,
+        })
+    }
+
+    fn binary(&mut self, op: BinaryOp, lhs: Lowered, rhs: Lowered) -> Lowered {
+        let typee = lhs.typee + rhs.typee;
+        let lhs = self.promote(lhs, typee);
+        let rhs = self.promote(rhs, typee);
+        let result_type = if is_comparison(op) {
+            ConcreteScalarType::Bool
+        } else {
+            typee
+        };
+        let lanes = match (&lhs.lanes, &rhs.lanes) {
+            (&Lanes::Scalar(l), &Lanes::Scalar(r)) => {
+                Lanes::Scalar(self.scalar_binary(op, typee, l, r))
+            }
+            _ => {
+                let l = self.broadcast(&lhs);
+                let r = self.broadcast(&rhs);
+                let simd = (0..l.len())
+                    .map(|register| self.simd_binary(op, typee, l[register], r[register]))
+                    .collect::<Option<Vec<_>>>();
+                if let Some(registers) = simd {
+                    Lanes::Vector(registers)
+                } else {
+                    let mut results = Vec::new();
+                    for lane in 0..self.lanes {
+                        let l = self.extract(typee, &l, lane);
+                        let r = self.extract(typee, &r, lane);
+                        results.push(self.scalar_binary(op, typee, l, r));
+                    }
+                    Lanes::Vector(self.build_vector(result_type, &results))
+                }
+            }
+        };
+        Lowered {
+            typee: result_type,
+            lanes,
+        }
+    }
+
+    fn bound_index(&mut self, index: Lowered, size: usize, policy: OutOfBoundsPolicy) -> Lowered {
+        let size = size as i32;
+        let lanes = match policy {
+            OutOfBoundsPolicy::Clamp => {
+                let vector = matches!(index.lanes, Lanes::Vector(..));
+                self.map(index.lanes, |s, x| {
+                    let mut max = s.iconst(size - 1);
+                    let mut zero = s.iconst(0);
+                    if vector {
+                        max = s.builder.ins().splat(types::I32X4, max);
+                        zero = s.builder.ins().splat(types::I32X4, zero);
+                    }
+                    let clamped = s.builder.ins().smin(x, max);
+                    s.builder.ins().smax(clamped, zero)
+                })
+            }
+            OutOfBoundsPolicy::Wrap => {
+                let wrap = |s: &mut Self, x: Value| {
+                    let remainder = s.builder.ins().srem_imm(x, size as i64);
+                    let negative = s
+                        .builder
+                        .ins()
+                        .icmp_imm(IntCC::SignedLessThan, remainder, 0);
+                    let wrapped = s.builder.ins().iadd_imm(remainder, size as i64);
+                    s.builder.ins().select(negative, wrapped, remainder)
+                };
+                match index.lanes {
+                    Lanes::Scalar(x) => Lanes::Scalar(wrap(self, x)),
+                    Lanes::Vector(vector) => {
+                        let mut results = Vec::new();
+                        for lane in 0..self.lanes {
+                            let x = self.extract(ConcreteScalarType::Int, &vector, lane);
+                            results.push(wrap(self, x));
+                        }
+                        Lanes::Vector(self.build_vector(ConcreteScalarType::Int, &results))
+                    }
+                }
+            }
+        };
+        Lowered {
+            typee: ConcreteScalarType::Int,
+            lanes,
+        }
+    }
+
+    fn select(&mut self, condition: Lowered, if_true: Lowered, if_false: Lowered) -> Lowered {
+        let typee = if_true.typee + if_false.typee;
+        let if_true = self.promote(if_true, typee);
+        let if_false = self.promote(if_false, typee);
+        let lanes = match (&condition.lanes, &if_true.lanes, &if_false.lanes) {
+            (&Lanes::Scalar(c), &Lanes::Scalar(t), &Lanes::Scalar(f)) => {
+                Lanes::Scalar(self.builder.ins().select(c, t, f))
+            }
+            _ => {
+                let c = self.broadcast(&condition);
+                let t = self.broadcast(&if_true);
+                let f = self.broadcast(&if_false);
+                let mut registers = Vec::new();
+                for register in 0..c.len() {
+                    let mut mask = c[register];
+                    if typee == ConcreteScalarType::Float {
+                        mask = self.builder.ins().bitcast(types::F32X4, lane_order(), mask);
+                    }
+                    registers.push(self.builder.ins().bitselect(mask, t[register], f[register]));
+                }
+                Lanes::Vector(registers)
+            }
+        };
+        Lowered { typee, lanes }
+    }
+
+    fn find_value(&self, value: &ConcreteValuePtr) -> Option<Lowered> {
+        let key = key(value);
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.values.get(&key).cloned())
+    }
+
+    fn find_loop(&self, value: &ConcreteValuePtr) -> Option<(StackSlot, ConcreteScalarType)> {
+        let key = key(value);
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.loops.get(&key).copied())
+    }
+
+    fn lower(&mut self, value: &ConcreteValuePtr) -> Lowered {
+        if let Some(lowered) = self.find_value(value) {
+            return lowered;
+        }
+        let lowered = self.lower_uncached(value);
+        self.scopes
+            .last_mut()
+            .unwrap()
+            .values
+            .insert(key(value), lowered.clone());
+        lowered
+    }
+
+    fn lower_uncached(&mut self, value: &ConcreteValuePtr) -> Lowered {
+        use ConcreteScalarType as T;
+        let scalar = |typee, value| Lowered {
+            typee,
+            lanes: Lanes::Scalar(value),
+        };
+        let vector = |typee, registers| Lowered {
+            typee,
+            lanes: Lanes::Vector(registers),
+        };
+        match &**value {
+            ConcreteValue::IntLiteral(value) => scalar(T::Int, self.iconst(*value)),
+            ConcreteValue::FloatLiteral(value) => {
+                scalar(T::Float, self.builder.ins().f32const(*value))
+            }
+            ConcreteValue::BoolLiteral(value) => {
+                scalar(T::Bool, self.builder.ins().iconst(types::I8, *value as i64))
+            }
+            ConcreteValue::Unvectorize(source, lane) => {
+                let source = self.lower(source);
+                let registers = self.broadcast(&source);
+                scalar(source.typee, self.extract(source.typee, &registers, *lane))
+            }
+            ConcreteValue::Vectorize(lanes) => {
+                let lanes: Vec<_> = lanes.iter().map(|lane| self.lower(lane)).collect();
+                let typee = lanes.iter().fold(T::Bool, |typee, lane| typee + lane.typee);
+                let mut scalars = Vec::new();
+                for lane in lanes {
+                    match self.promote(lane, typee).lanes {
+                        Lanes::Scalar(value) => scalars.push(value),
+                        Lanes::Vector(..) => panic!("Lanes of a vector must be scalars."),
+                    }
+                }
+                vector(typee, self.build_vector(typee, &scalars))
+            }
+            ConcreteValue::VectorizeByDuplication(source) => {
+                let source = self.lower(source);
+                let registers = self.broadcast(&source);
+                vector(source.typee, registers)
+            }
+            ConcreteValue::InputScalar { input, position } => {
+                let typee = self.program.inputs()[*input].base;
+                let offset = (*position * external_size(typee)) as i32;
+                let pointer = self.input_pointers[*input];
+                scalar(typee, self.load_external(typee, pointer, offset))
+            }
+            ConcreteValue::InputVector { input, position } => {
+                let typee = self.program.inputs()[*input].base;
+                let offset = (*position * external_size(typee)) as i32;
+                let pointer = self.input_pointers[*input];
+                vector(typee, self.load_external_vector(typee, pointer, offset))
+            }
+            ConcreteValue::UnaryOp(op, operand) => {
+                let operand = self.lower(operand);
+                self.unary(*op, operand)
+            }
+            ConcreteValue::BinaryOp(op, lhs, rhs) => {
+                let lhs = self.lower(lhs);
+                let rhs = self.lower(rhs);
+                self.binary(*op, lhs, rhs)
+            }
+            ConcreteValue::BoundIndex(index, size, policy) => {
+                let index = self.lower(index);
+                self.bound_index(index, *size, *policy)
+            }
+            ConcreteValue::DynamicLoad { source, index } => {
+                let index = self.lower(index);
+                let index = match index.lanes {
+                    Lanes::Scalar(index) => index,
+                    Lanes::Vector(..) => panic!("Expected a scalar index."),
+                };
+                let (slot, typee) = self.store_multi_value(source);
+                scalar(typee, self.load_dynamic(typee, slot, index))
+            }
+            ConcreteValue::Gather { source, indices } => {
+                let indices = self.lower(indices);
+                let indices = self.broadcast(&indices);
+                let (slot, typee) = self.store_multi_value(source);
+                let mut lanes = Vec::new();
+                for lane in 0..self.lanes {
+                    let index = self.extract(T::Int, &indices, lane);
+                    lanes.push(self.load_dynamic(typee, slot, index));
+                }
+                vector(typee, self.build_vector(typee, &lanes))
+            }
+            ConcreteValue::Select {
+                condition,
+                if_true,
+                if_false,
+            } => {
+                let condition = self.lower(condition);
+                let if_true = self.lower(if_true);
+                let if_false = self.lower(if_false);
+                self.select(condition, if_true, if_false)
+            }
+            ConcreteValue::Loop { .. } => {
+                panic!("A loop stands for several vectors, so it can't be used as a value.")
+            }
+            ConcreteValue::LoopElement { source, offset } => {
+                let (slot, typee) = self.lower_loop(source);
+                let iteration = self
+                    .iteration
+                    .expect("Loop elements can only be used inside the body of a loop.");
+                let iteration = self.builder.ins().iadd_imm(iteration, *offset as i64);
+                vector(typee, self.load_iteration(typee, slot, iteration))
+            }
+            ConcreteValue::LoopInputVector(input) => {
+                let typee = self.program.inputs()[*input].base;
+                let iteration = self
+                    .iteration
+                    .expect("Loop elements can only be used inside the body of a loop.");
+                let stride = (self.lanes * external_size(typee)) as i64;
+                let offset = self.builder.ins().imul_imm(iteration, stride);
+                let pointer = self.builder.ins().iadd(self.input_pointers[*input], offset);
+                vector(typee, self.load_external_vector(typee, pointer, 0))
+            }
+            ConcreteValue::Unloop(source, iteration) => {
+                let (slot, typee) = self.lower_loop(source);
+                let iteration = self
+                    .builder
+                    .ins()
+                    .iconst(self.pointer_type, *iteration as i64);
+                vector(typee, self.load_iteration(typee, slot, iteration))
+            }
+        }
+    }
+
+    fn load_external(&mut self, typee: ConcreteScalarType, pointer: Value, offset: i32) -> Value {
+        let flags = MemFlags::new();
+        match typee {
+            ConcreteScalarType::Int => self.builder.ins().load(types::I32, flags, pointer, offset),
+            ConcreteScalarType::Float => {
+                self.builder.ins().load(types::F32, flags, pointer, offset)
+            }
+            ConcreteScalarType::Bool => {
+                let byte = self.builder.ins().load(types::I8, flags, pointer, offset);
+                self.builder.ins().icmp_imm(IntCC::NotEqual, byte, 0)
+            }
+        }
+    }
+
+    fn load_external_vector(
+        &mut self,
+        typee: ConcreteScalarType,
+        pointer: Value,
+        offset: i32,
+    ) -> Vec<Value> {
+        if typee == ConcreteScalarType::Bool {
+            let lanes: Vec<_> = (0..self.lanes)
+                .map(|lane| self.load_external(typee, pointer, offset + lane as i32))
+                .collect();
+            self.build_vector(typee, &lanes)
+        } else {
+            self.load_vector(typee, pointer, offset)
+        }
+    }
+
+    /// Loads a vector held in memory four bytes per scalar, with Bools as
+    /// masks.
+    fn load_vector(
+        &mut self,
+        typee: ConcreteScalarType,
+        pointer: Value,
+        offset: i32,
+    ) -> Vec<Value> {
+        let flags = MemFlags::new();
+        let typee = vector_type(typee);
+        (0..self.registers() as i32)
+            .map(|register| {
+                self.builder
+                    .ins()
+                    .load(typee, flags, pointer, offset + 16 * register)
+            })
+            .collect()
+    }
+
+    fn store_vector(&mut self, vector: &[Value], pointer: Value, offset: i32) {
+        let flags = MemFlags::new();
+        for (register, value) in vector.iter().enumerate() {
+            self.builder
+                .ins()
+                .store(flags, *value, pointer, offset + 16 * register as i32);
+        }
+    }
+
+    /// Loads the vector a loop produced on an iteration given at runtime.
+    fn load_iteration(
+        &mut self,
+        typee: ConcreteScalarType,
+        slot: StackSlot,
+        iteration: Value,
+    ) -> Vec<Value> {
+        let base = self.builder.ins().stack_addr(self.pointer_type, slot, 0);
+        let vector_bytes = self.vector_bytes();
+        let offset = self.builder.ins().imul_imm(iteration, vector_bytes);
+        let pointer = self.builder.ins().iadd(base, offset);
+        self.load_vector(typee, pointer, 0)
+    }
+
+    /// Loads a scalar from a multi-value stored in memory, given its position
+    /// as an Int.
+    fn load_dynamic(&mut self, typee: ConcreteScalarType, slot: StackSlot, index: Value) -> Value {
+        let base = self.builder.ins().stack_addr(self.pointer_type, slot, 0);
+        let index = self.builder.ins().sextend(self.pointer_type, index);
+        let offset = self.builder.ins().imul_imm(index, 4);
+        let pointer = self.builder.ins().iadd(base, offset);
+        let flags = MemFlags::new();
+        match typee {
+            ConcreteScalarType::Float => self.builder.ins().load(types::F32, flags, pointer, 0),
+            ConcreteScalarType::Int => self.builder.ins().load(types::I32, flags, pointer, 0),
+            ConcreteScalarType::Bool => {
+                let mask = self.builder.ins().load(types::I32, flags, pointer, 0);
+                self.builder.ins().icmp_imm(IntCC::NotEqual, mask, 0)
+            }
+        }
+    }
+
+    fn create_slot(&mut self, size: usize) -> StackSlot {
+        self.builder.create_sized_stack_slot(StackSlotData::new(
+            StackSlotKind::ExplicitSlot,
+            size as u32,
+            4,
+        ))
+    }
+
+    /// Starts a loop which counts from zero, returning the counter.
+    fn begin_loop(&mut self) -> (Value, Block) {
+        let header = self.builder.create_block();
+        self.builder.append_block_param(header, self.pointer_type);
+        let zero = self.builder.ins().iconst(self.pointer_type, 0);
+        self.builder.ins().jump(header, &[zero]);
+        self.builder.switch_to_block(header);
+        (self.builder.block_params(header)[0], header)
+    }
+
+    fn end_loop(&mut self, counter: Value, header: Block, iterations: usize) {
+        let exit = self.builder.create_block();
+        let next = self.builder.ins().iadd_imm(counter, 1);
+        let repeat = self
+            .builder
+            .ins()
+            .icmp_imm(IntCC::SignedLessThan, next, iterations as i64);
+        self.builder.ins().brif(repeat, header, &[next], exit, &[]);
+        self.builder.switch_to_block(exit);
+    }
+
+    /// Lowers every loop `value` reads from so that they are computed before
+    /// the loop `value` is the body of.
+    fn lower_loops_used_by(
+        &mut self,
+        value: &ConcreteValuePtr,
+        visited: &mut Vec<*const ConcreteValue>,
+    ) {
+        if visited.contains(&key(value)) {
+            return;
+        }
+        visited.push(key(value));
+        match &**value {
+            ConcreteValue::LoopElement { source, .. } | ConcreteValue::Unloop(source, _) => {
+                self.lower_loop(source);
+            }
+            ConcreteValue::Unvectorize(operand, _)
+            | ConcreteValue::VectorizeByDuplication(operand)
+            | ConcreteValue::UnaryOp(_, operand)
+            | ConcreteValue::BoundIndex(operand, ..) => self.lower_loops_used_by(operand, visited),
+            ConcreteValue::Vectorize(lanes) => {
+                for lane in lanes {
+                    self.lower_loops_used_by(lane, visited);
+                }
+            }
+            ConcreteValue::BinaryOp(_, lhs, rhs) => {
+                self.lower_loops_used_by(lhs, visited);
+                self.lower_loops_used_by(rhs, visited);
+            }
+            ConcreteValue::DynamicLoad {
+                source,
+                index: operand,
+            }
+            | ConcreteValue::Gather {
+                source,
+                indices: operand,
+            } => {
+                for component in source.components() {
+                    if let ConcreteValue::Loop { .. } = &**component {
+                        self.lower_loop(component);
+                    } else {
+                        self.lower_loops_used_by(component, visited);
+                    }
+                }
+                self.lower_loops_used_by(operand, visited);
+            }
+            ConcreteValue::Select {
+                condition,
+                if_true,
+                if_false,
+            } => {
+                self.lower_loops_used_by(condition, visited);
+                self.lower_loops_used_by(if_true, visited);
+                self.lower_loops_used_by(if_false, visited);
+            }
+            _ => (),
+        }
+    }
+
+    /// Emits a loop computing every vector of a `ConcreteValue::Loop`,
+    /// storing them in a stack slot.
+    fn lower_loop(&mut self, value: &ConcreteValuePtr) -> (StackSlot, ConcreteScalarType) {
+        if let Some(lowered) = self.find_loop(value) {
+            return lowered;
+        }
+        let (iterations, body) = loop_parts(value);
+        assert!(iterations > 0);
+        self.lower_loops_used_by(body, &mut Vec::new());
+        let slot = self.create_slot(iterations * self.vector_bytes() as usize);
+        let (counter, header) = self.begin_loop();
+        let outer_iteration = self.iteration.replace(counter);
+        self.scopes.push(Scope::default());
+        let result = self.lower(body);
+        let vector = self.broadcast(&result);
+        let base = self.builder.ins().stack_addr(self.pointer_type, slot, 0);
+        let vector_bytes = self.vector_bytes();
+        let offset = self.builder.ins().imul_imm(counter, vector_bytes);
+        let pointer = self.builder.ins().iadd(base, offset);
+        self.store_vector(&vector, pointer, 0);
+        self.scopes.pop();
+        self.iteration = outer_iteration;
+        self.end_loop(counter, header, iterations);
+        let lowered = (slot, result.typee);
+        self.scopes
+            .last_mut()
+            .unwrap()
+            .loops
+            .insert(key(value), lowered);
+        lowered
+    }
+
+    /// Copies every scalar of a multi-value into a stack slot, four bytes
+    /// each, so that it can be indexed at runtime.
+    fn store_multi_value(&mut self, value: &ConcreteMultiValue) -> (StackSlot, ConcreteScalarType) {
+        let key = multi_value_key(value);
+        let found = self
+            .scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.multi_values.get(&key).copied());
+        if let Some(stored) = found {
+            return stored;
+        }
+        let typee = value.typee().base;
+        let slot = self.create_slot(value.typee().size() * 4);
+        let base = self.builder.ins().stack_addr(self.pointer_type, slot, 0);
+        let mut position = 0;
+        for component in value.components() {
+            if let ConcreteValue::Loop { iterations, .. } = &**component {
+                let (source, _) = self.lower_loop(component);
+                let source = self.builder.ins().stack_addr(self.pointer_type, source, 0);
+                let (counter, header) = self.begin_loop();
+                let vector_bytes = self.vector_bytes();
+                let offset = self.builder.ins().imul_imm(counter, vector_bytes);
+                let from = self.builder.ins().iadd(source, offset);
+                let vector = self.load_vector(typee, from, 0);
+                let to = self.builder.ins().iadd(base, offset);
+                self.store_vector(&vector, to, position as i32 * 4);
+                self.end_loop(counter, header, *iterations);
+            } else {
+                let lowered = self.lower(component);
+                let lowered = self.promote(lowered, typee);
+                match lowered.lanes {
+                    Lanes::Scalar(mut scalar) => {
+                        if typee == ConcreteScalarType::Bool {
+                            scalar = self.bool_to_mask(scalar);
+                        }
+                        self.builder.ins().store(
+                            MemFlags::new(),
+                            scalar,
+                            base,
+                            position as i32 * 4,
+                        );
+                    }
+                    Lanes::Vector(vector) => self.store_vector(&vector, base, position as i32 * 4),
+                }
+            }
+            position += component.width(self.lanes);
+        }
+        let stored = (slot, typee);
+        self.scopes
+            .last_mut()
+            .unwrap()
+            .multi_values
+            .insert(key, stored);
+        stored
+    }
+
+    fn store_output(&mut self, output: &ConcreteMultiValue, pointer: Value) {
+        let typee = output.typee().base;
+        let size = external_size(typee);
+        let mut position = 0;
+        for component in output.components() {
+            let offset = (position * size) as i32;
+            if let ConcreteValue::Loop { iterations, .. } = &**component {
+                let (source, source_type) = self.lower_loop(component);
+                let (counter, header) = self.begin_loop();
+                let base = self.builder.ins().stack_addr(self.pointer_type, source, 0);
+                let vector_bytes = self.vector_bytes();
+                let from = self.builder.ins().imul_imm(counter, vector_bytes);
+                let from = self.builder.ins().iadd(base, from);
+                let vector = self.load_vector(source_type, from, 0);
+                let vector = self.promote(
+                    Lowered {
+                        typee: source_type,
+                        lanes: Lanes::Vector(vector),
+                    },
+                    typee,
+                );
+                let to = self
+                    .builder
+                    .ins()
+                    .imul_imm(counter, (self.lanes * size) as i64);
+                let to = self.builder.ins().iadd(pointer, to);
+                self.store_external_lanes(vector, to, offset);
+                self.end_loop(counter, header, *iterations);
+            } else {
+                let lowered = self.lower(component);
+                let lowered = self.promote(lowered, typee);
+                self.store_external_lanes(lowered, pointer, offset);
+            }
+            position += component.width(self.lanes);
+        }
+    }
+
+    fn store_external_lanes(&mut self, value: Lowered, pointer: Value, offset: i32) {
+        match value.lanes {
+            // Bools are already one byte holding 0 or 1.
+            Lanes::Scalar(scalar) => {
+                self.builder
+                    .ins()
+                    .store(MemFlags::new(), scalar, pointer, offset);
+            }
+            Lanes::Vector(vector) if value.typee == ConcreteScalarType::Bool => {
+                for lane in 0..self.lanes {
+                    let scalar = self.extract(value.typee, &vector, lane);
+                    self.builder.ins().store(
+                        MemFlags::new(),
+                        scalar,
+                        pointer,
+                        offset + lane as i32,
+                    );
+                }
+            }
+            Lanes::Vector(vector) => self.store_vector(&vector, pointer, offset),
+        }
+    }
+}
+
+fn is_comparison(op: BinaryOp) -> bool {
+    matches!(
+        op,
+        BinaryOp::Gt | BinaryOp::Lt | BinaryOp::Gte | BinaryOp::Lte | BinaryOp::Eq | BinaryOp::Neq
+    )
+}
+
+fn int_condition(op: BinaryOp) -> IntCC {
+    match op {
+        BinaryOp::Gt => IntCC::SignedGreaterThan,
+        BinaryOp::Lt => IntCC::SignedLessThan,
+        BinaryOp::Gte => IntCC::SignedGreaterThanOrEqual,
+        BinaryOp::Lte => IntCC::SignedLessThanOrEqual,
+        BinaryOp::Eq => IntCC::Equal,
+        BinaryOp::Neq => IntCC::NotEqual,
+        _ => unreachable!(),
+    }
+}
+
+fn float_condition(op: BinaryOp) -> FloatCC {
+    match op {
+        BinaryOp::Gt => FloatCC::GreaterThan,
+        BinaryOp::Lt => FloatCC::LessThan,
+        BinaryOp::Gte => FloatCC::GreaterThanOrEqual,
+        BinaryOp::Lte => FloatCC::LessThanOrEqual,
+        BinaryOp::Eq => FloatCC::Equal,
+        // Like Rust's `!=`, true when either operand is NaN.
+        BinaryOp::Neq => FloatCC::NotEqual,
+        _ => unreachable!(),
+    }
+}
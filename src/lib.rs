@@ -1,7 +1,9 @@
-#![feature(type_alias_impl_trait)]
-
 pub mod util;
 pub mod values;
 pub mod parser;
 mod tests;
 pub mod concrete;
+pub mod interpreter;
+pub mod emit;
+#[cfg(feature = "jit")]
+pub mod jit;
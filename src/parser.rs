@@ -1,15 +1,22 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    fmt::{self, Display, Formatter},
+};
 
 use nom::{
     branch::alt,
     bytes::complete::{tag, take_while, take_while1},
     combinator::{fail, opt},
-    multi::many0,
-    sequence::{terminated, tuple},
+    error::ErrorKind,
+    sequence::tuple,
     IResult, Parser,
 };
 
-use crate::values::{BuiltinOp, BuiltinType, Index, Local, LocalPtr, Statement, Value, ValuePtr};
+use crate::values::{
+    simplify::{flatten_array_literal, FlattenedArrayLiteral},
+    BuiltinOp, BuiltinType, Index, Local, LocalPtr, Span, Statement, Value, ValuePtr,
+};
 
 #[derive(Clone, Debug)]
 pub struct Scope {
@@ -17,73 +24,358 @@ pub struct Scope {
     inputs: Vec<LocalPtr>,
     outputs: Vec<LocalPtr>,
     plain_locals: Vec<LocalPtr>,
+    /// Template parameters declared with `T?` in the function being parsed.
+    templates: Vec<LocalPtr>,
+    file: usize,
+    source_len: usize,
+    /// Doc comments written before the statement being parsed, which are
+    /// given to the first local it declares.
+    pending_doc: Option<String>,
 }
 
 impl Scope {
-    fn new() -> Self {
+    fn new(file: usize, source: &str) -> Self {
         Self {
             all_locals: HashMap::new(),
             inputs: Vec::new(),
             outputs: Vec::new(),
             plain_locals: Vec::new(),
+            templates: Vec::new(),
+            file,
+            source_len: source.len(),
+            pending_doc: None,
         }
     }
+
+    /// Returns the span of the text between `start` and `end`, which are both
+    /// remaining parts of the source this scope is parsing. Trailing
+    /// whitespace and comments are not included.
+    fn span(&self, start: &str, end: &str) -> Span {
+        let text = &start[..start.len() - end.len()];
+        let start = self.source_len - start.len();
+        Span::new(self.file, start, start + content_len(text))
+    }
 }
 
-type Result<'a, T> = IResult<&'a str, T>;
+/// Describes why a piece of source code could not be parsed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    /// Location of the offending text in the source.
+    pub span: Span,
+    /// 1-based line number of the start of the span.
+    pub line: usize,
+    /// 1-based column (in characters) of the start of the span.
+    pub column: usize,
+    pub expected: String,
+    pub found: String,
+}
 
-pub fn parse_root(input: &str) -> Result<(Scope, Vec<Statement>)> {
-    let (input, _) = ws(input)?;
-    let mut scope = Scope::new();
-    let (input, values) = many0(terminated(
-        parse_statement(&mut scope),
-        tuple((ws, tag(";"), ws)),
-    ))(input)?;
-    if input.len() > 0 {
-        fail(input)
-    } else {
-        Ok((
-            input,
-            (
-                scope,
-                values.into_iter().flat_map(|x| x.into_iter()).collect(),
-            ),
-        ))
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}: expected {}, found {}",
+            self.line, self.column, self.expected, self.found
+        )
     }
 }
 
-fn parse_body<'b>(
-    scope: &'b mut Scope,
-) -> impl for<'a> FnMut(&'a str) -> Result<'a, Vec<Statement>> + 'b {
-    move |input| {
-        let (input, _) = ws(input)?;
-        let (input, values) = many0(terminated(
-            parse_statement(scope),
-            tuple((ws, tag(";"), ws)),
-        ))(input)?;
-        Ok((
+impl std::error::Error for ParseError {}
+
+/// The error type used internally while parsing. It only knows where it
+/// happened relative to the end of the source, so it is converted to a
+/// `ParseError` once the whole source is available.
+#[derive(Clone, Debug, PartialEq)]
+struct RawError<'a> {
+    input: &'a str,
+    len: usize,
+    expected: String,
+    found: Option<String>,
+}
+
+impl<'a> nom::error::ParseError<&'a str> for RawError<'a> {
+    fn from_error_kind(input: &'a str, _kind: ErrorKind) -> Self {
+        Self {
             input,
-            values.into_iter().flat_map(|x| x.into_iter()).collect(),
-        ))
+            len: 0,
+            expected: "valid syntax".to_owned(),
+            found: None,
+        }
+    }
+
+    fn append(_input: &'a str, _kind: ErrorKind, other: Self) -> Self {
+        other
+    }
+}
+
+impl<'a> RawError<'a> {
+    fn into_parse_error(self, file: usize, source: &str) -> ParseError {
+        let start = source.len() - self.input.len();
+        let before = &source[..start];
+        let line = before.matches('\n').count() + 1;
+        let column = before.chars().rev().take_while(|&c| c != '\n').count() + 1;
+        let (input, len) = (self.input, self.len);
+        let found = self.found.unwrap_or_else(|| describe_token(input, len));
+        ParseError {
+            span: Span::new(file, start, start + self.len),
+            line,
+            column,
+            expected: self.expected,
+            found,
+        }
     }
 }
 
-fn ws(input: &str) -> Result<&str> {
-    take_while(|c: char| c.is_whitespace())(input)
+fn describe_token(input: &str, len: usize) -> String {
+    if len > 0 {
+        return format!("`{}`", &input[..len]);
+    }
+    let identifier_len = input.len() - input.trim_start_matches(is_identifier_char).len();
+    if identifier_len > 0 {
+        format!("`{}`", &input[..identifier_len])
+    } else if let Some(c) = input.chars().next() {
+        format!("`{}`", c)
+    } else {
+        "end of file".to_owned()
+    }
+}
+
+/// Produces an error which will not be backtracked out of.
+fn error<'a, T>(input: &'a str, len: usize, expected: impl Into<String>) -> Result<'a, T> {
+    Err(nom::Err::Failure(RawError {
+        input,
+        len,
+        expected: expected.into(),
+        found: None,
+    }))
+}
+
+/// Like `error`, but with a custom description of what was found instead of
+/// just quoting the source text.
+fn error_found<'a, T>(
+    input: &'a str,
+    len: usize,
+    expected: impl Into<String>,
+    found: impl Into<String>,
+) -> Result<'a, T> {
+    Err(nom::Err::Failure(RawError {
+        input,
+        len,
+        expected: expected.into(),
+        found: Some(found.into()),
+    }))
+}
+
+/// Returns the number of bytes between the start of `from` and the start of
+/// `to`, where `to` is a later part of the same string.
+fn distance(from: &str, to: &str) -> usize {
+    from.len() - to.len()
+}
+
+type Result<'a, T> = IResult<&'a str, T, RawError<'a>>;
+
+pub fn parse_root(source: &str) -> std::result::Result<(Scope, Vec<Statement>), ParseError> {
+    parse_file(0, source)
+}
+
+/// Like `parse_root`, but all spans produced will refer to the given file id.
+pub fn parse_file(
+    file: usize,
+    source: &str,
+) -> std::result::Result<(Scope, Vec<Statement>), ParseError> {
+    let mut scope = Scope::new(file, source);
+    match parse_statements(&mut scope, source, false) {
+        Ok((_, statements)) => Ok((scope, statements)),
+        Err(nom::Err::Error(err)) | Err(nom::Err::Failure(err)) => {
+            Err(err.into_parse_error(file, source))
+        }
+        Err(nom::Err::Incomplete(..)) => unreachable!("Only complete parsers are used."),
+    }
+}
+
+/// Parses `;`-terminated statements until the end of the input or, if
+/// `in_block` is true, until a closing `}` (which is not consumed.)
+fn parse_statements<'a>(
+    scope: &mut Scope,
+    mut input: &'a str,
+    in_block: bool,
+) -> Result<'a, Vec<Statement>> {
+    let mut statements = Vec::new();
+    loop {
+        let (new_input, doc) = doc_comments(input)?;
+        input = new_input;
+        scope.pending_doc = doc;
+        if input.is_empty() {
+            if in_block {
+                return error(input, 0, "`}`");
+            }
+            break;
+        }
+        if input.starts_with('}') {
+            if in_block {
+                break;
+            }
+            return error_found(input, 1, "a statement", "unmatched `}`");
+        }
+        let (new_input, statement) = match parse_statement(scope)(input) {
+            Err(nom::Err::Error(..)) => return error(input, 0, "a statement"),
+            other => other?,
+        };
+        statements.extend(statement);
+        let statement_end = new_input;
+        let (new_input, _) = ws(new_input)?;
+        let (new_input, semicolon) = opt(tag(";"))(new_input)?;
+        if semicolon.is_none() {
+            // Point just after the statement rather than at whatever follows
+            // it, which is usually on the next line.
+            return error_found(statement_end, 0, "`;`", describe_token(new_input, 0));
+        }
+        input = new_input;
+    }
+    Ok((input, statements))
+}
+
+/// Skips whitespace and comments. `#` starts a comment which runs to the end
+/// of the line and `#[` starts one which runs to the matching `]#`, so block
+/// comments can be nested.
+fn ws(input: &str) -> Result<'_, &str> {
+    let start = input;
+    let mut input = input;
+    loop {
+        input = input.trim_start();
+        if input.starts_with("#[") {
+            input = skip_block_comment(input)?.0;
+        } else if input.starts_with('#') {
+            input = &input[input.find('\n').unwrap_or(input.len())..];
+        } else {
+            break;
+        }
+    }
+    Ok((input, &start[..distance(start, input)]))
+}
+
+/// Skips a block comment, including any block comments nested inside it.
+fn skip_block_comment(input: &str) -> Result<'_, ()> {
+    let start = input;
+    let mut input = &input[2..];
+    let mut depth = 1;
+    while depth > 0 {
+        if input.starts_with("#[") {
+            depth += 1;
+            input = &input[2..];
+        } else if input.starts_with("]#") {
+            depth -= 1;
+            input = &input[2..];
+        } else if let Some(c) = input.chars().next() {
+            input = &input[c.len_utf8()..];
+        } else {
+            return error_found(start, 2, "`]#` to close this comment", "end of file");
+        }
+    }
+    Ok((input, ()))
+}
+
+/// Like `ws`, but also returns the text of any `##` doc comments that were
+/// skipped, with one line per comment.
+fn doc_comments(input: &str) -> Result<'_, Option<String>> {
+    let (rest, skipped) = ws(input)?;
+    let mut doc: Option<String> = None;
+    for line in skipped.lines() {
+        if let Some(text) = line.trim_start().strip_prefix("##") {
+            let text = text.strip_prefix(' ').unwrap_or(text).trim_end();
+            match &mut doc {
+                Some(doc) => {
+                    doc.push('\n');
+                    doc.push_str(text);
+                }
+                None => doc = Some(text.to_owned()),
+            }
+        }
+    }
+    Ok((rest, doc))
+}
+
+/// Returns the length of `text` without any whitespace or comments at its end.
+fn content_len(text: &str) -> usize {
+    let mut len = 0;
+    let mut rest = text;
+    while !rest.is_empty() {
+        let after_ws = match ws(rest) {
+            Ok((after_ws, _)) => after_ws,
+            // An unclosed block comment runs to the end of the text.
+            Err(..) => break,
+        };
+        if after_ws.is_empty() {
+            break;
+        }
+        let c = after_ws.chars().next().unwrap();
+        rest = &after_ws[c.len_utf8()..];
+        len = text.len() - rest.len();
+    }
+    len
 }
 
 fn parse_identifier_into_value<'b>(
     scope: &'b Scope,
 ) -> impl for<'a> Fn(&'a str) -> Result<'a, ValuePtr> + 'b {
     move |input| {
-        parse_identifier(scope)
-            .map(|local| ValuePtr::new(Value::Local(local)))
-            .parse(input)
+        let result = opt(parse_identifier(scope))(input)?;
+        if let (input, Some(local)) = result {
+            return Ok((input, ValuePtr::new(Value::Local(local))));
+        }
+        // Anything that looks like an identifier at this point can only be a
+        // reference to something that was never declared.
+        if input.starts_with(char::is_alphabetic) {
+            let (_, text) = parse_identifier_text(input)?;
+            return error_found(
+                input,
+                text.len(),
+                "a declared name",
+                format!("unknown identifier `{}`", text),
+            );
+        }
+        fail(input)
     }
 }
 
-fn parse_identifier_text(input: &str) -> Result<&str> {
-    take_while(|c: char| c.is_alphabetic() || c.is_numeric() || c == '_')(input)
+/// Parses `T?`, which declares a template parameter named `T` the first time
+/// it appears in a function and refers to the same parameter afterwards.
+fn parse_template_parameter<'b>(
+    scope: &'b mut Scope,
+) -> impl for<'a> FnMut(&'a str) -> Result<'a, ValuePtr> + 'b {
+    move |input| {
+        let start = input;
+        let (input, name) = parse_identifier_text(input)?;
+        let (input, _) = tag("?")(input)?;
+        if name.is_empty() {
+            return fail(start);
+        }
+        if let Some(local) = scope.templates.iter().find(|local| local.name == name) {
+            return Ok((input, ValuePtr::new(Value::Local(local.ptr_clone()))));
+        }
+        if scope.all_locals.contains_key(name) {
+            return error_found(
+                start,
+                name.len(),
+                "a new name for a template parameter",
+                format!("`{}`, which is already declared", name),
+            );
+        }
+        let local = LocalPtr::new(Local {
+            compile_time_only: true,
+            name: name.to_owned(),
+            typee: ValuePtr::new(Value::BuiltinType(BuiltinType::Any)),
+            span: Some(scope.span(start, &start[name.len()..])),
+            doc: None,
+        });
+        scope.all_locals.insert(name.to_owned(), local.ptr_clone());
+        scope.templates.push(local.ptr_clone());
+        Ok((input, ValuePtr::new(Value::Local(local))))
+    }
+}
+
+fn parse_identifier_text(input: &str) -> Result<'_, &str> {
+    take_while(is_identifier_char)(input)
 }
 
 fn parse_identifier<'b>(scope: &'b Scope) -> impl for<'a> Fn(&'a str) -> Result<'a, LocalPtr> + 'b {
@@ -120,21 +412,45 @@ fn parse_assignment_lhs<'b>(
             tag("ct_output"),
             tag(""),
         ))(input)?;
-        if label == "" && declaration_mode {
+        if label.is_empty() && declaration_mode {
             return fail(input);
         }
         let (input, _) = ws(input)?;
+        let name_start = input;
         let (input, name) = parse_identifier_text(input)?;
+        let name_span = scope.span(name_start, input);
         let (input, _) = ws(input)?;
+        let indices_start = input;
         let (input, indices) = opt(parse_argument_list(scope))(input)?;
-        if indices.is_some() {
-            assert_eq!(
-                label, "",
-                "Cannot simultaneously declare and index a local."
+        if indices.is_some() && !label.is_empty() {
+            return error_found(
+                indices_start,
+                distance(indices_start, input),
+                "a declaration without indices",
+                "an index on a local that is being declared",
             );
         }
         let (input, _) = ws(input)?;
-        let (input, keyword_8wide) = opt(tag("8wide"))(input)?;
+        // A keyword like `8wide` writes that many consecutive elements.
+        let width_start = input;
+        let (input, width) = opt(tuple((
+            take_while1(|c: char| c.is_ascii_digit()),
+            tag("wide"),
+        )))(input)?;
+        let width = match width {
+            None => 1,
+            Some((digits, _)) => match digits.parse::<i32>() {
+                Ok(width) if width > 0 => width as usize,
+                _ => {
+                    return error_found(
+                        width_start,
+                        distance(width_start, input),
+                        "a width of at least 1",
+                        format!("`{}wide`", digits),
+                    )
+                }
+            },
+        };
         let (input, _) = ws(input)?;
         let (input, has_colon) = opt(tag(":"))(input)?;
         let (input, typee) = if has_colon.is_some() {
@@ -144,7 +460,7 @@ fn parse_assignment_lhs<'b>(
         } else {
             (input, ValuePtr::new(Value::BuiltinType(BuiltinType::Any)))
         };
-        let local = if label == "" || declaration_mode {
+        let local = if label.is_empty() || declaration_mode {
             if let Some(local) = scope.all_locals.get(name) {
                 local.ptr_clone()
             } else {
@@ -155,6 +471,8 @@ fn parse_assignment_lhs<'b>(
                 compile_time_only: label.contains("ct"),
                 name: name.to_owned(),
                 typee,
+                span: Some(name_span),
+                doc: scope.pending_doc.take(),
             })
         };
         if !declaration_mode {
@@ -167,14 +485,7 @@ fn parse_assignment_lhs<'b>(
                 scope.outputs.push(local.ptr_clone());
             }
         }
-        let index = if let Some(indices) = indices {
-            Some(Index {
-                indices,
-                eight_wide_mode: keyword_8wide.is_some(),
-            })
-        } else {
-            None
-        };
+        let index = indices.map(|indices| Index { indices, width });
         Ok((input, (local, index)))
     }
 }
@@ -183,6 +494,7 @@ fn parse_assignment_statement<'b>(
     scope: &'b mut Scope,
 ) -> impl for<'a> FnMut(&'a str) -> Result<'a, Vec<Statement>> + 'b {
     move |input| {
+        let start = input;
         let mut targets = Vec::new();
         let mut input = input;
         loop {
@@ -198,34 +510,47 @@ fn parse_assignment_statement<'b>(
                 return fail(new_input);
             }
         }
-        if targets.len() == 0 {
+        if targets.is_empty() {
             return fail(input);
         }
+        let base_start = input;
         let (input, base) = parse_basic_expression(scope)(input)?;
+        let span = Some(scope.span(start, input));
         let value = if targets.len() == 1 {
             let (target, index) = targets.into_iter().next().unwrap();
             vec![Statement::Assignment {
                 base,
                 index,
                 target,
+                span,
             }]
         } else {
-            if let Value::FunctionCall(base, args, 0) = &*base.borrow() {
+            if let Value::FunctionCall(call_base, args, 0) = &*base.borrow() {
                 let mut value = Vec::new();
                 for (target_index, (target, index)) in targets.into_iter().enumerate() {
+                    let mut output = ValuePtr::new(Value::FunctionCall(
+                        call_base.ptr_clone(),
+                        args.clone(),
+                        target_index,
+                    ));
+                    if let Some(call_span) = base.span() {
+                        output = output.with_span(call_span);
+                    }
                     value.push(Statement::Assignment {
-                        base: ValuePtr::new(Value::FunctionCall(
-                            base.ptr_clone(),
-                            args.clone(),
-                            target_index,
-                        )),
+                        base: output,
                         index,
                         target,
+                        span,
                     });
                 }
                 value
             } else {
-                todo!("Nice error, only function calls can have multiple outputs.")
+                return error_found(
+                    base_start,
+                    distance(base_start, input),
+                    "a function call, since there are multiple assignment targets",
+                    "an expression with a single output",
+                );
             }
         };
         Ok((input, value))
@@ -241,7 +566,7 @@ fn parse_declaration_statement<'b>(
         loop {
             let (new_input, target) = opt(parse_assignment_lhs(scope, true))(input)?;
             if let Some(target) = target {
-                targets.push(target);
+                targets.push((target, scope.span(input, new_input)));
             } else {
                 break;
             }
@@ -251,16 +576,16 @@ fn parse_declaration_statement<'b>(
                 break;
             }
         }
-        if targets.len() == 0 {
+        if targets.is_empty() {
             return fail(input);
         }
         Ok((
             input,
             targets
                 .into_iter()
-                .map(|x| {
-                    debug_assert!(x.1.is_none());
-                    Statement::Declaration(x.0)
+                .map(|((local, index), span)| {
+                    debug_assert!(index.is_none());
+                    Statement::Declaration(local, Some(span))
                 })
                 .collect(),
         ))
@@ -271,6 +596,12 @@ fn parse_statement<'b>(
     scope: &'b mut Scope,
 ) -> impl for<'a> FnMut(&'a str) -> Result<'a, Vec<Statement>> + 'b {
     move |input| {
+        {
+            let result = opt(parse_repeat_statement(scope))(input)?;
+            if let (input, Some(result)) = result {
+                return Ok((input, vec![result]));
+            }
+        }
         {
             let result = opt(parse_assignment_statement(scope))(input)?;
             if let (input, Some(result)) = result {
@@ -293,10 +624,77 @@ fn parse_statement<'b>(
     }
 }
 
+/// Parses `repeat(count) (counter) { body }`, where the counter is optional.
+fn parse_repeat_statement<'b>(
+    scope: &'b mut Scope,
+) -> impl for<'a> FnMut(&'a str) -> Result<'a, Statement> + 'b {
+    move |input| {
+        let start = input;
+        let (input, _) = keyword("repeat")(input)?;
+        let (input, _) = ws(input)?;
+        let (input, _) = required_tag(input, "(")?;
+        let (input, _) = ws(input)?;
+        let (input, count) = parse_required_expression(scope, input)?;
+        let (input, _) = ws(input)?;
+        let (input, _) = required_tag(input, ")")?;
+        let (input, _) = ws(input)?;
+
+        let mut body_scope = scope.clone();
+        body_scope.plain_locals.clear();
+        let (input, counter) = if let Some(after_paren) = input.strip_prefix('(') {
+            let (after_ws, _) = ws(after_paren)?;
+            let name_start = after_ws;
+            let (after_name, name) = parse_identifier_text(after_ws)?;
+            if name.is_empty() {
+                return error(name_start, 0, "a name for the loop counter");
+            }
+            let counter = LocalPtr::new(Local {
+                compile_time_only: true,
+                name: name.to_owned(),
+                typee: ValuePtr::new(Value::BuiltinType(BuiltinType::Int)),
+                span: Some(body_scope.span(name_start, after_name)),
+                doc: None,
+            });
+            body_scope
+                .all_locals
+                .insert(name.to_owned(), counter.ptr_clone());
+            let (input, _) = ws(after_name)?;
+            let (input, _) = required_tag(input, ")")?;
+            let (input, _) = ws(input)?;
+            (input, Some(counter))
+        } else {
+            (input, None)
+        };
+        let (input, _) = required_tag(input, "{")?;
+        let (input, body) = parse_statements(&mut body_scope, input, true)?;
+        let (input, _) = tag("}")(input)?;
+        // Inputs and outputs belong to the enclosing function even when they
+        // are declared inside a loop.
+        scope.inputs = body_scope.inputs;
+        scope.outputs = body_scope.outputs;
+        Ok((
+            input,
+            Statement::Repeat {
+                count,
+                counter,
+                locals: body_scope.plain_locals,
+                body,
+                span: Some(scope.span(start, input)),
+            },
+        ))
+    }
+}
+
 fn parse_basic_expression<'b>(
     scope: &'b mut Scope,
 ) -> impl for<'a> FnMut(&'a str) -> Result<'a, ValuePtr> + 'b {
     move |input| {
+        {
+            let result = opt(parse_conditional(scope))(input)?;
+            if let (input, Some(result)) = result {
+                return Ok((input, result));
+            }
+        }
         {
             let result = opt(parse_expression_5(scope))(input)?;
             if let (input, Some(result)) = result {
@@ -307,17 +705,167 @@ fn parse_basic_expression<'b>(
     }
 }
 
-fn parse_expression_5<'b>(
+/// Parses `if condition then a else b`. Conditionals have the lowest
+/// precedence of any expression, so `else if` chains need no parentheses.
+fn parse_conditional<'b>(
     scope: &'b mut Scope,
 ) -> impl for<'a> FnMut(&'a str) -> Result<'a, ValuePtr> + 'b {
     move |input| {
-        {
-            let result = opt(parse_expression_4(scope))(input)?;
-            if let (input, Some(result)) = result {
-                return Ok((input, result));
+        let start = input;
+        let (input, _) = keyword("if")(input)?;
+        let (input, _) = ws(input)?;
+        let (input, condition) = parse_required_expression(scope, input)?;
+        let (input, _) = ws(input)?;
+        let (input, _) = required_keyword(input, "then")?;
+        let (input, _) = ws(input)?;
+        let (input, if_true) = parse_required_expression(scope, input)?;
+        let (input, _) = ws(input)?;
+        let (input, _) = required_keyword(input, "else")?;
+        let (input, _) = ws(input)?;
+        let (input, if_false) = parse_required_expression(scope, input)?;
+        let value = ValuePtr::new(Value::Select {
+            condition,
+            if_true,
+            if_false,
+        });
+        Ok((input, value.with_span(scope.span(start, input))))
+    }
+}
+
+fn parse_required_expression<'a>(scope: &mut Scope, input: &'a str) -> Result<'a, ValuePtr> {
+    match parse_basic_expression(scope)(input) {
+        Err(nom::Err::Error(..)) => error(input, 0, "an expression"),
+        other => other,
+    }
+}
+
+fn required_tag<'a>(input: &'a str, text: &str) -> Result<'a, &'a str> {
+    match tag(text)(input) {
+        Err(nom::Err::Error(..)) => error(input, 0, format!("`{}`", text)),
+        other => other,
+    }
+}
+
+fn required_keyword<'a>(input: &'a str, text: &str) -> Result<'a, &'a str> {
+    match keyword(text)(input) {
+        Err(nom::Err::Error(..)) => error(input, 0, format!("`{}`", text)),
+        other => other,
+    }
+}
+
+/// Operators are listed longest-first so that e.g. `**` is not parsed as `*`.
+const LOGICAL_OR_OPERATORS: &[(&str, BuiltinOp)] =
+    &[("or", BuiltinOp::Or), ("xor", BuiltinOp::Xor)];
+const LOGICAL_AND_OPERATORS: &[(&str, BuiltinOp)] = &[("and", BuiltinOp::And)];
+const COMPARISON_OPERATORS: &[(&str, BuiltinOp)] = &[
+    ("==", BuiltinOp::Eq),
+    ("!=", BuiltinOp::Neq),
+    ("<=", BuiltinOp::Lte),
+    (">=", BuiltinOp::Gte),
+    ("<", BuiltinOp::Lt),
+    (">", BuiltinOp::Gt),
+];
+const ADDITIVE_OPERATORS: &[(&str, BuiltinOp)] = &[("+", BuiltinOp::Add), ("-", BuiltinOp::Sub)];
+const MULTIPLICATIVE_OPERATORS: &[(&str, BuiltinOp)] = &[
+    ("//", BuiltinOp::IntDiv),
+    ("*", BuiltinOp::Mul),
+    ("/", BuiltinOp::Div),
+    ("%", BuiltinOp::Rem),
+];
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_alphabetic() || c.is_numeric() || c == '_'
+}
+
+/// Like `tag`, but refuses to match if the keyword is immediately followed by
+/// more identifier characters, so that `and` does not match the start of
+/// `android`.
+fn keyword<'k>(keyword: &'k str) -> impl for<'a> Fn(&'a str) -> Result<'a, &'a str> + 'k {
+    move |input| {
+        let (rest, matched) = tag(keyword)(input)?;
+        if rest.chars().next().map(is_identifier_char).unwrap_or(false) {
+            fail(input)
+        } else {
+            Ok((rest, matched))
+        }
+    }
+}
+
+fn parse_binary_operator<'a>(
+    input: &'a str,
+    operators: &[(&str, BuiltinOp)],
+) -> Result<'a, BuiltinOp> {
+    let (input, _) = ws(input)?;
+    for &(text, op) in operators {
+        let result = if text.chars().all(is_identifier_char) {
+            keyword(text)(input)
+        } else {
+            tag(text)(input)
+        };
+        if let Ok((input, _)) = result {
+            // Don't mistake a `**` for a `*` followed by garbage.
+            if text == "*" && input.starts_with('*') {
+                continue;
             }
+            let (input, _) = ws(input)?;
+            return Ok((input, op));
         }
-        fail(input)
+    }
+    fail(input)
+}
+
+fn make_binary_op(op: BuiltinOp, lhs: ValuePtr, rhs: ValuePtr) -> ValuePtr {
+    ValuePtr::new(Value::FunctionCall(
+        ValuePtr::new(Value::BuiltinOp(op)),
+        vec![lhs, rhs],
+        0,
+    ))
+}
+
+fn make_unary_op(op: BuiltinOp, operand: ValuePtr) -> ValuePtr {
+    ValuePtr::new(Value::FunctionCall(
+        ValuePtr::new(Value::BuiltinOp(op)),
+        vec![operand],
+        0,
+    ))
+}
+
+/// Parses a left-associative chain of operands joined by any of the given
+/// operators, like `a + b - c`.
+fn parse_binary_level<'a>(
+    scope: &mut Scope,
+    input: &'a str,
+    operators: &[(&str, BuiltinOp)],
+    mut parse_operand: impl FnMut(&mut Scope, &'a str) -> Result<'a, ValuePtr>,
+) -> Result<'a, ValuePtr> {
+    let start = input;
+    let (mut input, mut value) = parse_operand(scope, input)?;
+    loop {
+        let (after_op, op) = opt(|i| parse_binary_operator(i, operators))(input)?;
+        let op = if let Some(op) = op {
+            op
+        } else {
+            break;
+        };
+        let (after_rhs, rhs) = opt(|i| parse_operand(scope, i))(after_op)?;
+        let rhs = if let Some(rhs) = rhs {
+            rhs
+        } else {
+            break;
+        };
+        value = make_binary_op(op, value, rhs).with_span(scope.span(start, after_rhs));
+        input = after_rhs;
+    }
+    Ok((input, value))
+}
+
+fn parse_expression_5<'b>(
+    scope: &'b mut Scope,
+) -> impl for<'a> FnMut(&'a str) -> Result<'a, ValuePtr> + 'b {
+    move |input| {
+        parse_binary_level(scope, input, LOGICAL_OR_OPERATORS, |scope, input| {
+            parse_expression_4(scope)(input)
+        })
     }
 }
 
@@ -325,13 +873,26 @@ fn parse_expression_4<'b>(
     scope: &'b mut Scope,
 ) -> impl for<'a> FnMut(&'a str) -> Result<'a, ValuePtr> + 'b {
     move |input| {
-        {
-            let result = opt(parse_expression_3(scope))(input)?;
-            if let (input, Some(result)) = result {
-                return Ok((input, result));
-            }
+        parse_binary_level(scope, input, LOGICAL_AND_OPERATORS, |scope, input| {
+            parse_logical_not(scope)(input)
+        })
+    }
+}
+
+/// Like in Python, `not` binds more loosely than comparisons so that
+/// `not a == b` is `not (a == b)`.
+fn parse_logical_not<'b>(
+    scope: &'b mut Scope,
+) -> impl for<'a> FnMut(&'a str) -> Result<'a, ValuePtr> + 'b {
+    move |input| {
+        let start = input;
+        let (after_not, not) = opt(tuple((keyword("not"), ws)))(input)?;
+        if not.is_none() {
+            return parse_expression_3(scope)(input);
         }
-        fail(input)
+        let (input, operand) = parse_logical_not(scope)(after_not)?;
+        let value = make_unary_op(BuiltinOp::Not, operand);
+        Ok((input, value.with_span(scope.span(start, input))))
     }
 }
 
@@ -339,13 +900,9 @@ fn parse_expression_3<'b>(
     scope: &'b mut Scope,
 ) -> impl for<'a> FnMut(&'a str) -> Result<'a, ValuePtr> + 'b {
     move |input| {
-        {
-            let result = opt(parse_expression_2(scope))(input)?;
-            if let (input, Some(result)) = result {
-                return Ok((input, result));
-            }
-        }
-        fail(input)
+        parse_binary_level(scope, input, COMPARISON_OPERATORS, |scope, input| {
+            parse_expression_2(scope)(input)
+        })
     }
 }
 
@@ -353,32 +910,81 @@ fn parse_expression_2<'b>(
     scope: &'b mut Scope,
 ) -> impl for<'a> FnMut(&'a str) -> Result<'a, ValuePtr> + 'b {
     move |input| {
-        {
-            let result = opt(parse_expression_1(scope))(input)?;
-            if let (input, Some(result)) = result {
-                return Ok((input, result));
+        parse_binary_level(scope, input, ADDITIVE_OPERATORS, |scope, input| {
+            parse_expression_1(scope)(input)
+        })
+    }
+}
+
+fn parse_expression_1<'b>(
+    scope: &'b mut Scope,
+) -> impl for<'a> FnMut(&'a str) -> Result<'a, ValuePtr> + 'b {
+    move |input| {
+        parse_binary_level(scope, input, MULTIPLICATIVE_OPERATORS, |scope, input| {
+            parse_negation(scope)(input)
+        })
+    }
+}
+
+/// A unary minus binds more loosely than exponentiation, so `-2 ** 2` is
+/// `-(2 ** 2)`. Negated number literals become negative literals rather than
+/// negations.
+fn parse_negation<'b>(
+    scope: &'b mut Scope,
+) -> impl for<'a> FnMut(&'a str) -> Result<'a, ValuePtr> + 'b {
+    move |input| {
+        let start = input;
+        let (after_minus, minus) = opt(tuple((tag("-"), ws)))(input)?;
+        if minus.is_none() {
+            return parse_power_expression(scope)(input);
+        }
+        let (after_literal, literal) = opt(|i| parse_number(i, true))(after_minus)?;
+        if let Some(literal) = literal {
+            let (after_op, _) = ws(after_literal)?;
+            if !after_op.starts_with("**") {
+                let span = scope.span(start, after_literal);
+                return Ok((after_literal, ValuePtr::new(literal).with_span(span)));
             }
         }
-        fail(input)
+        let (input, operand) = parse_negation(scope)(after_minus)?;
+        let value = make_unary_op(BuiltinOp::Neg, operand);
+        Ok((input, value.with_span(scope.span(start, input))))
     }
 }
 
-fn parse_expression_1<'b>(
+/// Exponentiation binds tighter than multiplication and is right-associative,
+/// so `a ** b ** c` is `a ** (b ** c)`.
+fn parse_power_expression<'b>(
     scope: &'b mut Scope,
 ) -> impl for<'a> FnMut(&'a str) -> Result<'a, ValuePtr> + 'b {
     move |input| {
-        {
-            let result = opt(parse_expression_0(scope))(input)?;
-            if let (input, Some(result)) = result {
-                return Ok((input, result));
+        let start = input;
+        let (input, base) = parse_expression_0(scope)(input)?;
+        let (after_op, op) = opt(tuple((ws, tag("**"), ws)))(input)?;
+        if op.is_some() {
+            let result = opt(parse_negation(scope))(after_op)?;
+            if let (input, Some(exponent)) = result {
+                let span = scope.span(start, input);
+                let value = make_binary_op(BuiltinOp::Pow, base, exponent).with_span(span);
+                return Ok((input, value));
             }
         }
-        fail(input)
+        Ok((input, base))
     }
 }
 
 fn parse_expression_0<'b>(
     scope: &'b mut Scope,
+) -> impl for<'a> FnMut(&'a str) -> Result<'a, ValuePtr> + 'b {
+    move |input| {
+        let start = input;
+        let (input, value) = parse_unspanned_expression_0(scope)(input)?;
+        Ok((input, value.with_span(scope.span(start, input))))
+    }
+}
+
+fn parse_unspanned_expression_0<'b>(
+    scope: &'b mut Scope,
 ) -> impl for<'a> FnMut(&'a str) -> Result<'a, ValuePtr> + 'b {
     move |input| {
         {
@@ -388,13 +994,19 @@ fn parse_expression_0<'b>(
             }
         }
         {
-            let result = opt(parse_int_literal)(input)?;
-            if let (input, Some(result)) = result {
-                return Ok((input, result));
+            let result = opt(parse_array_literal(scope))(input)?;
+            if let (input, Some(value)) = result {
+                return Ok((input, value));
             }
         }
         {
-            let result = opt(parse_float_literal)(input)?;
+            let result = opt(parse_parenthesized_expression(scope))(input)?;
+            if let (input, Some(value)) = result {
+                return Ok((input, value));
+            }
+        }
+        {
+            let result = opt(parse_number_literal)(input)?;
             if let (input, Some(result)) = result {
                 return Ok((input, result));
             }
@@ -406,37 +1018,37 @@ fn parse_expression_0<'b>(
             }
         }
         {
-            let result = opt(tag("ANY"))(input)?;
+            let result = opt(keyword("ANY"))(input)?;
             if let (input, Some(_)) = result {
                 return Ok((input, ValuePtr::new(Value::BuiltinType(BuiltinType::Any))));
             }
         }
         {
-            let result = opt(tag("MALFORMED"))(input)?;
+            let result = opt(keyword("MALFORMED"))(input)?;
             if let (input, Some(_)) = result {
                 return Ok((input, ValuePtr::new(Value::Malformed)));
             }
         }
         {
-            let result = opt(tag("Int"))(input)?;
+            let result = opt(keyword("Int"))(input)?;
             if let (input, Some(_)) = result {
                 return Ok((input, ValuePtr::new(Value::BuiltinType(BuiltinType::Int))));
             }
         }
         {
-            let result = opt(tag("Float"))(input)?;
+            let result = opt(keyword("Float"))(input)?;
             if let (input, Some(_)) = result {
                 return Ok((input, ValuePtr::new(Value::BuiltinType(BuiltinType::Float))));
             }
         }
         {
-            let result = opt(tag("Bool"))(input)?;
+            let result = opt(keyword("Bool"))(input)?;
             if let (input, Some(_)) = result {
                 return Ok((input, ValuePtr::new(Value::BuiltinType(BuiltinType::Bool))));
             }
         }
         {
-            let result = opt(tag("Malformed"))(input)?;
+            let result = opt(keyword("Malformed"))(input)?;
             if let (input, Some(_)) = result {
                 return Ok((
                     input,
@@ -445,6 +1057,7 @@ fn parse_expression_0<'b>(
             }
         }
         {
+            let start = input;
             let result = opt(parse_function_call(scope))(input)?;
             if let (input, Some((name, args))) = result {
                 let base = match name {
@@ -453,6 +1066,8 @@ fn parse_expression_0<'b>(
                     "mul" => ValuePtr::new(Value::BuiltinOp(BuiltinOp::Mul)),
                     "div" => ValuePtr::new(Value::BuiltinOp(BuiltinOp::Div)),
                     "rem" => ValuePtr::new(Value::BuiltinOp(BuiltinOp::Rem)),
+                    "pow" => ValuePtr::new(Value::BuiltinOp(BuiltinOp::Pow)),
+                    "neg" => ValuePtr::new(Value::BuiltinOp(BuiltinOp::Neg)),
 
                     "gt" => ValuePtr::new(Value::BuiltinOp(BuiltinOp::Gt)),
                     "lt" => ValuePtr::new(Value::BuiltinOp(BuiltinOp::Lt)),
@@ -470,7 +1085,14 @@ fn parse_expression_0<'b>(
                     "typeof" => ValuePtr::new(Value::BuiltinOp(BuiltinOp::Typeof)),
 
                     "Array" => {
-                        assert!(args.len() >= 2);
+                        if args.len() < 2 {
+                            return error_found(
+                                start,
+                                distance(start, input),
+                                "an element type and at least one dimension",
+                                format!("{} argument(s) to `Array`", args.len()),
+                            );
+                        }
                         let mut args = args.into_iter();
                         let eltype = args.next().unwrap();
                         let dims = args.collect();
@@ -478,7 +1100,14 @@ fn parse_expression_0<'b>(
                         return Ok((input, ValuePtr::new(value)));
                     }
                     "InSet" => {
-                        assert!(args.len() >= 1);
+                        if args.is_empty() {
+                            return error_found(
+                                start,
+                                distance(start, input),
+                                "at least one element",
+                                "an empty `InSet`",
+                            );
+                        }
                         let mut iter = args.clone().into_iter();
                         let mut eltype = ValuePtr::new(iter.next().unwrap().typee());
                         for arg in iter {
@@ -492,12 +1121,38 @@ fn parse_expression_0<'b>(
                         let value = Value::BuiltinType(BuiltinType::InSet { eltype, elements });
                         return Ok((input, ValuePtr::new(value)));
                     }
-                    "Fn" => todo!(),
+                    "Fn" => {
+                        return error_found(
+                            start,
+                            name.len(),
+                            "a supported type",
+                            "`Fn`, which is not supported yet",
+                        );
+                    }
                     _ => {
                         if let Some(base) = scope.all_locals.get(name) {
-                            ValuePtr::new(Value::Local(base.ptr_clone()))
+                            let base_is_array = matches!(
+                                &*base.typee.borrow(),
+                                Value::BuiltinType(BuiltinType::Array { .. })
+                            );
+                            let base = ValuePtr::new(Value::Local(base.ptr_clone()));
+                            if base_is_array {
+                                let value = Value::ElementRead {
+                                    base,
+                                    indices: args,
+                                };
+                                return Ok((input, ValuePtr::new(value)));
+                            }
+                            base
+                        } else if name.is_empty() {
+                            return fail(start);
                         } else {
-                            return fail(input);
+                            return error_found(
+                                start,
+                                name.len(),
+                                "a declared name",
+                                format!("unknown identifier `{}`", name),
+                            );
                         }
                     }
                 };
@@ -505,6 +1160,12 @@ fn parse_expression_0<'b>(
                 return Ok((input, ValuePtr::new(value)));
             }
         }
+        {
+            let result = opt(parse_template_parameter(scope))(input)?;
+            if let (input, Some(result)) = result {
+                return Ok((input, result));
+            }
+        }
         {
             let result = opt(parse_identifier_into_value(scope))(input)?;
             if let (input, Some(result)) = result {
@@ -515,6 +1176,57 @@ fn parse_expression_0<'b>(
     }
 }
 
+fn parse_parenthesized_expression<'b>(
+    scope: &'b mut Scope,
+) -> impl for<'a> FnMut(&'a str) -> Result<'a, ValuePtr> + 'b {
+    move |input| {
+        let (input, _) = tuple((tag("("), ws))(input)?;
+        let (input, value) = parse_basic_expression(scope)(input)?;
+        let (input, _) = ws(input)?;
+        let (input, close) = opt(tag(")"))(input)?;
+        if close.is_none() {
+            return error(input, 0, "`)`");
+        }
+        Ok((input, value))
+    }
+}
+
+fn parse_array_literal<'b>(
+    scope: &'b mut Scope,
+) -> impl for<'a> FnMut(&'a str) -> Result<'a, ValuePtr> + 'b {
+    move |input| {
+        let start = input;
+        let (input, _) = tuple((tag("["), ws))(input)?;
+        let result = opt(parse_comma_expression_list(scope))(input)?;
+        let (input, elements) = if let (input, Some(elements)) = result {
+            (input, elements)
+        } else {
+            return error(input, 0, "an array element");
+        };
+        let (input, _) = ws(input)?;
+        let (input, close) = opt(tag("]"))(input)?;
+        if close.is_none() {
+            return error(input, 0, "`,` or `]`");
+        }
+        let dims = vec![ValuePtr::new(Value::IntLiteral(elements.len() as i32))];
+        let value = match flatten_array_literal(&elements, &dims) {
+            FlattenedArrayLiteral::Unchanged => Value::ArrayLiteral { elements, dims },
+            FlattenedArrayLiteral::Flattened { elements, dims } => {
+                Value::ArrayLiteral { elements, dims }
+            }
+            FlattenedArrayLiteral::Ragged => {
+                return error_found(
+                    start,
+                    distance(start, input),
+                    "elements which all have the same shape",
+                    "a ragged array literal",
+                );
+            }
+        };
+        Ok((input, ValuePtr::new(value)))
+    }
+}
+
 fn parse_comma_expression_list<'b>(
     scope: &'b mut Scope,
 ) -> impl for<'a> FnMut(&'a str) -> Result<Vec<ValuePtr>> + 'b {
@@ -549,7 +1261,12 @@ fn parse_argument_list<'b>(
     move |input| {
         let (input, _) = tuple((ws, tag("("), ws))(input)?;
         let (input, args) = parse_comma_expression_list(scope)(input)?;
-        let (input, _) = tuple((ws, tag(")"), ws))(input)?;
+        let (input, _) = ws(input)?;
+        let (input, close) = opt(tag(")"))(input)?;
+        if close.is_none() {
+            return error(input, 0, "`,` or `)`");
+        }
+        let (input, _) = ws(input)?;
         Ok((input, args))
     }
 }
@@ -562,12 +1279,11 @@ fn parse_function_def<'b>(
         new_scope.plain_locals.clear();
         new_scope.inputs.clear();
         new_scope.outputs.clear();
+        new_scope.templates.clear();
         let (input, _) = tag("fn")(input)?;
         let (input, _) = ws(input)?;
         let (input, _) = tag("{")(input)?;
-        let (input, _) = ws(input)?;
-        let (input, body) = parse_body(&mut new_scope)(input)?;
-        let (input, _) = ws(input)?;
+        let (input, body) = parse_statements(&mut new_scope, input, true)?;
         let (input, _) = tag("}")(input)?;
         Ok((
             input,
@@ -575,30 +1291,124 @@ fn parse_function_def<'b>(
                 inputs: new_scope.inputs,
                 outputs: new_scope.outputs,
                 locals: new_scope.plain_locals,
+                templates: new_scope.templates,
                 body,
             }),
         ))
     }
 }
 
-fn parse_int_literal(input: &str) -> Result<ValuePtr> {
-    // TODO: Error.
-    let (input, chars) = take_while1(|c| "0123456789_".contains(c))(input)?;
-    let number = chars.parse().unwrap();
-    let value = ValuePtr::new(Value::IntLiteral(number));
-    Ok((input, value))
+/// Parses an integer or float literal. Literals have no sign of their own,
+/// negative numbers are written using the unary minus operator.
+fn parse_number_literal(input: &str) -> Result<'_, ValuePtr> {
+    let (input, value) = parse_number(input, false)?;
+    Ok((input, ValuePtr::new(value)))
 }
 
-fn parse_float_literal(input: &str) -> Result<ValuePtr> {
-    // TODO: Error.
-    // TODO: Make this better.
-    let (input, chars) = take_while1(|c| "0123456789_.e+-".contains(c))(input)?;
-    let number = chars.parse().unwrap();
-    let value = ValuePtr::new(Value::FloatLiteral(number));
-    Ok((input, value))
+const RADIX_PREFIXES: &[(&str, u32, &str)] = &[
+    ("0x", 16, "a hexadecimal digit"),
+    ("0b", 2, "a binary digit"),
+    ("0o", 8, "an octal digit"),
+];
+
+/// Parses a number literal, negating it if `negative` is true. Negating here
+/// rather than afterwards allows literals like `-2147483648` whose magnitude
+/// does not fit in an Int.
+fn parse_number(input: &str, negative: bool) -> Result<'_, Value> {
+    let start = input;
+    for &(prefix, radix, expected) in RADIX_PREFIXES {
+        if let Some(rest) = input.strip_prefix(prefix) {
+            let (rest, digits) = take_while(|c: char| c.is_digit(radix) || c == '_')(rest)?;
+            if rest.starts_with(is_identifier_char) || !digits.contains(|c| c != '_') {
+                return error(rest, 0, expected);
+            }
+            return parse_int_digits(start, rest, digits, radix, negative);
+        }
+    }
+
+    let is_digit_or_underscore = |c: char| c.is_ascii_digit() || c == '_';
+    let (rest, whole) = take_while(is_digit_or_underscore)(input)?;
+    if whole.starts_with('_') {
+        return fail(input);
+    }
+    let (rest, fraction) = if let Some(after_point) = rest.strip_prefix('.') {
+        let (after_fraction, fraction) = take_while(is_digit_or_underscore)(after_point)?;
+        if fraction.starts_with('_') || (whole.is_empty() && fraction.is_empty()) {
+            return fail(input);
+        }
+        (after_fraction, Some(fraction))
+    } else if whole.is_empty() {
+        return fail(input);
+    } else {
+        (rest, None)
+    };
+    let (rest, exponent) = if let Some(after_e) = rest.strip_prefix(|c| c == 'e' || c == 'E') {
+        let (after_sign, _) = opt(alt((tag("+"), tag("-"))))(after_e)?;
+        let (after_exponent, digits) = take_while(is_digit_or_underscore)(after_sign)?;
+        if !digits.starts_with(|c: char| c.is_ascii_digit()) {
+            return error(after_sign, 0, "the digits of an exponent");
+        }
+        (after_exponent, Some(digits))
+    } else {
+        (rest, None)
+    };
+    if rest.starts_with(is_identifier_char) {
+        return error_found(rest, 0, "the end of a number", describe_token(rest, 0));
+    }
+
+    let text = &start[..distance(start, rest)];
+    if fraction.is_none() && exponent.is_none() {
+        // Like in C, a leading zero means the rest of the digits are octal.
+        return if whole.len() > 1 && whole.starts_with('0') {
+            if whole.contains(['8', '9']) {
+                return error_found(start, text.len(), "an octal digit", format!("`{}`", text));
+            }
+            parse_int_digits(start, rest, &whole[1..], 8, negative)
+        } else {
+            parse_int_digits(start, rest, whole, 10, negative)
+        };
+    }
+    let cleaned: String = text.chars().filter(|&c| c != '_').collect();
+    match cleaned.parse::<f32>() {
+        Ok(value) if value.is_finite() => {
+            let value = if negative { -value } else { value };
+            Ok((rest, Value::FloatLiteral(value)))
+        }
+        _ => error_found(
+            start,
+            text.len(),
+            "a float small enough to fit in 32 bits",
+            format!("`{}`", text),
+        ),
+    }
+}
+
+fn parse_int_digits<'a>(
+    start: &'a str,
+    rest: &'a str,
+    digits: &str,
+    radix: u32,
+    negative: bool,
+) -> Result<'a, Value> {
+    let cleaned: String = digits.chars().filter(|&c| c != '_').collect();
+    let magnitude = i64::from_str_radix(&cleaned, radix).ok();
+    let value = magnitude.map(|magnitude| if negative { -magnitude } else { magnitude });
+    match value.map(i32::try_from) {
+        Some(Ok(value)) => Ok((rest, Value::IntLiteral(value))),
+        _ => {
+            let text = &start[..distance(start, rest)];
+            let sign = if negative { "-" } else { "" };
+            error_found(
+                start,
+                text.len(),
+                format!("an integer from {} to {}", i32::MIN, i32::MAX),
+                format!("`{}{}`", sign, text),
+            )
+        }
+    }
 }
 
-fn parse_bool_literal(input: &str) -> Result<ValuePtr> {
+fn parse_bool_literal(input: &str) -> Result<'_, ValuePtr> {
     let base = tag("TRUE")
         .map(|_| ValuePtr::new(Value::BoolLiteral(true)))
         .parse(input);
@@ -1,30 +1,2283 @@
 #![cfg(test)]
 
-use crate::{parser::parse_root, values::simplify::SimplificationContext, concrete::solidify};
+use std::collections::HashMap;
+
+use crate::{
+    concrete::{
+        solidify, solidify_with, ConcreteProgram, ConcreteScalarType, ConcreteValue,
+        OutOfBoundsPolicy, SolidificationContext, UnaryOp,
+    },
+    interpreter::{execute, Scalar},
+    parser::{parse_root, ParseError},
+    values::{
+        simplify::{SimplificationContext, DEFAULT_RECURSION_LIMIT, DEFAULT_UNROLL_LIMIT},
+        BuiltinOp, BuiltinType, CompileError, CompileErrorKind, LocalPtr, Statement, Value,
+        ValuePtr,
+    },
+};
+
+fn try_simplify_source(
+    source: &str,
+) -> Result<Vec<HashMap<LocalPtr, ValuePtr>>, Vec<CompileError>> {
+    let (_scope, statements) = parse_root(source).unwrap();
+    let mut ctx = SimplificationContext::new();
+    for statement in statements {
+        statement.check_and_simplify(&mut ctx);
+    }
+    ctx.finish()
+}
+
+fn simplify_source(source: &str) -> Vec<HashMap<LocalPtr, ValuePtr>> {
+    try_simplify_source(source).unwrap()
+}
+
+fn compile_errors(source: &str) -> Vec<CompileError> {
+    match try_simplify_source(source) {
+        Ok(..) => panic!("Expected {:?} to fail to compile", source),
+        Err(errors) => errors,
+    }
+}
+
+fn find_value(blocks: &[HashMap<LocalPtr, ValuePtr>], name: &str) -> Value {
+    for block in blocks {
+        for (local, value) in block {
+            if local.name == name {
+                return value.borrow().clone();
+            }
+        }
+    }
+    panic!("No value assigned to {}", name)
+}
 
 #[test]
 fn basic_parsing() {
     let file = r#"
-    local thing = fn {
+    ct_local thing = fn {
         output d: Array(Int, 5);
         d(1) = 5;
     };
 "#;
-    let result = parse_root(file);
-    if let Ok((_, (_scope, statements))) = result {
-        let mut ctx = SimplificationContext::new();
-        for statement in statements {
-            statement.check_and_simplify(&mut ctx);
-        }
-        let blocks = ctx.finish();
-        println!("{:#?}", blocks);
-        // for (local, value) in assignments {
-        //     if local.name == "thing" {
-        //         println!("{:#?}", solidify(value));
-        //     }
-        // }
+    let blocks = simplify_source(file);
+    assert!(matches!(
+        find_value(&blocks, "thing"),
+        Value::Function { .. }
+    ));
+}
+
+#[test]
+fn operator_precedence() {
+    let blocks = simplify_source(
+        r#"
+        local a = 1 + 2 * 3;
+        local b = (1 + 2) * 3;
+        local c = 10 - 4 - 3;
+        local d = 2 ** 3 ** 2;
+        local e = 7 // 2 + 7 % 4;
+        local f = 1 + 2 < 4 and 3 > 2;
+        local g = 1 == 2 or 2 != 2 xor 3 >= 3;
+    "#,
+    );
+    assert_eq!(find_value(&blocks, "a"), Value::IntLiteral(7));
+    assert_eq!(find_value(&blocks, "b"), Value::IntLiteral(9));
+    assert_eq!(find_value(&blocks, "c"), Value::IntLiteral(3));
+    assert_eq!(find_value(&blocks, "d"), Value::IntLiteral(512));
+    assert_eq!(find_value(&blocks, "e"), Value::IntLiteral(6));
+    assert_eq!(find_value(&blocks, "f"), Value::BoolLiteral(true));
+    assert_eq!(find_value(&blocks, "g"), Value::BoolLiteral(true));
+}
+
+#[test]
+fn constant_integer_arithmetic_wraps() {
+    let blocks = simplify_source(
+        r#"
+        local a = 1 / 0;
+        local b = 1 // 0;
+        local c = 1 % 0;
+        local d = (0 - 2147483647 - 1) // (0 - 1);
+        local e = (0 - 2147483647 - 1) % (0 - 1);
+        local f = 2147483647 + 1;
+        local g = 0 - 2147483647 - 2;
+        local h = 65536 * 65536;
+    "#,
+    );
+    assert_eq!(find_value(&blocks, "a"), Value::IntLiteral(0));
+    assert_eq!(find_value(&blocks, "b"), Value::IntLiteral(0));
+    assert_eq!(find_value(&blocks, "c"), Value::IntLiteral(0));
+    assert_eq!(find_value(&blocks, "d"), Value::IntLiteral(i32::MIN));
+    assert_eq!(find_value(&blocks, "e"), Value::IntLiteral(0));
+    assert_eq!(find_value(&blocks, "f"), Value::IntLiteral(i32::MIN));
+    assert_eq!(find_value(&blocks, "g"), Value::IntLiteral(i32::MAX));
+    assert_eq!(find_value(&blocks, "h"), Value::IntLiteral(0));
+}
+
+#[test]
+fn integer_powers_wrap() {
+    let blocks = simplify_source(
+        r#"
+        local a = 2 ** 40;
+        local b = 0 ** (0 - 1);
+        local c = 2 ** (0 - 2147483647 - 1);
+        local d = (0 - 1) ** (0 - 2147483647 - 1);
+        local e = (0 - 1) ** (0 - 3);
+        local f = 3 ** 21;
+        local g = 2 ** (0 - 1);
+    "#,
+    );
+    assert_eq!(find_value(&blocks, "a"), Value::IntLiteral(0));
+    assert_eq!(find_value(&blocks, "b"), Value::IntLiteral(0));
+    assert_eq!(find_value(&blocks, "c"), Value::IntLiteral(0));
+    assert_eq!(find_value(&blocks, "d"), Value::IntLiteral(1));
+    assert_eq!(find_value(&blocks, "e"), Value::IntLiteral(-1));
+    assert_eq!(
+        find_value(&blocks, "f"),
+        Value::IntLiteral(3i32.wrapping_pow(21))
+    );
+    assert_eq!(find_value(&blocks, "g"), Value::IntLiteral(0));
+}
+
+#[test]
+fn infix_matches_named_calls() {
+    let (_scope, statements) = parse_root(
+        r#"
+        input a; input b; input c;
+        local infix = a * b + c >= a and b < c;
+        local named = and(gte(add(mul(a, b), c), a), lt(b, c));
+    "#,
+    )
+    .unwrap();
+    let bases: Vec<_> = statements
+        .iter()
+        .filter_map(|statement| match statement {
+            Statement::Assignment { base, .. } => Some(base.ptr_clone()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(bases.len(), 2);
+    assert_eq!(bases[0], bases[1]);
+}
+
+fn parse_error(source: &str) -> ParseError {
+    match parse_root(source) {
+        Ok(..) => panic!("Expected {:?} to fail to parse", source),
+        Err(err) => err,
+    }
+}
+
+#[test]
+fn missing_semicolon_is_reported() {
+    let err = parse_error("local a = 1;\nlocal b = 2\nlocal c = 3;");
+    assert_eq!((err.line, err.column), (2, 12));
+    assert_eq!(err.expected, "`;`");
+    assert_eq!(err.found, "`local`");
+}
+
+#[test]
+fn unknown_identifier_is_reported() {
+    let source = "local a = 1;\nlocal b = a + bogus;";
+    let err = parse_error(source);
+    assert_eq!((err.line, err.column), (2, 15));
+    assert_eq!(&source[err.span.range()], "bogus");
+    assert!(err.found.contains("bogus"));
+}
+
+#[test]
+fn unbalanced_delimiters_are_reported() {
+    let err = parse_error("local a = (1 + 2;");
+    assert_eq!(err.expected, "`)`");
+    assert_eq!(err.found, "`;`");
+
+    let err = parse_error("ct_local f = fn {\n    output o;\n    o = 1;\n");
+    assert_eq!(err.expected, "`}`");
+    assert_eq!(err.found, "end of file");
+
+    let err = parse_error("local a = 1;\n};");
+    assert_eq!((err.line, err.column), (2, 1));
+    assert_eq!(err.found, "unmatched `}`");
+}
+
+#[test]
+fn invalid_assignment_is_reported() {
+    let errors = compile_errors("input x: Float; local a: Int = x;");
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].kind, CompileErrorKind::InvalidAssignment);
+    assert_eq!(
+        *errors[0].types[0].borrow(),
+        Value::BuiltinType(BuiltinType::Float)
+    );
+    assert_eq!(errors[0].locals[0].name, "a");
+}
+
+#[test]
+fn invalid_operation_is_reported_once() {
+    let errors = compile_errors(
+        r#"
+        input flag: Bool;
+        local a = flag + 1;
+        local b = a * 2;
+    "#,
+    );
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].kind, CompileErrorKind::InvalidOperation);
+    assert_eq!(errors[0].locals[0].name, "flag");
+}
+
+#[test]
+fn function_call_errors_are_reported() {
+    let errors = compile_errors(
+        r#"
+        ct_local f = fn {
+            input x: Int;
+            output y: Int;
+            output z: Int;
+            y = x;
+        };
+        local a = f(1, 2);
+        local b = f(TRUE);
+        local c, local d = f(1);
+    "#,
+    );
+    let kinds: Vec<_> = errors.iter().map(|error| error.kind.clone()).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            CompileErrorKind::WrongArgumentCount {
+                expected: 1,
+                found: 2
+            },
+            CompileErrorKind::OutputNotAssigned,
+            CompileErrorKind::OutputNotAssigned,
+            CompileErrorKind::OutputNotAssigned,
+        ]
+    );
+    assert_eq!(errors[1].locals[0].name, "z");
+}
+
+#[test]
+fn errors_point_at_source() {
+    let source = "input flag: Bool;\nlocal a = 2 * (flag + 1);";
+    let errors = compile_errors(source);
+    let span = errors[0].span.unwrap();
+    assert_eq!(&source[span.range()], "(flag + 1)");
+    assert!(errors[0].call_sites.is_empty());
+    assert_eq!(&source[errors[0].locals[0].span.unwrap().range()], "flag");
+}
+
+#[test]
+fn errors_in_inlined_functions_point_at_definition_and_call() {
+    let source = r#"
+        ct_local f = fn {
+            input x;
+            output y;
+            y = x + TRUE;
+        };
+        local a = f(1);
+    "#;
+    let errors = compile_errors(source);
+    assert_eq!(errors.len(), 1);
+    assert_eq!(&source[errors[0].span.unwrap().range()], "x + TRUE");
+    let call_sites: Vec<_> = errors[0]
+        .call_sites
+        .iter()
+        .map(|span| &source[span.range()])
+        .collect();
+    assert_eq!(call_sites, vec!["f(1)"]);
+}
+
+fn int_array(elements: &[i32], dims: &[i32]) -> Value {
+    Value::ArrayLiteral {
+        elements: elements
+            .iter()
+            .map(|&x| ValuePtr::new(Value::IntLiteral(x)))
+            .collect(),
+        dims: dims
+            .iter()
+            .map(|&x| ValuePtr::new(Value::IntLiteral(x)))
+            .collect(),
+    }
+}
+
+#[test]
+fn array_literals() {
+    let blocks = simplify_source(
+        r#"
+        local a = [1, 2, 3];
+        local b: Array(Int, 2, 3) = [[1, 2], [3, 4], [5, 6]];
+        local c = [a, [4, 5, 6]];
+        local d: Array(Float, 3) = [1, TRUE, 3];
+    "#,
+    );
+    assert_eq!(find_value(&blocks, "a"), int_array(&[1, 2, 3], &[3]));
+    assert_eq!(
+        find_value(&blocks, "b"),
+        int_array(&[1, 2, 3, 4, 5, 6], &[2, 3])
+    );
+    assert_eq!(
+        find_value(&blocks, "c"),
+        int_array(&[1, 2, 3, 4, 5, 6], &[3, 2])
+    );
+    let c_type = ValuePtr::new(find_value(&blocks, "c")).typee();
+    assert_eq!(
+        c_type,
+        Value::BuiltinType(BuiltinType::Array {
+            eltype: ValuePtr::new(Value::BuiltinType(BuiltinType::Int)),
+            dims: vec![
+                ValuePtr::new(Value::IntLiteral(3)),
+                ValuePtr::new(Value::IntLiteral(2))
+            ],
+        })
+    );
+}
+
+#[test]
+fn ragged_array_literals_are_reported() {
+    let err = parse_error("local a = [[1, 2], [3]];");
+    assert_eq!(err.found, "a ragged array literal");
+    assert_eq!(err.span.range(), 10..23);
+
+    let err = parse_error("local a = [[1, 2], 3];");
+    assert_eq!(err.found, "a ragged array literal");
+
+    let errors = compile_errors("local a = [1, 2]; local b = [a, [1, 2, 3]];");
+    assert_eq!(errors[0].kind, CompileErrorKind::RaggedArrayLiteral);
+
+    let err = parse_error("local a = [];");
+    assert_eq!(err.expected, "an array element");
+}
+
+#[test]
+fn element_reads_are_folded() {
+    let blocks = simplify_source(
+        r#"
+        local grid = [[1, 2, 3], [4, 5, 6]];
+        local row = grid(1);
+        local element = grid(1, 2);
+        local from_row = row(0);
+        local i = 1;
+        local computed = grid(i - 1, i + 1);
+    "#,
+    );
+    assert_eq!(find_value(&blocks, "row"), int_array(&[4, 5, 6], &[3]));
+    assert_eq!(find_value(&blocks, "element"), Value::IntLiteral(6));
+    assert_eq!(find_value(&blocks, "from_row"), Value::IntLiteral(4));
+    assert_eq!(find_value(&blocks, "computed"), Value::IntLiteral(3));
+}
+
+#[test]
+fn invalid_element_reads_are_reported() {
+    let kinds = |source| {
+        compile_errors(source)
+            .into_iter()
+            .map(|error| error.kind)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        kinds("local a = [1, 2]; local b = a(2);"),
+        vec![CompileErrorKind::IndexOutOfBounds { index: 2, size: 2 }]
+    );
+    assert_eq!(
+        kinds("local a = [1, 2]; local b = a(0, 0);"),
+        vec![CompileErrorKind::TooManyIndices {
+            expected: 1,
+            found: 2
+        }]
+    );
+    assert_eq!(
+        kinds("input x: Float; local a = [1, 2]; local b = a(x);"),
+        vec![CompileErrorKind::InvalidIndex]
+    );
+}
+
+#[test]
+fn element_reads_are_lowered_to_components() {
+    let blocks = simplify_source(
+        r#"
+        ct_local main = fn {
+            input x: Array(Int, 8, 3);
+            output row: Array(Int, 8);
+            output element: Int;
+            row = x(1);
+            element = x(2, 5);
+        };
+    "#,
+    );
+    let program = solidify(ValuePtr::new(find_value(&blocks, "main")));
+    let row = &program.outputs()[0];
+    assert_eq!(row.typee().dims, vec![8]);
+    assert!(matches!(
+        &*row.components()[0],
+        ConcreteValue::InputVector {
+            input: 0,
+            position: 8
+        }
+    ));
+    let element = &program.outputs()[1];
+    assert!(element.typee().dims.is_empty());
+    assert!(matches!(
+        &*element.components()[0],
+        ConcreteValue::Unvectorize(vector, 5)
+            if matches!(&**vector, ConcreteValue::InputVector { position: 16, .. })
+    ));
+}
+
+const WAVETABLE: &str = r#"
+    ct_local main = fn {
+        input table: Array(Float, 16);
+        input phase: Int;
+        input phases: Array(Int, 8);
+        output sample: Float;
+        output samples: Array(Float, 8);
+        sample = table(phase);
+        samples = table(phases);
+    };
+"#;
+
+#[test]
+fn runtime_indices_are_lowered_to_loads() {
+    let blocks = simplify_source(WAVETABLE);
+    let program = solidify(ValuePtr::new(find_value(&blocks, "main")));
+
+    let sample = &program.outputs()[0];
+    assert!(sample.typee().dims.is_empty());
+    match &*sample.components()[0] {
+        ConcreteValue::DynamicLoad { index, .. } => match &**index {
+            ConcreteValue::BinaryOp(_, bounded, _) => assert!(matches!(
+                &**bounded,
+                ConcreteValue::BoundIndex(_, 16, OutOfBoundsPolicy::Clamp)
+            )),
+            other => panic!("Unexpected index {:?}", other),
+        },
+        other => panic!("Expected a dynamic load, got {:?}", other),
+    }
+
+    let samples = &program.outputs()[1];
+    assert_eq!(samples.typee().dims, vec![8]);
+    assert_eq!(samples.components().len(), 1);
+    assert!(matches!(
+        &*samples.components()[0],
+        ConcreteValue::Gather { .. }
+    ));
+}
+
+#[test]
+fn out_of_bounds_policy_is_configurable() {
+    let blocks = simplify_source(WAVETABLE);
+    let mut ctx = SolidificationContext::new();
+    ctx.out_of_bounds_policy = OutOfBoundsPolicy::Wrap;
+    let program = solidify_with(ValuePtr::new(find_value(&blocks, "main")), ctx);
+    let gather_indices = match &*program.outputs()[1].components()[0] {
+        ConcreteValue::Gather { indices, .. } => indices.ptr_clone(),
+        other => panic!("Expected a gather, got {:?}", other),
+    };
+    assert!(matches!(
+        &*gather_indices,
+        ConcreteValue::BinaryOp(_, bounded, _)
+            if matches!(&**bounded, ConcreteValue::BoundIndex(_, 16, OutOfBoundsPolicy::Wrap))
+    ));
+}
+
+#[test]
+fn index_arrays_gather_sub_arrays() {
+    let blocks = simplify_source(
+        r#"
+        input grid: Array(Int, 3, 4);
+        input rows: Array(Int, 2);
+        local picked = grid(rows);
+    "#,
+    );
+    let picked = ValuePtr::new(find_value(&blocks, "picked"));
+    assert_eq!(
+        picked.typee(),
+        Value::BuiltinType(BuiltinType::Array {
+            eltype: ValuePtr::new(Value::BuiltinType(BuiltinType::Int)),
+            dims: vec![
+                ValuePtr::new(Value::IntLiteral(3)),
+                ValuePtr::new(Value::IntLiteral(2))
+            ],
+        })
+    );
+}
+
+#[test]
+fn indexed_assignments_update_elements() {
+    let blocks = simplify_source(
+        r#"
+        local a: Array(Int, 4);
+        a(1) = 5;
+        a(3) = a(1) + 2;
+        local b = [[1, 2], [3, 4]];
+        b(1, 0) = 7;
+        local c: Array(Int, 16);
+        c(8) 8wide = 1;
+        c(0) 8wide = [1, 2, 3, 4, 5, 6, 7, 8];
+        c(3) = 0;
+    "#,
+    );
+    assert_eq!(find_value(&blocks, "a"), int_array(&[0, 5, 0, 7], &[4]));
+    assert_eq!(find_value(&blocks, "b"), int_array(&[1, 2, 7, 4], &[2, 2]));
+    assert_eq!(
+        find_value(&blocks, "c"),
+        int_array(&[1, 2, 3, 0, 5, 6, 7, 8, 1, 1, 1, 1, 1, 1, 1, 1], &[16])
+    );
+}
+
+#[test]
+fn invalid_indexed_assignments_are_reported() {
+    let kinds = |source| {
+        compile_errors(source)
+            .into_iter()
+            .map(|error| error.kind)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        kinds("input i: Int; local a: Array(Int, 4); a(i) = 1;"),
+        vec![CompileErrorKind::DynamicIndexedAssignment]
+    );
+    assert_eq!(
+        kinds("local a: Array(Int, 8); a(1) 8wide = 1;"),
+        vec![CompileErrorKind::IndexOutOfBounds { index: 8, size: 8 }]
+    );
+    assert_eq!(
+        kinds("local a: Array(Int, 4); a(0, 1) = 1;"),
+        vec![CompileErrorKind::TooManyIndices {
+            expected: 1,
+            found: 2
+        }]
+    );
+    assert_eq!(
+        kinds("local a: Array(Int, 4); a(0) = [1, 2];"),
+        vec![CompileErrorKind::InvalidAssignment]
+    );
+}
+
+#[test]
+fn indexed_assignments_can_have_any_width() {
+    let blocks = simplify_source(
+        r#"
+        local a: Array(Int, 20);
+        a(0) 4wide = [1, 2, 3, 4];
+        a(4) 16wide = 5;
+        a(19) 1wide = 6;
+    "#,
+    );
+    let mut expected = vec![1, 2, 3, 4];
+    expected.extend(vec![5; 15]);
+    expected.push(6);
+    assert_eq!(find_value(&blocks, "a"), int_array(&expected, &[20]));
+
+    let errors = compile_errors("local a: Array(Int, 8); a(0) 4wide = [1, 2, 3, 4, 5, 6, 7, 8];");
+    assert_eq!(errors[0].kind, CompileErrorKind::InvalidAssignment);
+
+    let err = parse_error("local a: Array(Int, 8);\na(0) 0wide = 1;");
+    assert_eq!((err.line, err.column), (2, 6));
+    assert_eq!(err.expected, "a width of at least 1");
+    assert_eq!(err.found, "`0wide`");
+}
+
+#[test]
+fn outputs_are_built_element_by_element() {
+    let (_scope, statements) = parse_root(
+        r#"
+        ct_local main = fn {
+            input x: Int;
+            output d: Array(Int, 10);
+            d(9) = x;
+            d(0) 8wide = x;
+            d(2) = 3;
+        };
+    "#,
+    )
+    .unwrap();
+    // Solidify the function as written so that the indexed assignments are
+    // not replaced by the simplified array.
+    let function = match &statements[..] {
+        [.., Statement::Assignment { base, .. }] => base.ptr_clone(),
+        _ => unreachable!(),
+    };
+    let program = solidify(function);
+    let output = &program.outputs()[0];
+    assert_eq!(output.typee().dims, vec![10]);
+    let scalar = |position| output.get_scalar(position);
+    assert!(matches!(&*scalar(0), ConcreteValue::InputScalar { .. }));
+    assert!(matches!(&*scalar(2), ConcreteValue::IntLiteral(3)));
+    assert!(matches!(&*scalar(7), ConcreteValue::InputScalar { .. }));
+    assert!(matches!(&*scalar(8), ConcreteValue::IntLiteral(0)));
+    assert!(matches!(&*scalar(9), ConcreteValue::InputScalar { .. }));
+
+    let blocks = simplify_source(
+        r#"
+        ct_local main = fn {
+            input x: Int;
+            output d: Array(Int, 16);
+            d(8) 8wide = [1, 2, 3, 4, 5, 6, 7, 8];
+            d(0) = x;
+        };
+    "#,
+    );
+    let program = solidify(ValuePtr::new(find_value(&blocks, "main")));
+    let output = &program.outputs()[0];
+    assert_eq!(output.typee().dims, vec![16]);
+    assert!(matches!(
+        &*output.get_scalar(0),
+        ConcreteValue::InputScalar { .. }
+    ));
+    assert!(output.get_vector(8).is_some());
+}
+
+#[test]
+fn comments_are_skipped() {
+    let blocks = simplify_source(
+        r#"
+        # A line comment.
+        local a = 1 + # between operands
+            2;
+        #[ A block comment #[ which nests ]# and
+           spans lines. ]#
+        local b = #[ inline ]# a * 3;
+    "#,
+    );
+    assert_eq!(find_value(&blocks, "a"), Value::IntLiteral(3));
+    assert_eq!(find_value(&blocks, "b"), Value::IntLiteral(9));
+
+    let error = parse_error("local a = 1; #[ never #[ closed ]#");
+    assert_eq!(error.expected, "`]#` to close this comment");
+    assert_eq!((error.line, error.column), (1, 14));
+
+    let source = "input flag: Bool;\nlocal a = 2 * (flag + 1) # trailing\n;";
+    let errors = compile_errors(source);
+    assert_eq!(&source[errors[0].span.unwrap().range()], "(flag + 1)");
+}
+
+#[test]
+fn doc_comments_are_attached_to_declarations() {
+    let blocks = simplify_source(
+        r#"
+        ## Doubles its input.
+        ##
+        ## Works on any type that can be added.
+        ct_local double = fn {
+            ## The value to double.
+            input x;
+            # Not a doc comment.
+            output y;
+            y = x + x;
+        };
+        ## Documentation for an assignment is dropped.
+        double = double;
+    "#,
+    );
+    let (local, function) = blocks[0]
+        .iter()
+        .find(|(local, _)| local.name == "double")
+        .unwrap();
+    assert_eq!(
+        local.doc.as_deref(),
+        Some("Doubles its input.\n\nWorks on any type that can be added.")
+    );
+    match &*function.borrow() {
+        Value::Function {
+            inputs, outputs, ..
+        } => {
+            assert_eq!(inputs[0].doc.as_deref(), Some("The value to double."));
+            assert_eq!(outputs[0].doc, None);
+        }
+        other => panic!("Expected a function, got {:?}", other),
+    };
+}
+
+#[test]
+fn number_literals() {
+    let blocks = simplify_source(
+        r#"
+        local a = 12_03;
+        local b = 0xFF_FF + 0b1101_0100 + 0o107;
+        local c = 0123;
+        local d = .124;
+        local e = 14.0e+3;
+        local f = 9_8.76e-1_2;
+        local g = 1.;
+        local h = 1-2;
+        local i = -2147483648;
+        local j = -3 ** 2;
+        local k = 2 ** -1.0;
+        local l = - a;
+    "#,
+    );
+    assert_eq!(find_value(&blocks, "a"), Value::IntLiteral(1203));
+    assert_eq!(
+        find_value(&blocks, "b"),
+        Value::IntLiteral(65535 + 212 + 71)
+    );
+    assert_eq!(find_value(&blocks, "c"), Value::IntLiteral(83));
+    assert_eq!(find_value(&blocks, "d"), Value::FloatLiteral(0.124));
+    assert_eq!(find_value(&blocks, "e"), Value::FloatLiteral(14000.0));
+    assert_eq!(find_value(&blocks, "f"), Value::FloatLiteral(98.76e-12));
+    assert_eq!(find_value(&blocks, "g"), Value::FloatLiteral(1.0));
+    assert_eq!(find_value(&blocks, "h"), Value::IntLiteral(-1));
+    assert_eq!(find_value(&blocks, "i"), Value::IntLiteral(i32::MIN));
+    assert_eq!(find_value(&blocks, "j"), Value::IntLiteral(-9));
+    assert_eq!(find_value(&blocks, "k"), Value::FloatLiteral(0.5));
+    assert_eq!(find_value(&blocks, "l"), Value::IntLiteral(-1203));
+}
+
+#[test]
+fn invalid_number_literals_are_reported() {
+    let error = parse_error("local a = 99999999999;");
+    assert_eq!(error.expected, "an integer from -2147483648 to 2147483647");
+    assert_eq!(error.found, "`99999999999`");
+    assert_eq!(parse_error("local a = 2147483648;").found, "`2147483648`");
+    assert_eq!(parse_error("local a = -2147483649;").found, "`-2147483649`");
+    assert_eq!(
+        parse_error("local a = 1e;").expected,
+        "the digits of an exponent"
+    );
+    assert_eq!(
+        parse_error("local a = 1e39;").expected,
+        "a float small enough to fit in 32 bits"
+    );
+    assert_eq!(parse_error("local a = 0x;").expected, "a hexadecimal digit");
+    assert_eq!(parse_error("local a = 0b102;").expected, "a binary digit");
+    assert_eq!(parse_error("local a = 09;").expected, "an octal digit");
+    assert_eq!(parse_error("local a = 12ab;").found, "`ab`");
+}
+
+#[test]
+fn unary_operators() {
+    let blocks = simplify_source(
+        r#"
+        local a = 3;
+        local b = -a * 2;
+        local c = -(a + 0.5);
+        local d = not 1 == 2;
+        local e = not 5;
+        local f = neg(a);
+        ct_local g = typeof(-[1.0, 2.0]);
+    "#,
+    );
+    assert_eq!(find_value(&blocks, "b"), Value::IntLiteral(-6));
+    assert_eq!(find_value(&blocks, "c"), Value::FloatLiteral(-3.5));
+    assert_eq!(find_value(&blocks, "d"), Value::BoolLiteral(true));
+    assert_eq!(find_value(&blocks, "e"), Value::IntLiteral(!5));
+    assert_eq!(find_value(&blocks, "f"), Value::IntLiteral(-3));
+    assert_eq!(
+        find_value(&blocks, "g"),
+        Value::BuiltinType(BuiltinType::Array {
+            eltype: ValuePtr::new(Value::BuiltinType(BuiltinType::Float)),
+            dims: vec![ValuePtr::new(Value::IntLiteral(2))],
+        })
+    );
+
+    let kinds = |source| {
+        compile_errors(source)
+            .into_iter()
+            .map(|error| error.kind)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        kinds("local a = -TRUE;"),
+        vec![CompileErrorKind::InvalidOperation]
+    );
+    assert_eq!(
+        kinds("input x: Float; local a = not x;"),
+        vec![CompileErrorKind::InvalidOperation]
+    );
+    assert_eq!(
+        kinds("local a = not [1.0, 2.0];"),
+        vec![CompileErrorKind::InvalidOperation]
+    );
+    assert_eq!(
+        kinds("local a = neg(1, 2);"),
+        vec![CompileErrorKind::WrongArgumentCount {
+            expected: 1,
+            found: 2
+        }]
+    );
+    assert_eq!(
+        kinds("local a = add(1);"),
+        vec![CompileErrorKind::WrongArgumentCount {
+            expected: 2,
+            found: 1
+        }]
+    );
+}
+
+#[test]
+fn operator_types() {
+    let blocks = simplify_source(
+        r#"
+        input x: Int;
+        ct_local a = typeof(typeof(x));
+    "#,
+    );
+    assert_eq!(
+        find_value(&blocks, "a"),
+        Value::BuiltinType(BuiltinType::Type)
+    );
+
+    // Type arithmetic on dims builds calls to max, which never appear in
+    // source code.
+    let max = ValuePtr::new(Value::FunctionCall(
+        ValuePtr::new(Value::BuiltinOp(BuiltinOp::Max)),
+        vec![
+            ValuePtr::new(Value::IntLiteral(1)),
+            ValuePtr::new(Value::FloatLiteral(2.0)),
+        ],
+        0,
+    ));
+    let typee = ValuePtr::new(max.typee());
+    typee.check_and_simplify(&mut SimplificationContext::new());
+    assert_eq!(*typee.borrow(), Value::BuiltinType(BuiltinType::Float));
+
+    let any = || ValuePtr::new(Value::BuiltinType(BuiltinType::Any));
+    assert_eq!(
+        ValuePtr::new(Value::BuiltinOp(BuiltinOp::Max)).typee(),
+        Value::BuiltinType(BuiltinType::Function {
+            inputs: vec![any(), any()],
+            outputs: vec![any()],
+        })
+    );
+    assert_eq!(
+        ValuePtr::new(Value::BuiltinOp(BuiltinOp::Typeof)).typee(),
+        Value::BuiltinType(BuiltinType::Function {
+            inputs: vec![any()],
+            outputs: vec![ValuePtr::new(Value::BuiltinType(BuiltinType::Type))],
+        })
+    );
+}
+
+#[test]
+fn unary_operators_are_lowered() {
+    let blocks = simplify_source(
+        r#"
+        ct_local main = fn {
+            input x: Array(Float, 8);
+            input flag: Bool;
+            output y;
+            output z;
+            y = -x;
+            z = not flag;
+        };
+    "#,
+    );
+    let program = solidify(ValuePtr::new(find_value(&blocks, "main")));
+    let y = &program.outputs()[0];
+    assert_eq!(y.typee().dims, vec![8]);
+    assert!(matches!(
+        &*y.components()[0],
+        ConcreteValue::UnaryOp(UnaryOp::Neg, operand)
+            if matches!(&**operand, ConcreteValue::InputVector { .. })
+    ));
+    assert!(matches!(
+        &*program.outputs()[1].components()[0],
+        ConcreteValue::UnaryOp(UnaryOp::Not, _)
+    ));
+}
+
+#[test]
+fn conditionals() {
+    let blocks = simplify_source(
+        r#"
+        input flag: Bool;
+        local a = if 1 < 2 then 10 else 20;
+        local b = if FALSE then 1 else if TRUE then 2 else 3;
+        local c = if TRUE then 1 else TRUE + 1;
+        ct_local d = typeof(if flag then 1 else 2.0);
+        ct_local e = typeof(if [TRUE, FALSE] then 1 else [2, 3]);
+    "#,
+    );
+    assert_eq!(find_value(&blocks, "a"), Value::IntLiteral(10));
+    assert_eq!(find_value(&blocks, "b"), Value::IntLiteral(2));
+    // Only the branch which is taken is checked.
+    assert_eq!(find_value(&blocks, "c"), Value::IntLiteral(1));
+    assert_eq!(
+        find_value(&blocks, "d"),
+        Value::BuiltinType(BuiltinType::Float)
+    );
+    assert_eq!(
+        find_value(&blocks, "e"),
+        Value::BuiltinType(BuiltinType::Array {
+            eltype: ValuePtr::new(Value::BuiltinType(BuiltinType::Int)),
+            dims: vec![ValuePtr::new(Value::IntLiteral(2))],
+        })
+    );
+
+    let kinds = |source| {
+        compile_errors(source)
+            .into_iter()
+            .map(|error| error.kind)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        kinds("input x: Int; local a = if x then 1 else 2;"),
+        vec![CompileErrorKind::InvalidCondition]
+    );
+    assert_eq!(
+        kinds("input flag: Bool; local a = if flag then [1, 2] else [1, 2, 3];"),
+        vec![CompileErrorKind::IncompatibleBranches]
+    );
+    assert_eq!(
+        parse_error("local a = if TRUE 1 else 2;").expected,
+        "`then`"
+    );
+    assert_eq!(parse_error("local a = if TRUE then 1;").expected, "`else`");
+}
+
+#[test]
+fn conditionals_are_lowered_to_selects() {
+    let blocks = simplify_source(
+        r#"
+        ct_local main = fn {
+            input flags: Array(Bool, 8);
+            input x: Array(Int, 8);
+            output y;
+            output z;
+            y = if flags then x else 0.5;
+            z = if flags(0) then 1 else 2;
+        };
+    "#,
+    );
+    let program = solidify(ValuePtr::new(find_value(&blocks, "main")));
+    let y = &program.outputs()[0];
+    assert_eq!(y.typee().dims, vec![8]);
+    assert_eq!(y.components().len(), 1);
+    match &*y.components()[0] {
+        ConcreteValue::Select {
+            condition,
+            if_true,
+            if_false,
+        } => {
+            assert!(matches!(&**condition, ConcreteValue::InputVector { .. }));
+            assert!(matches!(
+                &**if_true,
+                ConcreteValue::UnaryOp(UnaryOp::IntToFloat, _)
+            ));
+            assert!(matches!(
+                &**if_false,
+                ConcreteValue::VectorizeByDuplication(..)
+            ));
+        }
+        other => panic!("Expected a select, got {:?}", other),
+    }
+    let z = &program.outputs()[1];
+    assert!(z.typee().dims.is_empty());
+    assert!(matches!(&*z.components()[0], ConcreteValue::Select { .. }));
+}
+
+#[test]
+fn repeat_loops_are_unrolled() {
+    let blocks = simplify_source(
+        r#"
+        local total = 0;
+        repeat(4) (i) {
+            total = total + i;
+        };
+        local sums: Array(Int, 3);
+        repeat(3) (i) {
+            # Starts over as all zeros in each iteration.
+            local scratch: Array(Int, 3);
+            scratch(i) = 1;
+            sums(i) = scratch(0) + scratch(1) + scratch(2);
+        };
+        local grid: Array(Int, 3, 2);
+        repeat(2) (row) {
+            repeat(3) (column) {
+                grid(row, column) = row * 10 + column;
+            };
+        };
+        local count = 0;
+        repeat(2 + 1) {
+            count = count + 1;
+        };
+    "#,
+    );
+    assert_eq!(find_value(&blocks, "total"), Value::IntLiteral(6));
+    assert_eq!(find_value(&blocks, "sums"), int_array(&[1, 1, 1], &[3]));
+    assert_eq!(
+        find_value(&blocks, "grid"),
+        int_array(&[0, 1, 2, 10, 11, 12], &[3, 2])
+    );
+    assert_eq!(find_value(&blocks, "count"), Value::IntLiteral(3));
+    assert_eq!(
+        parse_error("repeat(2) (i) { }; local x = i;").found,
+        "unknown identifier `i`"
+    );
+}
+
+#[test]
+fn invalid_repeat_loops_are_reported() {
+    let errors = compile_errors("input n: Int; repeat(n) { };");
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].kind, CompileErrorKind::DynamicRepeatCount);
+    assert_eq!(errors[0].locals[0].name, "n");
+
+    // Errors in the body are only reported once.
+    let errors = compile_errors("repeat(3) { local a = TRUE + 1; };");
+    assert_eq!(errors.len(), 1);
+
+    let source = "local n = 2; repeat(n - 5) (i) { };";
+    let errors = compile_errors(source);
+    assert_eq!(errors.len(), 1);
+    assert_eq!(
+        errors[0].kind,
+        CompileErrorKind::NegativeRepeatCount { count: -3 }
+    );
+    assert_eq!(&source[errors[0].span.unwrap().range()], "n - 5");
+
+    let errors = compile_errors("repeat(1000000000) { };");
+    assert_eq!(
+        errors[0].kind,
+        CompileErrorKind::UnrollLimitExceeded {
+            limit: DEFAULT_UNROLL_LIMIT,
+            iterations: 1000000000
+        }
+    );
+
+    let (_scope, statements) = parse_root("repeat(4) { repeat(3) { }; };").unwrap();
+    let mut ctx = SimplificationContext::new();
+    ctx.unroll_limit = 10;
+    for statement in statements {
+        statement.check_and_simplify(&mut ctx);
+    }
+    let errors = ctx.finish().unwrap_err();
+    assert_eq!(
+        errors[0].kind,
+        CompileErrorKind::UnrollLimitExceeded {
+            limit: 10,
+            iterations: 12
+        }
+    );
+}
+
+const LOOPED: &str = r#"
+    ct_local main = fn {
+        input x: Array(Float, 1001);
+        input gain: Float;
+        output y;
+        output first;
+        local scaled: Array(Float, 1001) = x * gain;
+        y = -(scaled + 1.0);
+        first = scaled(3);
+    };
+"#;
+
+#[test]
+fn large_elementwise_operations_become_loops() {
+    let blocks = simplify_source(LOOPED);
+    let program = solidify(ValuePtr::new(find_value(&blocks, "main")));
+    let y = &program.outputs()[0];
+    assert_eq!(y.typee().dims, vec![1001]);
+    // One loop over the first 1000 elements, then the leftover element.
+    assert_eq!(y.components().len(), 2);
+    let body = match &*y.components()[0] {
+        ConcreteValue::Loop {
+            iterations: 125,
+            body,
+        } => body.ptr_clone(),
+        other => panic!("Expected a loop, got {:?}", other),
+    };
+    assert!(matches!(
+        &*body,
+        ConcreteValue::UnaryOp(UnaryOp::Neg, element)
+            if matches!(&**element, ConcreteValue::LoopElement { offset: 0, .. })
+    ));
+    assert!(y.components()[1].is_scalar());
+    assert!(matches!(
+        &*program.outputs()[1].components()[0],
+        ConcreteValue::Unvectorize(vector, 3)
+            if matches!(&**vector, ConcreteValue::Unloop(_, 0))
+    ));
+}
+
+#[test]
+fn lane_count_is_configurable() {
+    let blocks = simplify_source(LOOPED);
+    let main = ValuePtr::new(find_value(&blocks, "main"));
+    let mut ctx = SolidificationContext::new();
+    ctx.lanes = 4;
+    let program = solidify_with(main.ptr_clone(), ctx);
+    assert_eq!(program.lanes(), 4);
+    let y = &program.outputs()[0];
+    assert!(matches!(
+        &*y.components()[0],
+        ConcreteValue::Loop {
+            iterations: 250,
+            ..
+        }
+    ));
+    assert!(matches!(
+        &*program.outputs()[1].components()[0],
+        ConcreteValue::Unvectorize(vector, 3)
+            if matches!(&**vector, ConcreteValue::Unloop(_, 0))
+    ));
+
+    // With one lane, every value is a scalar.
+    let mut ctx = SolidificationContext::new();
+    ctx.lanes = 1;
+    let program = solidify_with(main, ctx);
+    let y = &program.outputs()[0];
+    assert_eq!(y.components().len(), 1001);
+    assert!(y.components().iter().all(|component| component.is_scalar()));
+}
+
+#[test]
+fn loop_threshold_is_configurable() {
+    let blocks = simplify_source(LOOPED);
+    let mut ctx = SolidificationContext::new();
+    ctx.loop_threshold = usize::MAX;
+    let program = solidify_with(ValuePtr::new(find_value(&blocks, "main")), ctx);
+    let y = &program.outputs()[0];
+    assert_eq!(y.components().len(), 126);
+    assert!(y
+        .components()
+        .iter()
+        .all(|component| !matches!(&**component, ConcreteValue::Loop { .. })));
+}
+
+#[test]
+fn template_parameters() {
+    let blocks = simplify_source(
+        r#"
+        ct_local combine = fn {
+            input a: T?;
+            input b: T?;
+            output c: T;
+            local sum: T = a + b;
+            c = sum;
+        };
+        ct_local element_type = fn {
+            input a: Array(T?, 2);
+            ct_output t;
+            t = T;
+        };
+        local ints = combine(1, 2);
+        local floats = combine(1, 2.5);
+        ct_local broadcast = typeof(combine([1, 2], 0.5));
+        ct_local kind = element_type([1.5, 2.5]);
+    "#,
+    );
+    assert_eq!(find_value(&blocks, "ints"), Value::IntLiteral(3));
+    assert_eq!(find_value(&blocks, "floats"), Value::FloatLiteral(3.5));
+    assert!(matches!(
+        find_value(&blocks, "broadcast"),
+        Value::BuiltinType(BuiltinType::Array { eltype, .. })
+            if *eltype.borrow() == Value::BuiltinType(BuiltinType::Float)
+    ));
+    assert_eq!(
+        find_value(&blocks, "kind"),
+        Value::BuiltinType(BuiltinType::Float)
+    );
+}
+
+#[test]
+fn conflicting_template_arguments_are_reported() {
+    let errors = compile_errors(
+        r#"
+        ct_local combine = fn {
+            input a: T?;
+            input b: T?;
+            output c: T;
+            c = a + b;
+        };
+        local sum = combine([1, 2], [1, 2, 3]);
+    "#,
+    );
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].kind, CompileErrorKind::TemplateConflict);
+    assert_eq!(errors[0].types.len(), 2);
+    assert_eq!(errors[0].locals[0].name, "T");
+
+    let error = parse_root("local T = 1;\nlocal f = fn { input a: T?; };").unwrap_err();
+    assert_eq!(error.expected, "a new name for a template parameter");
+}
+
+#[test]
+fn size_template_parameters() {
+    let blocks = simplify_source(
+        r#"
+        ct_local summary = fn {
+            input data: Array(T?, SIZE?);
+            input offsets: Array(Int, SIZE + 1);
+            output length;
+            output last: T;
+            length = SIZE;
+            last = data(SIZE - 1);
+        };
+        local length, local last = summary([1.5, 2.5, 3.5], [0, 1, 2, 3]);
+        local rows, local last_row = summary([[1, 2], [3, 4]], [0, 1, 2]);
+    "#,
+    );
+    assert_eq!(find_value(&blocks, "length"), Value::IntLiteral(3));
+    assert_eq!(find_value(&blocks, "last"), Value::FloatLiteral(3.5));
+    assert_eq!(find_value(&blocks, "rows"), Value::IntLiteral(2));
+    assert_eq!(find_value(&blocks, "last_row"), int_array(&[3, 4], &[2]));
+}
+
+#[test]
+fn conflicting_sizes_are_reported() {
+    let source = r#"
+        ct_local pair = fn {
+            input a: Array(Float, SIZE?);
+            input b: Array(Float, SIZE?);
+            output size;
+            size = SIZE;
+        };
+        local size = pair([1.0, 2.0], [1.0, 2.0, 3.0]);
+    "#;
+    let errors = compile_errors(source);
+    assert_eq!(errors.len(), 1);
+    assert_eq!(
+        errors[0].kind,
+        CompileErrorKind::TemplateSizeConflict { sizes: vec![2, 3] }
+    );
+    assert_eq!(
+        errors[0].to_string(),
+        "arguments have conflicting sizes 2, 3 (`SIZE`)"
+    );
+
+    let errors = compile_errors(
+        r#"
+        ct_local summary = fn {
+            input data: Array(Float, SIZE?);
+            input offsets: Array(Int, SIZE + 1);
+            output length;
+            length = SIZE;
+        };
+        local length = summary([1.0, 2.0], [0, 1]);
+    "#,
+    );
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].kind, CompileErrorKind::InvalidArgument);
+    assert_eq!(errors[0].locals[0].name, "offsets");
+}
+
+fn locals_used_by(value: &Value) -> Vec<String> {
+    let mut names = Vec::new();
+    value.visit_locals(&mut |local| names.push(local.name.clone()));
+    names
+}
+
+#[test]
+fn calls_with_the_same_signature_reuse_simplified_bodies() {
+    let (_scope, statements) = parse_root(
+        r#"
+        input a: Float;
+        input b: Float;
+        ct_local scale = fn {
+            input x: Float;
+            input factor: Float;
+            output y: Float;
+            y = x * factor + 1.0;
+        };
+        local first = scale(a, 2.0);
+        local second = scale(b, 2.0);
+        local tripled = scale(a, 3.0);
+        local known = scale(1.0, 2.0);
+        local known_again = scale(1.0, 2.0);
+        local elements = [a, b];
+        ct_local picked = fn {
+            input values: Array(Float, 2);
+            output y: Float;
+            y = values(1) * 2.0;
+        };
+        local from_literal = picked(elements);
+    "#,
+    )
+    .unwrap();
+    let mut ctx = SimplificationContext::new();
+    for statement in statements {
+        statement.check_and_simplify(&mut ctx);
+    }
+    let stats = ctx.call_cache_stats();
+    assert_eq!((stats.hits, stats.misses), (2, 4));
+    let blocks = ctx.finish().unwrap();
+    assert_eq!(locals_used_by(&find_value(&blocks, "first")), vec!["a"]);
+    assert_eq!(locals_used_by(&find_value(&blocks, "second")), vec!["b"]);
+    assert_ne!(find_value(&blocks, "first"), find_value(&blocks, "tripled"));
+    assert_eq!(find_value(&blocks, "known"), Value::FloatLiteral(3.0));
+    assert_eq!(find_value(&blocks, "known_again"), Value::FloatLiteral(3.0));
+    // The element is read from the argument once it replaces the placeholder.
+    assert_eq!(
+        locals_used_by(&find_value(&blocks, "from_literal")),
+        vec!["b"]
+    );
+}
+
+#[test]
+fn cached_calls_still_report_errors() {
+    let errors = compile_errors(
+        r#"
+        input a: Array(Int, 2);
+        ct_local last = fn {
+            input values: Array(Int, SIZE?);
+            output y: Int;
+            y = values(2);
+        };
+        local first = last(a);
+        local second = last(a);
+    "#,
+    );
+    assert_eq!(errors.len(), 2);
+    assert!(errors
+        .iter()
+        .all(|error| error.kind == CompileErrorKind::IndexOutOfBounds { index: 2, size: 2 }));
+    assert_ne!(errors[0].call_sites, errors[1].call_sites);
+}
+
+#[test]
+fn each_inlined_call_gets_its_own_locals() {
+    let blocks = simplify_source(
+        r#"
+        input p: Int;
+        input q: Int;
+        ct_local adder = fn {
+            input n: Int;
+            ct_output f;
+            f = fn {
+                input x: Int;
+                output y: Int;
+                y = x + n;
+            };
+        };
+        ct_local add_p = adder(p);
+        ct_local add_q = adder(q);
+        local from_p = add_p(1);
+        local from_q = add_q(1);
+    "#,
+    );
+    let input_of = |name| match find_value(&blocks, name) {
+        Value::Function { inputs, .. } => inputs[0].ptr_clone(),
+        other => panic!("Expected a function, found {:?}", other),
+    };
+    let (p_input, q_input) = (input_of("add_p"), input_of("add_q"));
+    assert_ne!(p_input, q_input);
+    assert_eq!((p_input.name.as_str(), q_input.name.as_str()), ("x", "x"));
+    assert_eq!(locals_used_by(&find_value(&blocks, "from_p")), vec!["p"]);
+    assert_eq!(locals_used_by(&find_value(&blocks, "from_q")), vec!["q"]);
+}
+
+#[test]
+fn functions_can_be_inlined_inside_themselves() {
+    let blocks = simplify_source(
+        r#"
+        input k: Int;
+        ct_local apply = fn {
+            ct_input g;
+            input v;
+            output r;
+            local t = g(v);
+            r = t * 2;
+        };
+        ct_local nested = fn {
+            input x;
+            output y;
+            y = apply(fn {
+                input z;
+                output w;
+                w = z + 1;
+            }, x);
+        };
+        ct_local twice_nested = fn {
+            input x;
+            output y;
+            y = apply(nested, x);
+        };
+        local constant = apply(nested, 3);
+        local deeper = apply(twice_nested, 3);
+        local dynamic = apply(nested, k);
+    "#,
+    );
+    assert_eq!(find_value(&blocks, "constant"), Value::IntLiteral(16));
+    assert_eq!(find_value(&blocks, "deeper"), Value::IntLiteral(32));
+    // ((k + 1) * 2) * 2 refers to nothing but the input.
+    let dynamic = find_value(&blocks, "dynamic");
+    assert_eq!(locals_used_by(&dynamic), vec!["k"]);
+    assert!(matches!(
+        &dynamic,
+        Value::FunctionCall(_, args, _) if matches!(
+            &*args[0].borrow(),
+            Value::FunctionCall(_, inner, _) if matches!(
+                &*inner[0].borrow(),
+                Value::FunctionCall(..)
+            )
+        )
+    ));
+}
+
+#[test]
+fn recursive_calls_are_reported() {
+    let source = r#"
+        ct_local f = fn {
+            input x;
+            output y;
+            y = f(x);
+        };
+        local a = f(3);
+    "#;
+    let errors = compile_errors(source);
+    assert_eq!(errors.len(), 1);
+    assert_eq!(
+        errors[0].kind,
+        CompileErrorKind::RecursionLimitExceeded {
+            limit: DEFAULT_RECURSION_LIMIT
+        }
+    );
+    assert_eq!(&source[errors[0].span.unwrap().range()], "f(x)");
+    assert_eq!(errors[0].call_sites.len(), DEFAULT_RECURSION_LIMIT);
+
+    let (_scope, statements) = parse_root(source).unwrap();
+    let mut ctx = SimplificationContext::new();
+    ctx.recursion_limit = 3;
+    for statement in statements {
+        statement.check_and_simplify(&mut ctx);
+    }
+    let errors = ctx.finish().unwrap_err();
+    assert_eq!(
+        errors[0].kind,
+        CompileErrorKind::RecursionLimitExceeded { limit: 3 }
+    );
+
+    // Recursion which stops before the limit is fine.
+    let blocks = simplify_source(
+        r#"
+        ct_local count = fn {
+            ct_input n: Int;
+            output y;
+            y = if n > 0 then count(n - 1) + 1 else 0;
+        };
+        local a = count(10);
+    "#,
+    );
+    assert_eq!(find_value(&blocks, "a"), Value::IntLiteral(10));
+}
+
+#[test]
+fn compile_time_locals_are_enforced() {
+    let errors = compile_errors(
+        r#"
+        input x: Int;
+        ct_local known = 2 + 3;
+        ct_local unknown = x + 1;
+        local kind = Int;
+        ct_local scale = fn {
+            ct_input factor: Int;
+            input value: Int;
+            output y: Int;
+            ct_local doubled = value * 2;
+            y = value * factor;
+        };
+        local fine = scale(known, 1);
+        local runtime = scale(known, x);
+        local bad = scale(x, 1);
+    "#,
+    );
+    let summary = errors
+        .iter()
+        .map(|error| (error.kind.clone(), error.locals[0].name.as_str()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        summary,
+        vec![
+            (CompileErrorKind::CompileTimeOnlyValue, "kind"),
+            (CompileErrorKind::UnknownCompileTimeValue, "doubled"),
+            (CompileErrorKind::UnknownCompileTimeValue, "factor"),
+            (CompileErrorKind::UnknownCompileTimeValue, "unknown"),
+        ]
+    );
+    assert_eq!(errors[1].call_sites.len(), 1);
+    assert_eq!(
+        errors[3].to_string(),
+        "value must be known at compile time (`unknown`)"
+    );
+}
+
+#[test]
+fn locals_holding_types_can_be_used_as_annotations() {
+    let blocks = simplify_source(
+        r#"
+        ct_local make = fn {
+            ct_input N: Int;
+            ct_output Buffer;
+            Buffer = Array(Int, N);
+        };
+        ct_local Buffer = make(3);
+        input buf: Buffer;
+        input direct: make(2);
+        ct_local Scalar = Int;
+        input s: Scalar;
+        Scalar = Float;
+        input f: Scalar;
+        ct_local doubled = fn {
+            ct_input N: Int;
+            ct_local Row = make(N);
+            input data: Row;
+            output y: Row;
+            local twice: Row = data * 2;
+            y = twice;
+        };
+        ct_local identity = fn {
+            ct_input Kind;
+            input value: Kind;
+            output y: Kind;
+            y = value;
+        };
+        local twice = doubled(3, buf);
+        local same = identity(Buffer, buf);
+        local first = buf(0);
+        ct_local buf_type = typeof(buf);
+        ct_local direct_type = typeof(direct);
+        ct_local s_type = typeof(s);
+        ct_local f_type = typeof(f);
+    "#,
+    );
+    let int_array = |size| {
+        Value::BuiltinType(BuiltinType::Array {
+            eltype: ValuePtr::new(Value::BuiltinType(BuiltinType::Int)),
+            dims: vec![ValuePtr::new(Value::IntLiteral(size))],
+        })
+    };
+    assert_eq!(find_value(&blocks, "buf_type"), int_array(3));
+    assert_eq!(find_value(&blocks, "direct_type"), int_array(2));
+    assert_eq!(
+        find_value(&blocks, "s_type"),
+        Value::BuiltinType(BuiltinType::Int)
+    );
+    assert_eq!(
+        find_value(&blocks, "f_type"),
+        Value::BuiltinType(BuiltinType::Float)
+    );
+    assert!(matches!(
+        find_value(&blocks, "first"),
+        Value::ElementRead { .. }
+    ));
+    assert_eq!(locals_used_by(&find_value(&blocks, "twice")), vec!["buf"]);
+    assert_eq!(locals_used_by(&find_value(&blocks, "same")), vec!["buf"]);
+
+    let errors = compile_errors(
+        r#"
+        ct_local Buffer = Array(Int, 3);
+        input buf: Buffer;
+        local wrong: Buffer = [1, 2];
+        ct_local identity = fn {
+            ct_input Kind;
+            input value: Kind;
+            output y: Kind;
+            y = value;
+        };
+        local passed = identity(Int, buf);
+    "#,
+    );
+    assert_eq!(errors[0].kind, CompileErrorKind::InvalidAssignment);
+    assert_eq!(errors[0].locals[0].name, "wrong");
+    // Assigning the value to the output inside the call fails as well.
+    assert_eq!(errors[1].kind, CompileErrorKind::InvalidArgument);
+    assert_eq!(
+        *errors[1].types[1].borrow(),
+        Value::BuiltinType(BuiltinType::Int)
+    );
+}
+
+fn floats(values: impl IntoIterator<Item = f32>) -> Vec<Scalar> {
+    values.into_iter().map(Scalar::Float).collect()
+}
+
+fn ints(values: impl IntoIterator<Item = i32>) -> Vec<Scalar> {
+    values.into_iter().map(Scalar::Int).collect()
+}
+
+#[test]
+fn looped_programs_can_be_executed() {
+    let x = floats((0..1001).map(|i| i as f32));
+    let gain = floats(vec![2.0]);
+    let expected_y = floats((0..1001).map(|i| -(i as f32 * 2.0 + 1.0)));
+
+    let blocks = simplify_source(LOOPED);
+    let program = solidify(ValuePtr::new(find_value(&blocks, "main")));
+    let outputs = execute(&program, &[&x, &gain]);
+    assert_eq!(outputs, vec![expected_y.clone(), floats(vec![6.0])]);
+
+    // Unrolling the loops doesn't change the results.
+    let mut ctx = SolidificationContext::new();
+    ctx.loop_threshold = usize::MAX;
+    let program = solidify_with(ValuePtr::new(find_value(&blocks, "main")), ctx);
+    assert_eq!(execute(&program, &[&x, &gain]), outputs);
+}
+
+#[test]
+fn runtime_indices_can_be_executed() {
+    let table = floats((0..16).map(|i| i as f32 * 0.5));
+    let phases = ints(vec![-1, 0, 3, 15, 16, 17, 33, 7]);
+    let blocks = simplify_source(WAVETABLE);
+
+    let program = solidify(ValuePtr::new(find_value(&blocks, "main")));
+    let outputs = execute(&program, &[&table, &ints(vec![20]), &phases]);
+    assert_eq!(outputs[0], floats(vec![7.5]));
+    assert_eq!(
+        outputs[1],
+        floats(vec![0.0, 0.0, 1.5, 7.5, 7.5, 7.5, 7.5, 3.5])
+    );
+
+    let mut ctx = SolidificationContext::new();
+    ctx.out_of_bounds_policy = OutOfBoundsPolicy::Wrap;
+    let program = solidify_with(ValuePtr::new(find_value(&blocks, "main")), ctx);
+    let outputs = execute(&program, &[&table, &ints(vec![20]), &phases]);
+    assert_eq!(outputs[0], floats(vec![2.0]));
+    assert_eq!(
+        outputs[1],
+        floats(vec![7.5, 0.0, 1.5, 7.5, 0.0, 0.5, 0.5, 3.5])
+    );
+}
+
+/// Solidifies `main` once for each lane count, with `policy` for runtime
+/// indices.
+fn solidify_for_lane_counts(
+    source: &str,
+    policy: OutOfBoundsPolicy,
+    lane_counts: &[usize],
+) -> Vec<ConcreteProgram> {
+    let blocks = simplify_source(source);
+    let main = ValuePtr::new(find_value(&blocks, "main"));
+    lane_counts
+        .iter()
+        .map(|&lanes| {
+            let mut ctx = SolidificationContext::new();
+            ctx.out_of_bounds_policy = policy;
+            ctx.lanes = lanes;
+            solidify_with(main.ptr_clone(), ctx)
+        })
+        .collect()
+}
+
+#[test]
+fn lane_count_does_not_change_results() {
+    let x = floats((0..1001).map(|i| i as f32 * 0.25));
+    let gain = floats(vec![-3.0]);
+    let programs = solidify_for_lane_counts(LOOPED, OutOfBoundsPolicy::Clamp, &[8, 1, 4, 16]);
+    let expected = execute(&programs[0], &[&x, &gain]);
+    for program in &programs[1..] {
+        assert_eq!(execute(program, &[&x, &gain]), expected);
+    }
+
+    let inputs = operator_inputs();
+    let inputs: Vec<_> = inputs.iter().map(Vec::as_slice).collect();
+    let programs = solidify_for_lane_counts(OPERATORS, OutOfBoundsPolicy::Clamp, &[8, 1, 4, 16]);
+    // Compared as text so that NaNs count as equal to each other.
+    let expected = format!("{:?}", execute(&programs[0], &inputs));
+    for program in &programs[1..] {
+        assert_eq!(format!("{:?}", execute(program, &inputs)), expected);
+    }
+
+    let table = floats((0..16).map(|i| i as f32 * 0.5));
+    let phases = ints(vec![-1, 0, 3, 15, 16, 17, 33, 7]);
+    let inputs: &[&[Scalar]] = &[&table, &ints(vec![-20]), &phases];
+    let programs = solidify_for_lane_counts(WAVETABLE, OutOfBoundsPolicy::Wrap, &[8, 1, 4, 16]);
+    let expected = execute(&programs[0], inputs);
+    for program in &programs[1..] {
+        assert_eq!(execute(program, inputs), expected);
+    }
+}
+
+#[test]
+fn selects_and_casts_can_be_executed() {
+    let blocks = simplify_source(
+        r#"
+        ct_local main = fn {
+            input flags: Array(Bool, 8);
+            input x: Array(Int, 8);
+            output y;
+            output z;
+            y = if flags then x else 0.5;
+            z = if flags(0) then 1 else 2;
+        };
+    "#,
+    );
+    let program = solidify(ValuePtr::new(find_value(&blocks, "main")));
+    let flags: Vec<_> = (0..8).map(|i| Scalar::Bool(i % 3 == 0)).collect();
+    let x = ints(0..8);
+    assert_eq!(
+        execute(&program, &[&flags, &x]),
+        vec![
+            floats(vec![0.0, 0.5, 0.5, 3.0, 0.5, 0.5, 6.0, 0.5]),
+            ints(vec![1]),
+        ]
+    );
+}
+
+#[test]
+fn executed_integer_arithmetic_wraps() {
+    let blocks = simplify_source(
+        r#"
+        ct_local main = fn {
+            input x: Int;
+            input y: Int;
+            output sum;
+            output quotient;
+            output remainder;
+            output power;
+            output bits;
+            sum = x + y;
+            quotient = x // y;
+            remainder = x % y;
+            power = y ** -1;
+            bits = not x xor y;
+        };
+    "#,
+    );
+    let program = solidify(ValuePtr::new(find_value(&blocks, "main")));
+    let run = |x, y| {
+        execute(&program, &[&ints(vec![x]), &ints(vec![y])])
+            .into_iter()
+            .map(|output| output[0])
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        run(i32::MAX, 1),
+        ints(vec![i32::MIN, i32::MAX, 0, 1, !i32::MAX ^ 1])
+    );
+    assert_eq!(run(7, -2), ints(vec![5, -3, 1, 0, !7 ^ -2]));
+    // Dividing by zero gives zero instead of trapping.
+    assert_eq!(run(7, 0), ints(vec![7, 0, 0, 0, !7]));
+}
+
+#[test]
+#[should_panic(expected = "Input 0 should only hold Float values.")]
+fn inputs_must_have_the_right_types() {
+    let blocks = simplify_source(LOOPED);
+    let program = solidify(ValuePtr::new(find_value(&blocks, "main")));
+    execute(&program, &[&ints(0..1001), &floats(vec![1.0])]);
+}
+
+#[cfg(feature = "jit")]
+fn assert_jit_matches_interpreter(
+    program: &crate::concrete::ConcreteProgram,
+    inputs: &[&[Scalar]],
+) {
+    let compiled = crate::jit::compile(program).unwrap();
+    // Compared as text so that NaNs count as equal to each other.
+    assert_eq!(
+        format!("{:?}", compiled.run(inputs)),
+        format!("{:?}", execute(program, inputs))
+    );
+}
+
+#[test]
+#[cfg(feature = "jit")]
+fn jit_compiled_loops_match_the_interpreter() {
+    let x = floats((0..1001).map(|i| i as f32 * 0.25));
+    let gain = floats(vec![-3.0]);
+    let blocks = simplify_source(LOOPED);
+    let program = solidify(ValuePtr::new(find_value(&blocks, "main")));
+    assert_jit_matches_interpreter(&program, &[&x, &gain]);
+
+    let mut ctx = SolidificationContext::new();
+    ctx.loop_threshold = usize::MAX;
+    let program = solidify_with(ValuePtr::new(find_value(&blocks, "main")), ctx);
+    assert_jit_matches_interpreter(&program, &[&x, &gain]);
+
+    let blocks = simplify_source(
+        r#"
+        ct_local main = fn {
+            input x: Array(Float, 200);
+            input phase: Int;
+            output above: Array(Bool, 200);
+            output picked: Float;
+            local scaled: Array(Float, 200) = x * 3.0;
+            above = scaled > 100.0;
+            picked = scaled(phase);
+        };
+    "#,
+    );
+    let program = solidify(ValuePtr::new(find_value(&blocks, "main")));
+    let x = floats((0..200).map(|i| i as f32 * 0.5));
+    for phase in [-5, 0, 123, 250] {
+        assert_jit_matches_interpreter(&program, &[&x, &ints(vec![phase])]);
+    }
+}
+
+#[test]
+#[cfg(feature = "jit")]
+fn jit_compiled_runtime_indices_match_the_interpreter() {
+    let table = floats((0..16).map(|i| i as f32 * 0.5));
+    let phases = ints(vec![-1, 0, 3, 15, 16, 17, 33, 7]);
+    let blocks = simplify_source(WAVETABLE);
+    for policy in [OutOfBoundsPolicy::Clamp, OutOfBoundsPolicy::Wrap] {
+        let mut ctx = SolidificationContext::new();
+        ctx.out_of_bounds_policy = policy;
+        let program = solidify_with(ValuePtr::new(find_value(&blocks, "main")), ctx);
+        for phase in [-17, -1, 0, 9, 20] {
+            assert_jit_matches_interpreter(&program, &[&table, &ints(vec![phase]), &phases]);
+        }
+    }
+}
+
+const OPERATORS: &str = r#"
+    ct_local main = fn {
+        input x: Array(Int, 8);
+        input y: Array(Int, 8);
+        input f: Array(Float, 8);
+        input flags: Array(Bool, 8);
+        output r0;
+        output r1;
+        output r2;
+        output r3;
+        output r4;
+        output r5;
+        output r6;
+        output r7;
+        output r8;
+        output r9;
+        output r10;
+        output r11;
+        output r12;
+        output r13;
+        output r14;
+        output r15;
+        output r16;
+        output r17;
+        output r18;
+        output r19;
+        output scalars;
+        r0 = x + y;
+        r1 = x - y;
+        r2 = x * y;
+        r3 = x // y;
+        r4 = x % y;
+        r5 = x ** y;
+        r6 = (x and y) xor not y;
+        r7 = f + x;
+        r8 = f - 1.5;
+        r9 = f * f;
+        r10 = f / x;
+        r11 = f % 2.0;
+        r12 = f ** 0.5;
+        r13 = x < y;
+        r14 = f >= x;
+        r15 = x == y;
+        r16 = f != 1.0;
+        r17 = flags xor x > 2;
+        r18 = not flags;
+        r19 = if flags then f else x;
+        scalars = [x(0) // y(0) + x(1) % y(1), x(2) ** y(2), -x(3)];
+    };
+"#;
+
+fn operator_inputs() -> Vec<Vec<Scalar>> {
+    vec![
+        ints(vec![i32::MIN, 7, -7, 3, 0, i32::MAX, 2, -1]),
+        ints(vec![-1, 0, 2, -3, 5, 1, 31, -2]),
+        floats(vec![0.5, -2.25, 1.0, f32::NAN, 4.0, -0.0, 9.0, 1e30]),
+        (0..8).map(|i| Scalar::Bool(i % 3 != 1)).collect(),
+    ]
+}
+
+#[test]
+#[cfg(feature = "jit")]
+fn jit_compiled_operators_match_the_interpreter() {
+    let blocks = simplify_source(OPERATORS);
+    let program = solidify(ValuePtr::new(find_value(&blocks, "main")));
+    let inputs = operator_inputs();
+    let inputs: Vec<_> = inputs.iter().map(Vec::as_slice).collect();
+    assert_jit_matches_interpreter(&program, &inputs);
+}
+
+#[test]
+#[cfg(feature = "jit")]
+fn jit_compiled_programs_can_have_other_lane_counts() {
+    let x = floats((0..1001).map(|i| i as f32 * 0.25));
+    let gain = floats(vec![-3.0]);
+    for program in solidify_for_lane_counts(LOOPED, OutOfBoundsPolicy::Clamp, &[1, 4, 16]) {
+        assert_jit_matches_interpreter(&program, &[&x, &gain]);
+    }
+
+    let inputs = operator_inputs();
+    let inputs: Vec<_> = inputs.iter().map(Vec::as_slice).collect();
+    for program in solidify_for_lane_counts(OPERATORS, OutOfBoundsPolicy::Clamp, &[1, 4, 16]) {
+        assert_jit_matches_interpreter(&program, &inputs);
+    }
+
+    let table = floats((0..16).map(|i| i as f32 * 0.5));
+    let phases = ints(vec![-1, 0, 3, 15, 16, 17, 33, 7]);
+    for program in solidify_for_lane_counts(WAVETABLE, OutOfBoundsPolicy::Wrap, &[1, 4, 16]) {
+        assert_jit_matches_interpreter(&program, &[&table, &ints(vec![-20]), &phases]);
+    }
+
+    let programs = solidify_for_lane_counts(LOOPED, OutOfBoundsPolicy::Clamp, &[6]);
+    assert!(matches!(
+        crate::jit::compile(&programs[0]),
+        Err(crate::jit::JitError::UnsupportedLanes(6))
+    ));
+}
+
+/// Formats scalars the way Rust and C print arrays of them in the tests of
+/// the emitted code.
+fn format_scalars(scalars: &[Scalar]) -> String {
+    let scalars: Vec<_> = scalars
+        .iter()
+        .map(|scalar| match scalar {
+            Scalar::Int(value) => format!("{}", value),
+            Scalar::Float(value) => format!("{:?}", value),
+            Scalar::Bool(value) => format!("{}", value),
+        })
+        .collect();
+    format!("[{}]", scalars.join(", "))
+}
+
+/// Prints floats as their bits so that C programs can print them the same
+/// way.
+fn format_scalar_bits(scalars: &[Scalar]) -> String {
+    let scalars: Vec<_> = scalars
+        .iter()
+        .map(|scalar| match scalar {
+            Scalar::Int(value) => format!("{}", value),
+            Scalar::Float(value) if value.is_nan() => "NaN".to_owned(),
+            Scalar::Float(value) => format!("0x{:08x}", value.to_bits()),
+            Scalar::Bool(value) => format!("{}", value),
+        })
+        .collect();
+    format!("[{}]", scalars.join(", "))
+}
+
+/// Compiles and runs a program with a tool, returning what it printed.
+/// `libraries` are passed to the tool after the source file.
+fn compile_and_run(
+    name: &str,
+    source: &str,
+    extension: &str,
+    compiler: &[&str],
+    libraries: &[&str],
+) -> String {
+    let dir = std::env::temp_dir().join(format!("nodespeak-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let source_path = dir.join(format!("main.{}", extension));
+    let binary_path = dir.join("main");
+    std::fs::write(&source_path, source).unwrap();
+    let compiled = std::process::Command::new(compiler[0])
+        .args(&compiler[1..])
+        .arg(&source_path)
+        .arg("-o")
+        .arg(&binary_path)
+        .args(libraries)
+        .output()
+        .unwrap();
+    assert!(
+        compiled.status.success(),
+        "{}\n{}",
+        source,
+        String::from_utf8_lossy(&compiled.stderr)
+    );
+    let output = std::process::Command::new(&binary_path).output().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+fn run_emitted_rust(name: &str, program: &ConcreteProgram, inputs: &[&[Scalar]]) -> String {
+    let mut source = crate::emit::rust::emit(program, "run");
+    source.push_str("\nfn main() {\n");
+    let mut args = Vec::new();
+    for (index, input) in inputs.iter().enumerate() {
+        let scalars: Vec<_> = input
+            .iter()
+            .map(|scalar| match scalar {
+                Scalar::Int(value) => format!("{}", value),
+                Scalar::Float(value) => format!("f32::from_bits({})", value.to_bits()),
+                Scalar::Bool(value) => format!("{}", value),
+            })
+            .collect();
+        source.push_str(&format!(
+            "    let input_{} = [{}];\n",
+            index,
+            scalars.join(", ")
+        ));
+        args.push(format!("&input_{}", index));
+    }
+    for (index, output) in program.outputs().iter().enumerate() {
+        let zero = match output.typee().base {
+            ConcreteScalarType::Int => "0",
+            ConcreteScalarType::Float => "0.0",
+            ConcreteScalarType::Bool => "false",
+        };
+        source.push_str(&format!(
+            "    let mut output_{} = [{}; {}];\n",
+            index,
+            zero,
+            output.typee().size()
+        ));
+        args.push(format!("&mut output_{}", index));
+    }
+    source.push_str(&format!("    run({});\n", args.join(", ")));
+    for index in 0..program.outputs().len() {
+        source.push_str(&format!(
+            "    println!(\"{{:?}}\", &output_{}[..]);\n",
+            index
+        ));
+    }
+    source.push_str("}\n");
+    compile_and_run(name, &source, "rs", &["rustc", "-O", "-D", "warnings"], &[])
+}
+
+/// Prints the scalars of outputs the way `format_scalar_bits` does.
+const C_PRINTERS: &str = r#"
+#include <stdio.h>
+#include <string.h>
+
+static inline void print_int32_t(int32_t value) {
+    printf("%d", (int)value);
+}
+
+static inline void print_float(float value) {
+    uint32_t bits;
+    memcpy(&bits, &value, sizeof bits);
+    if (isnan(value)) {
+        printf("NaN");
     } else {
-        println!("{:#?}", result);
+        printf("0x%08x", (unsigned)bits);
+    }
+}
+
+static inline void print_bool(bool value) {
+    fputs(value ? "true" : "false", stdout);
+}
+"#;
+
+fn c_type_name(typee: ConcreteScalarType) -> &'static str {
+    match typee {
+        ConcreteScalarType::Int => "int32_t",
+        ConcreteScalarType::Float => "float",
+        ConcreteScalarType::Bool => "bool",
+    }
+}
+
+fn run_emitted_c(name: &str, program: &ConcreteProgram, inputs: &[&[Scalar]]) -> String {
+    let emitted = crate::emit::c::emit(program, "run");
+    // Everything goes in one file, so the header is pasted in.
+    let mut source = emitted
+        .source
+        .replace("#include \"run.h\"\n", &emitted.header);
+    source.push_str(C_PRINTERS);
+    source.push_str("\nint main(void) {\n");
+    let mut args = Vec::new();
+    for (index, (input, typee)) in inputs.iter().zip(program.inputs()).enumerate() {
+        // Ints and floats are copied from their bits so that they are exact.
+        let scalars: Vec<_> = input
+            .iter()
+            .map(|scalar| match scalar {
+                Scalar::Int(value) => format!("{}u", *value as u32),
+                Scalar::Float(value) => format!("{}u", value.to_bits()),
+                Scalar::Bool(value) => format!("{}", value),
+            })
+            .collect();
+        let type_name = c_type_name(typee.base);
+        if typee.base == ConcreteScalarType::Bool {
+            source.push_str(&format!(
+                "    bool input_{}[] = {{{}}};\n",
+                index,
+                scalars.join(", ")
+            ));
+        } else {
+            source.push_str(&format!(
+                "    uint32_t bits_{0}[] = {{{1}}};\n    {2} input_{0}[{3}];\n    \
+                memcpy(input_{0}, bits_{0}, sizeof input_{0});\n",
+                index,
+                scalars.join(", "),
+                type_name,
+                input.len()
+            ));
+        }
+        args.push(format!("input_{}", index));
+    }
+    for (index, output) in program.outputs().iter().enumerate() {
+        source.push_str(&format!(
+            "    {} output_{}[{}];\n",
+            c_type_name(output.typee().base),
+            index,
+            output.typee().size()
+        ));
+        args.push(format!("output_{}", index));
+    }
+    source.push_str(&format!("    run({});\n", args.join(", ")));
+    for (index, output) in program.outputs().iter().enumerate() {
+        source.push_str(&format!(
+            "    printf(\"[\");\n    \
+            for (int i = 0; i < {}; i++) {{\n        \
+            if (i > 0) {{\n            \
+            printf(\", \");\n        \
+            }}\n        \
+            print_{}(output_{}[i]);\n    \
+            }}\n    \
+            printf(\"]\\n\");\n",
+            output.typee().size(),
+            c_type_name(output.typee().base),
+            index
+        ));
+    }
+    source.push_str("    return 0;\n}\n");
+    compile_and_run(
+        name,
+        &source,
+        "c",
+        &["cc", "-std=c99", "-pedantic", "-Wall", "-Werror", "-O2"],
+        &["-lm"],
+    )
+}
+
+fn expected_output(
+    program: &ConcreteProgram,
+    inputs: &[&[Scalar]],
+    format: fn(&[Scalar]) -> String,
+) -> String {
+    execute(program, inputs)
+        .iter()
+        .map(|output| format!("{}\n", format(output)))
+        .collect()
+}
+
+#[test]
+fn emitted_rust_takes_typed_buffers() {
+    let blocks = simplify_source(WAVETABLE);
+    let program = solidify(ValuePtr::new(find_value(&blocks, "main")));
+    let source = crate::emit::rust::emit(&program, "wavetable");
+    assert!(source.starts_with(
+        "pub fn wavetable(input_0: &[f32; 16], input_1: &[i32; 1], input_2: &[i32; 8], \
+        output_0: &mut [f32; 1], output_1: &mut [f32; 8]) {\n"
+    ));
+    // The gather is computed one lane at a time.
+    assert!(source.contains("for l in 0..8 {"));
+}
+
+#[test]
+fn emitted_rust_matches_the_interpreter() {
+    let blocks = simplify_source(LOOPED);
+    let program = solidify(ValuePtr::new(find_value(&blocks, "main")));
+    let x = floats((0..1001).map(|i| i as f32 * 0.25));
+    let gain = floats(vec![-3.0]);
+    let inputs: &[&[Scalar]] = &[&x, &gain];
+    assert_eq!(
+        run_emitted_rust("looped", &program, inputs),
+        expected_output(&program, inputs, format_scalars)
+    );
+
+    let blocks = simplify_source(OPERATORS);
+    let program = solidify(ValuePtr::new(find_value(&blocks, "main")));
+    let inputs = operator_inputs();
+    let inputs: Vec<_> = inputs.iter().map(Vec::as_slice).collect();
+    assert_eq!(
+        run_emitted_rust("operators", &program, &inputs),
+        expected_output(&program, &inputs, format_scalars)
+    );
+
+    let blocks = simplify_source(WAVETABLE);
+    let mut ctx = SolidificationContext::new();
+    ctx.out_of_bounds_policy = OutOfBoundsPolicy::Wrap;
+    let program = solidify_with(ValuePtr::new(find_value(&blocks, "main")), ctx);
+    let table = floats((0..16).map(|i| i as f32 * 0.5));
+    let phases = ints(vec![-1, 0, 3, 15, 16, 17, 33, 7]);
+    let inputs: &[&[Scalar]] = &[&table, &ints(vec![-20]), &phases];
+    assert_eq!(
+        run_emitted_rust("wavetable", &program, inputs),
+        expected_output(&program, inputs, format_scalars)
+    );
+}
+
+#[test]
+fn emitted_c_documents_the_buffer_layout() {
+    let blocks = simplify_source(WAVETABLE);
+    let program = solidify(ValuePtr::new(find_value(&blocks, "main")));
+    let emitted = crate::emit::c::emit(&program, "wavetable");
+    assert!(emitted.header.contains(
+        "void wavetable(const float input_0[16], const int32_t input_1[1], \
+        const int32_t input_2[8], float output_0[1], float output_1[8]);\n"
+    ));
+    assert!(emitted
+        .header
+        .contains(" * input_0: Array(Float, 16), laid out as float[16]\n"));
+    assert!(emitted.source.starts_with("#include \"wavetable.h\"\n"));
+    assert!(emitted.source.contains("for (int l = 0; l < 8; l++) {"));
+
+    let blocks = simplify_source(
+        "
+        ct_local main = fn {
+            input x: Array(Int, 8, 3);
+            output y: Array(Int, 8);
+            y = x(2);
+        };
+        ",
+    );
+    let program = solidify(ValuePtr::new(find_value(&blocks, "main")));
+    let emitted = crate::emit::c::emit(&program, "rows");
+    assert!(emitted
+        .header
+        .contains(" * input_0: Array(Int, 8, 3), laid out as int32_t[3][8]\n"));
+    let rows = ints(0..24);
+    assert_eq!(
+        run_emitted_c("rows", &program, &[&rows]),
+        "[16, 17, 18, 19, 20, 21, 22, 23]\n"
+    );
+}
+
+#[test]
+fn emitted_c_matches_the_interpreter() {
+    let blocks = simplify_source(LOOPED);
+    let program = solidify(ValuePtr::new(find_value(&blocks, "main")));
+    let x = floats((0..1001).map(|i| i as f32 * 0.25));
+    let gain = floats(vec![-3.0]);
+    let inputs: &[&[Scalar]] = &[&x, &gain];
+    assert_eq!(
+        run_emitted_c("looped", &program, inputs),
+        expected_output(&program, inputs, format_scalar_bits)
+    );
+
+    let blocks = simplify_source(OPERATORS);
+    let program = solidify(ValuePtr::new(find_value(&blocks, "main")));
+    let inputs = operator_inputs();
+    let inputs: Vec<_> = inputs.iter().map(Vec::as_slice).collect();
+    assert_eq!(
+        run_emitted_c("operators", &program, &inputs),
+        expected_output(&program, &inputs, format_scalar_bits)
+    );
+
+    let blocks = simplify_source(WAVETABLE);
+    let mut ctx = SolidificationContext::new();
+    ctx.out_of_bounds_policy = OutOfBoundsPolicy::Wrap;
+    let program = solidify_with(ValuePtr::new(find_value(&blocks, "main")), ctx);
+    let table = floats((0..16).map(|i| i as f32 * 0.5));
+    let phases = ints(vec![-1, 0, 3, 15, 16, 17, 33, 7]);
+    let inputs: &[&[Scalar]] = &[&table, &ints(vec![-20]), &phases];
+    assert_eq!(
+        run_emitted_c("wavetable", &program, inputs),
+        expected_output(&program, inputs, format_scalar_bits)
+    );
+}
+
+#[test]
+fn emitted_code_can_have_other_lane_counts() {
+    let x = floats((0..1001).map(|i| i as f32 * 0.25));
+    let gain = floats(vec![-3.0]);
+    let inputs: &[&[Scalar]] = &[&x, &gain];
+    let programs = solidify_for_lane_counts(LOOPED, OutOfBoundsPolicy::Clamp, &[1, 4, 6]);
+    for (program, name) in programs.iter().zip(["looped_1", "looped_4", "looped_6"]) {
+        assert_eq!(
+            run_emitted_rust(name, program, inputs),
+            expected_output(program, inputs, format_scalars)
+        );
+        assert_eq!(
+            run_emitted_c(name, program, inputs),
+            expected_output(program, inputs, format_scalar_bits)
+        );
+    }
+
+    let table = floats((0..16).map(|i| i as f32 * 0.5));
+    let phases = ints(vec![-1, 0, 3, 15, 16, 17, 33, 7]);
+    let inputs: &[&[Scalar]] = &[&table, &ints(vec![-20]), &phases];
+    let programs = solidify_for_lane_counts(WAVETABLE, OutOfBoundsPolicy::Wrap, &[1, 4]);
+    for (program, name) in programs.iter().zip(["wavetable_1", "wavetable_4"]) {
+        assert_eq!(
+            run_emitted_rust(name, program, inputs),
+            expected_output(program, inputs, format_scalars)
+        );
+        assert_eq!(
+            run_emitted_c(name, program, inputs),
+            expected_output(program, inputs, format_scalar_bits)
+        );
     }
-    panic!();
+    let emitted = crate::emit::c::emit(&programs[1], "wavetable");
+    assert!(emitted.source.contains("for (int l = 0; l < 4; l++) {"));
 }
@@ -26,10 +26,7 @@ impl<T: Clone> NVec<T> {
         for index in crate::util::nd_index_iter(dimensions.clone()) {
             data.push(value_builder(index)?);
         }
-        Ok(Self::from_vec_and_dims(
-            data,
-            dimensions.iter().map(|index| *index as usize).collect(),
-        ))
+        Ok(Self::from_vec_and_dims(data, dimensions))
     }
 
     pub fn build(
@@ -40,14 +37,11 @@ impl<T: Clone> NVec<T> {
         for index in crate::util::nd_index_iter(dimensions.clone()) {
             data.push(value_builder(index));
         }
-        Self::from_vec_and_dims(
-            data,
-            dimensions.iter().map(|index| *index as usize).collect(),
-        )
+        Self::from_vec_and_dims(data, dimensions)
     }
 
     pub fn new(dimensions: Vec<usize>, filler_value: T) -> NVec<T> {
-        assert!(dimensions.len() > 0);
+        assert!(!dimensions.is_empty());
         let mut size = 1;
         for dim in dimensions.iter() {
             let dim = *dim;
@@ -81,13 +75,13 @@ impl<T: Clone> NVec<T> {
         assert!(items.len() == size);
         NVec {
             multipliers: Self::make_multipliers(&dimensions),
-            dimensions: dimensions,
+            dimensions,
             data: items,
         }
     }
 
     pub fn collect(sub_arrays: Vec<NVec<T>>) -> NVec<T> {
-        assert!(sub_arrays.len() > 0);
+        assert!(!sub_arrays.is_empty());
 
         let mut dimensions = sub_arrays[0].dimensions.clone();
         let mut multipliers = sub_arrays[0].multipliers.clone();
@@ -165,7 +159,7 @@ impl<T: Clone> NVec<T> {
         let new_dimensions = Vec::from(&self.dimensions[slice_order..]);
         let new_multipliers = Vec::from(&self.multipliers[slice_order..]);
         let start_index = self.convert_to_raw_index(coordinate);
-        let size = if new_dimensions.len() == 0 {
+        let size = if new_dimensions.is_empty() {
             1
         } else {
             new_dimensions[0] * new_multipliers[0]
@@ -203,27 +197,27 @@ mod test {
 
     #[test]
     fn store_fetch_1d() {
-        let mut array = NVec::new(vec![8], 0 as usize);
+        let mut array = NVec::new(vec![8], 0_usize);
         for x in 0..8 {
-            array.set_item(&vec![x], x * 2);
+            array.set_item(&[x], x * 2);
         }
         for x in 0..8 {
-            assert!(*array.borrow_item(&vec![x]) == x * 2);
+            assert!(*array.borrow_item(&[x]) == x * 2);
         }
     }
 
     #[test]
     fn store_fetch_2d() {
         // [4][3]usize
-        let mut array = NVec::new(vec![4, 3], 0 as usize);
+        let mut array = NVec::new(vec![4, 3], 0_usize);
         for x in 0..4 {
             for y in 0..3 {
-                array.set_item(&vec![x, y], x + y * 10);
+                array.set_item(&[x, y], x + y * 10);
             }
         }
         for x in 0..4 {
             for y in 0..3 {
-                assert!(*array.borrow_item(&vec![x, y]) == x + y * 10);
+                assert!(*array.borrow_item(&[x, y]) == x + y * 10);
             }
         }
     }
@@ -233,9 +227,9 @@ mod test {
         // 3x [6]usize
         let mut arrays = Vec::with_capacity(3);
         for x in 0..3 {
-            let mut array = NVec::new(vec![6], 0 as usize);
+            let mut array = NVec::new(vec![6], 0_usize);
             for y in 0..6 {
-                array.set_item(&vec![y], x + y * 10);
+                array.set_item(&[y], x + y * 10);
             }
             arrays.push(array);
         }
@@ -243,7 +237,7 @@ mod test {
         let collected_array = NVec::collect(arrays);
         for x in 0..3 {
             for y in 0..6 {
-                assert!(*collected_array.borrow_item(&vec![x, y]) == x + y * 10);
+                assert!(*collected_array.borrow_item(&[x, y]) == x + y * 10);
             }
         }
     }
@@ -251,16 +245,16 @@ mod test {
     #[test]
     fn slice_2d() {
         // [4][3]usize
-        let mut array = NVec::new(vec![4, 3], 0 as usize);
+        let mut array = NVec::new(vec![4, 3], 0_usize);
         for x in 0..4 {
             for y in 0..3 {
-                array.set_item(&vec![x, y], x + y * 10);
+                array.set_item(&[x, y], x + y * 10);
             }
         }
         for x in 0..4 {
-            let slice = array.clone_slice(&vec![x]);
+            let slice = array.clone_slice(&[x]);
             for y in 0..3 {
-                assert!(*slice.borrow_item(&vec![y]) == x + y * 10);
+                assert!(*slice.borrow_item(&[y]) == x + y * 10);
             }
         }
     }
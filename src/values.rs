@@ -1,8 +1,10 @@
 mod base;
+mod error;
 mod ptr;
 pub mod simplify;
 mod type_arithmetic;
 mod type_compatibility;
 
 pub use base::*;
+pub use error::*;
 pub use ptr::*;
\ No newline at end of file
@@ -1,53 +1,188 @@
 use std::{
     hash::{Hash, Hasher},
-    ops::Deref,
+    ops::{Deref, Range},
     rc::Rc, fmt::{Debug, Formatter, self},
 };
 
 use super::ValuePtr;
 
+/// A region of source code, identified by the file it came from and a byte
+/// range within that file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub file: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(file: usize, start: usize, end: usize) -> Self {
+        Self { file, start, end }
+    }
+
+    pub fn range(&self) -> Range<usize> {
+        self.start..self.end
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Index {
     pub indices: Vec<ValuePtr>,
-    pub eight_wide_mode: bool,
+    /// How many consecutive elements are written, starting at the one the
+    /// indices select. This is 1 unless a keyword such as `8wide` follows
+    /// the indices.
+    pub width: usize,
 }
 
 impl Index {
     pub fn deep_clone(&self) -> Self {
+        self.substituted(&[])
+    }
+
+    /// See `ValuePtr::substituted`.
+    pub fn substituted(&self, substitutions: &[(LocalPtr, ValuePtr)]) -> Self {
         Self {
-            indices: self.indices.iter().map(ValuePtr::deep_clone).collect(),
-            eight_wide_mode: self.eight_wide_mode,
+            indices: substitute_all(&self.indices, substitutions),
+            width: self.width,
         }
     }
 }
 
+fn substitute_all(values: &[ValuePtr], substitutions: &[(LocalPtr, ValuePtr)]) -> Vec<ValuePtr> {
+    values
+        .iter()
+        .map(|value| value.substituted(substitutions))
+        .collect()
+}
+
+fn substitute_locals(locals: &[LocalPtr], substitutions: &[(LocalPtr, ValuePtr)]) -> Vec<LocalPtr> {
+    locals
+        .iter()
+        .map(|local| local.substituted(substitutions))
+        .collect()
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum Statement {
     Assignment {
         base: ValuePtr,
         index: Option<Index>,
         target: LocalPtr,
+        span: Option<Span>,
+    },
+    Declaration(LocalPtr, Option<Span>),
+    /// Runs the body `count` times, which must be known at compile time. The
+    /// counter holds the number of the current iteration, starting at zero.
+    /// `locals` lists the locals declared in the body, which start over in
+    /// each iteration.
+    Repeat {
+        count: ValuePtr,
+        counter: Option<LocalPtr>,
+        locals: Vec<LocalPtr>,
+        body: Vec<Statement>,
+        span: Option<Span>,
     },
-    Declaration(LocalPtr),
     Noop,
 }
 
 impl Statement {
     pub fn deep_clone(&self) -> Self {
+        self.substituted(&[])
+    }
+
+    /// See `ValuePtr::substituted`. Locals which are replaced by other locals
+    /// are also renamed where they are assigned to or declared.
+    pub fn substituted(&self, substitutions: &[(LocalPtr, ValuePtr)]) -> Self {
         match self {
             Statement::Assignment {
                 base,
                 index,
                 target,
+                span,
             } => Statement::Assignment {
-                base: base.deep_clone(),
-                index: index.clone(),
-                target: target.ptr_clone(),
+                base: base.substituted(substitutions),
+                index: index.as_ref().map(|index| index.substituted(substitutions)),
+                target: target.substituted(substitutions),
+                span: *span,
+            },
+            Statement::Declaration(local, span) => {
+                Statement::Declaration(local.substituted(substitutions), *span)
+            }
+            Statement::Repeat {
+                count,
+                counter,
+                locals,
+                body,
+                span,
+            } => Statement::Repeat {
+                count: count.substituted(substitutions),
+                counter: counter
+                    .as_ref()
+                    .map(|counter| counter.substituted(substitutions)),
+                locals: substitute_locals(locals, substitutions),
+                body: body
+                    .iter()
+                    .map(|statement| statement.substituted(substitutions))
+                    .collect(),
+                span: *span,
             },
-            Statement::Declaration(local) => Statement::Declaration(local.ptr_clone()),
             Statement::Noop => Statement::Noop,
         }
     }
+
+    /// Calls `visit` with every local the statement reads the value of.
+    pub fn visit_locals(&self, visit: &mut dyn FnMut(&LocalPtr)) {
+        match self {
+            Statement::Assignment {
+                base,
+                index,
+                target,
+                ..
+            } => {
+                base.borrow().visit_locals(visit);
+                target.typee.borrow().visit_locals(visit);
+                for index in index.iter().flat_map(|index| &index.indices) {
+                    index.borrow().visit_locals(visit);
+                }
+            }
+            Statement::Declaration(local, _) => local.typee.borrow().visit_locals(visit),
+            Statement::Repeat { count, body, .. } => {
+                count.borrow().visit_locals(visit);
+                for statement in body {
+                    statement.visit_locals(visit);
+                }
+            }
+            Statement::Noop => (),
+        }
+    }
+
+    /// Calls `visit` with every local declared by the statement, including
+    /// those declared by loops and functions inside it.
+    pub fn visit_declared_locals(&self, visit: &mut dyn FnMut(&LocalPtr)) {
+        match self {
+            Statement::Assignment { base, index, .. } => {
+                base.borrow().visit_declared_locals(visit);
+                for index in index.iter().flat_map(|index| &index.indices) {
+                    index.borrow().visit_declared_locals(visit);
+                }
+            }
+            Statement::Declaration(local, _) => visit(local),
+            Statement::Repeat {
+                count,
+                counter,
+                locals,
+                body,
+                ..
+            } => {
+                count.borrow().visit_declared_locals(visit);
+                counter.iter().chain(locals).for_each(&mut *visit);
+                for statement in body {
+                    statement.visit_declared_locals(visit);
+                }
+            }
+            Statement::Noop => (),
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -67,41 +202,184 @@ pub enum Value {
         inputs: Vec<LocalPtr>,
         outputs: Vec<LocalPtr>,
         locals: Vec<LocalPtr>,
+        /// Parameters declared with `T?` in the types of the inputs, whose
+        /// values are inferred from the arguments of each call.
+        templates: Vec<LocalPtr>,
         body: Vec<Statement>,
     },
     FunctionCall(ValuePtr, Vec<ValuePtr>, usize),
+    /// Reads an element or sub-array of an array. The first index selects
+    /// along the outermost (last) dimension.
+    ElementRead {
+        base: ValuePtr,
+        indices: Vec<ValuePtr>,
+    },
+    /// `if condition then if_true else if_false`. If the condition is an
+    /// array, each element chooses between the corresponding elements of the
+    /// two branches.
+    Select {
+        condition: ValuePtr,
+        if_true: ValuePtr,
+        if_false: ValuePtr,
+    },
 }
 
 impl Value {
     pub fn deep_clone(&self) -> Self {
+        self.substituted(&[])
+    }
+
+    /// See `ValuePtr::substituted`.
+    pub fn substituted(&self, substitutions: &[(LocalPtr, ValuePtr)]) -> Self {
         match &self {
-            Value::BuiltinType(_)
-            | Value::BuiltinOp(_)
+            Value::BuiltinType(typee) => Value::BuiltinType(typee.substituted(substitutions)),
+            Value::BuiltinOp(_)
             | Value::Malformed
             | Value::FloatLiteral(_)
             | Value::IntLiteral(_)
-            | Value::BoolLiteral(_)
-            | Value::Local(_) => self.clone(),
+            | Value::BoolLiteral(_) => self.clone(),
+            Value::Local(local) => substitutions
+                .iter()
+                .find(|(target, _)| target == local)
+                .map_or_else(|| self.clone(), |(_, value)| value.borrow().clone()),
             Value::ArrayLiteral { elements, dims } => Self::ArrayLiteral {
-                elements: elements.iter().map(ValuePtr::deep_clone).collect(),
-                dims: dims.iter().map(ValuePtr::deep_clone).collect(),
+                elements: substitute_all(elements, substitutions),
+                dims: substitute_all(dims, substitutions),
             },
             Value::Function {
                 inputs,
                 outputs,
                 locals,
+                templates,
                 body,
             } => Value::Function {
-                inputs: inputs.clone(),
-                outputs: outputs.clone(),
-                locals: locals.clone(),
-                body: body.iter().map(Statement::deep_clone).collect(),
+                inputs: substitute_locals(inputs, substitutions),
+                outputs: substitute_locals(outputs, substitutions),
+                locals: substitute_locals(locals, substitutions),
+                templates: substitute_locals(templates, substitutions),
+                body: body
+                    .iter()
+                    .map(|statement| statement.substituted(substitutions))
+                    .collect(),
             },
             Value::FunctionCall(base, args, output) => Value::FunctionCall(
-                base.deep_clone(),
-                args.iter().map(ValuePtr::deep_clone).collect(),
+                base.substituted(substitutions),
+                substitute_all(args, substitutions),
                 *output,
             ),
+            Value::ElementRead { base, indices } => Value::ElementRead {
+                base: base.substituted(substitutions),
+                indices: substitute_all(indices, substitutions),
+            },
+            Value::Select {
+                condition,
+                if_true,
+                if_false,
+            } => Value::Select {
+                condition: condition.substituted(substitutions),
+                if_true: if_true.substituted(substitutions),
+                if_false: if_false.substituted(substitutions),
+            },
+        }
+    }
+
+    /// Calls `visit` with every local this value refers to, including those
+    /// used by the bodies of functions inside it.
+    pub fn visit_locals(&self, visit: &mut dyn FnMut(&LocalPtr)) {
+        let visit_all = |values: &[ValuePtr], visit: &mut dyn FnMut(&LocalPtr)| {
+            for value in values {
+                value.borrow().visit_locals(visit);
+            }
+        };
+        match self {
+            Value::BuiltinType(BuiltinType::Array { eltype, dims }) => {
+                eltype.borrow().visit_locals(visit);
+                visit_all(dims, visit);
+            }
+            Value::BuiltinType(BuiltinType::InSet { eltype, elements }) => {
+                eltype.borrow().visit_locals(visit);
+                visit_all(elements, visit);
+            }
+            Value::BuiltinType(BuiltinType::Function { inputs, outputs }) => {
+                visit_all(inputs, visit);
+                visit_all(outputs, visit);
+            }
+            Value::Local(local) => visit(local),
+            Value::ArrayLiteral { elements, dims } => {
+                visit_all(elements, visit);
+                visit_all(dims, visit);
+            }
+            Value::Function { body, .. } => {
+                for statement in body {
+                    statement.visit_locals(visit);
+                }
+            }
+            Value::FunctionCall(base, args, _) => {
+                base.borrow().visit_locals(visit);
+                visit_all(args, visit);
+            }
+            Value::ElementRead { base, indices } => {
+                base.borrow().visit_locals(visit);
+                visit_all(indices, visit);
+            }
+            Value::Select {
+                condition,
+                if_true,
+                if_false,
+            } => {
+                condition.borrow().visit_locals(visit);
+                if_true.borrow().visit_locals(visit);
+                if_false.borrow().visit_locals(visit);
+            }
+            _ => (),
+        }
+    }
+
+    /// Calls `visit` with every local declared by functions inside this value,
+    /// including their inputs, outputs and template parameters.
+    pub fn visit_declared_locals(&self, visit: &mut dyn FnMut(&LocalPtr)) {
+        let visit_all = |values: &[ValuePtr], visit: &mut dyn FnMut(&LocalPtr)| {
+            for value in values {
+                value.borrow().visit_declared_locals(visit);
+            }
+        };
+        match self {
+            Value::ArrayLiteral { elements, .. } => visit_all(elements, visit),
+            Value::Function {
+                inputs,
+                outputs,
+                locals,
+                templates,
+                body,
+            } => {
+                inputs
+                    .iter()
+                    .chain(outputs)
+                    .chain(locals)
+                    .chain(templates)
+                    .for_each(&mut *visit);
+                for statement in body {
+                    statement.visit_declared_locals(visit);
+                }
+            }
+            Value::FunctionCall(base, args, _) => {
+                base.borrow().visit_declared_locals(visit);
+                visit_all(args, visit);
+            }
+            Value::ElementRead { base, indices } => {
+                base.borrow().visit_declared_locals(visit);
+                visit_all(indices, visit);
+            }
+            Value::Select {
+                condition,
+                if_true,
+                if_false,
+            } => {
+                condition.borrow().visit_declared_locals(visit);
+                if_true.borrow().visit_declared_locals(visit);
+                if_false.borrow().visit_declared_locals(visit);
+            }
+            _ => (),
         }
     }
 }
@@ -128,13 +406,41 @@ pub enum BuiltinType {
     Malformed,
 }
 
+impl BuiltinType {
+    pub fn deep_clone(&self) -> Self {
+        self.substituted(&[])
+    }
+
+    /// See `ValuePtr::substituted`.
+    pub fn substituted(&self, substitutions: &[(LocalPtr, ValuePtr)]) -> Self {
+        match self {
+            BuiltinType::Array { eltype, dims } => BuiltinType::Array {
+                eltype: eltype.substituted(substitutions),
+                dims: substitute_all(dims, substitutions),
+            },
+            BuiltinType::InSet { eltype, elements } => BuiltinType::InSet {
+                eltype: eltype.substituted(substitutions),
+                elements: substitute_all(elements, substitutions),
+            },
+            BuiltinType::Function { inputs, outputs } => BuiltinType::Function {
+                inputs: substitute_all(inputs, substitutions),
+                outputs: substitute_all(outputs, substitutions),
+            },
+            _ => self.clone(),
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum BuiltinOp {
     Add,
     Sub,
     Mul,
     Div,
+    IntDiv,
     Rem,
+    Pow,
+    Neg,
 
     Min,
     Max,
@@ -160,6 +466,10 @@ pub struct Local {
     pub compile_time_only: bool,
     pub name: String,
     pub typee: ValuePtr,
+    /// Where the local's name appears in its declaration.
+    pub span: Option<Span>,
+    /// The text of the `##` comments written before the local's declaration.
+    pub doc: Option<String>,
 }
 
 #[derive(Clone)]
@@ -189,7 +499,7 @@ impl Deref for LocalPtr {
     type Target = Local;
 
     fn deref(&self) -> &Self::Target {
-        &*self.0
+        &self.0
     }
 }
 
@@ -201,6 +511,42 @@ impl LocalPtr {
     pub fn ptr_clone(&self) -> Self {
         Self(Rc::clone(&self.0))
     }
+
+    /// Returns the local this one is replaced by if the substitutions replace
+    /// it with another local, or this local otherwise.
+    pub fn substituted(&self, substitutions: &[(LocalPtr, ValuePtr)]) -> Self {
+        for (target, value) in substitutions {
+            if target == self {
+                if let Value::Local(local) = &*value.borrow() {
+                    return local.ptr_clone();
+                }
+            }
+        }
+        self.ptr_clone()
+    }
+
+    /// Makes a copy of each local, paired with a reference to the copy so that
+    /// `substituted` renames the originals to the copies. Where the type of a
+    /// local refers to one of the others, the copy's type refers to its copy.
+    pub fn fresh_copies(locals: &[LocalPtr]) -> Vec<(LocalPtr, ValuePtr)> {
+        let copies = locals
+            .iter()
+            .map(|local| {
+                let copy = LocalPtr::new(Local {
+                    typee: local.typee.deep_clone(),
+                    ..Local::clone(local)
+                });
+                (local.ptr_clone(), ValuePtr::new(Value::Local(copy)))
+            })
+            .collect::<Vec<_>>();
+        for (local, copy) in &copies {
+            if let Value::Local(copy) = &*copy.borrow() {
+                let typee = local.typee.borrow().substituted(&copies);
+                *copy.typee.borrow_mut() = typee;
+            }
+        }
+        copies
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -0,0 +1,214 @@
+use std::fmt::{self, Display, Formatter};
+
+use super::{LocalPtr, Span, ValuePtr};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CompileErrorKind {
+    /// The type of a value is not compatible with the type of the local it is
+    /// assigned to. `types` holds the value's type followed by the local's.
+    InvalidAssignment,
+    /// An assignment to a local of an array type used fewer indices than the
+    /// array has dimensions.
+    NotEnoughIndices {
+        expected: usize,
+        found: usize,
+    },
+    /// Indices were used on a value that is not an array.
+    IndexedNonArray,
+    /// An element read or indexed assignment used more indices than the array
+    /// has dimensions.
+    TooManyIndices {
+        expected: usize,
+        found: usize,
+    },
+    /// An index is not an integer, or arrays of indices have shapes which
+    /// can't be broadcast together. `types` holds the types of the indices.
+    InvalidIndex,
+    /// A constant index is outside the bounds of the dimension it selects
+    /// along.
+    IndexOutOfBounds {
+        index: i32,
+        size: usize,
+    },
+    /// The operator cannot be applied to values of the types in `types`.
+    InvalidOperation,
+    /// The first argument to `cast` is not a type.
+    CastToNonType,
+    /// The value's type (the second entry in `types`) cannot be cast to the
+    /// requested type (the first entry.)
+    InvalidCast,
+    WrongArgumentCount {
+        expected: usize,
+        found: usize,
+    },
+    /// An argument's type (the first entry in `types`) is not compatible with
+    /// the type of the corresponding input (the second entry.)
+    InvalidArgument,
+    /// A function returned without assigning a value to one of its outputs.
+    OutputNotAssigned,
+    /// The elements of an array literal do not all have the same shape.
+    RaggedArrayLiteral,
+    /// The condition of an `if` is not a Bool or an array of Bools. `types`
+    /// holds its type.
+    InvalidCondition,
+    /// The branches of an `if` have types which can't be combined. `types`
+    /// holds the type of each branch.
+    IncompatibleBranches,
+    /// The number of times to repeat a loop is not an Int known at compile
+    /// time. `types` holds the type of the count.
+    DynamicRepeatCount,
+    /// A loop was asked to repeat a negative number of times.
+    NegativeRepeatCount {
+        count: i32,
+    },
+    /// Unrolling a loop, including the loops it is nested inside, would take
+    /// more iterations than the context allows.
+    UnrollLimitExceeded {
+        limit: usize,
+        iterations: usize,
+    },
+    /// Inlining a call would nest more calls inside each other than the
+    /// context allows, usually because a function calls itself.
+    RecursionLimitExceeded {
+        limit: usize,
+    },
+    /// An indexed assignment used an index that isn't known at compile time,
+    /// or wrote to an array whose shape or element type isn't known.
+    DynamicIndexedAssignment,
+    /// The arguments for the inputs that mention a template parameter give it
+    /// values which can't be combined. `types` holds the value each argument
+    /// gives it.
+    TemplateConflict,
+    /// The arguments for the inputs that mention a size template parameter
+    /// have different sizes where it appears. `types` holds the size each
+    /// argument gives it.
+    TemplateSizeConflict {
+        sizes: Vec<i32>,
+    },
+    /// A compile-time local was left with a value which isn't completely known
+    /// at compile time, or a `ct_input` was given an argument which isn't.
+    UnknownCompileTimeValue,
+    /// A type or function was stored in a local which isn't compile-time only.
+    /// `types` holds the type of the value.
+    CompileTimeOnlyValue,
+}
+
+/// A problem with a program which was found while simplifying it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompileError {
+    pub kind: CompileErrorKind,
+    /// Where the error occurred. If it happened inside a function body, this
+    /// points into the function's definition.
+    pub span: Option<Span>,
+    /// The calls that were being inlined when the error occurred, innermost
+    /// first.
+    pub call_sites: Vec<Span>,
+    /// The types involved in the error, in the order described by `kind`.
+    pub types: Vec<ValuePtr>,
+    /// The locals involved in the error, if any.
+    pub locals: Vec<LocalPtr>,
+}
+
+impl CompileError {
+    pub fn new(
+        kind: CompileErrorKind,
+        span: Option<Span>,
+        types: Vec<ValuePtr>,
+        locals: Vec<LocalPtr>,
+    ) -> Self {
+        Self {
+            kind,
+            span,
+            call_sites: Vec::new(),
+            types,
+            locals,
+        }
+    }
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match &self.kind {
+            CompileErrorKind::InvalidAssignment => write!(f, "value has the wrong type")?,
+            CompileErrorKind::NotEnoughIndices { expected, found } => {
+                write!(f, "expected {} indices, found {}", expected, found)?
+            }
+            CompileErrorKind::IndexedNonArray => write!(f, "only arrays can be indexed")?,
+            CompileErrorKind::TooManyIndices { expected, found } => {
+                write!(f, "expected at most {} indices, found {}", expected, found)?
+            }
+            CompileErrorKind::InvalidIndex => write!(f, "indices must be integers")?,
+            CompileErrorKind::IndexOutOfBounds { index, size } => write!(
+                f,
+                "index {} is out of bounds for a dimension of size {}",
+                index, size
+            )?,
+            CompileErrorKind::InvalidOperation => {
+                write!(f, "operator cannot be used with these types")?
+            }
+            CompileErrorKind::CastToNonType => write!(f, "can only cast to a type")?,
+            CompileErrorKind::InvalidCast => write!(f, "value cannot be cast to this type")?,
+            CompileErrorKind::WrongArgumentCount { expected, found } => {
+                write!(f, "expected {} arguments, found {}", expected, found)?
+            }
+            CompileErrorKind::InvalidArgument => write!(f, "argument has the wrong type")?,
+            CompileErrorKind::OutputNotAssigned => write!(f, "output is never assigned")?,
+            CompileErrorKind::RaggedArrayLiteral => {
+                write!(f, "array literal elements have different shapes")?
+            }
+            CompileErrorKind::InvalidCondition => write!(f, "condition must be a Bool")?,
+            CompileErrorKind::IncompatibleBranches => {
+                write!(f, "branches have incompatible types")?
+            }
+            CompileErrorKind::DynamicRepeatCount => write!(
+                f,
+                "the number of repetitions must be an Int known at compile time"
+            )?,
+            CompileErrorKind::NegativeRepeatCount { count } => {
+                write!(f, "cannot repeat a loop {} times", count)?
+            }
+            CompileErrorKind::UnrollLimitExceeded { limit, iterations } => write!(
+                f,
+                "unrolling this loop takes {} iterations, more than the limit of {}",
+                iterations, limit
+            )?,
+            CompileErrorKind::RecursionLimitExceeded { limit } => write!(
+                f,
+                "inlining this call nests more than {} calls inside each other",
+                limit
+            )?,
+            CompileErrorKind::DynamicIndexedAssignment => write!(
+                f,
+                "indexed assignments need indices and array shapes known at compile time"
+            )?,
+            CompileErrorKind::TemplateConflict => write!(
+                f,
+                "arguments give a template parameter values which can't be combined"
+            )?,
+            CompileErrorKind::TemplateSizeConflict { sizes } => {
+                write!(f, "arguments have conflicting sizes")?;
+                for (index, size) in sizes.iter().enumerate() {
+                    let separator = if index == 0 { " " } else { ", " };
+                    write!(f, "{}{}", separator, size)?;
+                }
+            }
+            CompileErrorKind::UnknownCompileTimeValue => {
+                write!(f, "value must be known at compile time")?
+            }
+            CompileErrorKind::CompileTimeOnlyValue => write!(
+                f,
+                "types and functions can only be stored in compile-time locals"
+            )?,
+        }
+        for (index, local) in self.locals.iter().enumerate() {
+            let separator = if index == 0 { " (" } else { ", " };
+            write!(f, "{}`{}`", separator, local.name)?;
+        }
+        if !self.locals.is_empty() {
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for CompileError {}
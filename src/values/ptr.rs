@@ -1,15 +1,25 @@
 use std::{
     cell::{Ref, RefMut},
     fmt::{self, Debug, Formatter},
-    ops::{Add, BitAnd, BitOr, Deref, Div, Mul, Not, Rem, Sub},
+    ops::{Add, BitAnd, BitOr, Div, Mul, Not, Rem, Sub},
     rc::Rc,
 };
 
-use super::{BuiltinOp, BuiltinType, Value};
+use super::{BuiltinOp, BuiltinType, LocalPtr, Span, Value};
 use crate::util::{rcrc, Rcrc};
 
-#[derive(Clone, PartialEq)]
-pub struct ValuePtr(pub(super) Rcrc<Value>);
+/// A shared, mutable value. The span belongs to the pointer rather than the
+/// value so that simplifying a value in place does not lose track of where
+/// it was written.
+#[derive(Clone)]
+pub struct ValuePtr(pub(super) Rcrc<Value>, pub(super) Option<Span>);
+
+/// Spans are ignored, two pointers are equal if their values are equal.
+impl PartialEq for ValuePtr {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
 
 impl Debug for ValuePtr {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
@@ -19,18 +29,26 @@ impl Debug for ValuePtr {
 
 impl ValuePtr {
     pub fn new(value: Value) -> Self {
-        Self(rcrc(value))
+        Self(rcrc(value), None)
+    }
+
+    pub fn with_span(self, span: Span) -> Self {
+        Self(self.0, Some(span))
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        self.1
     }
 
     pub fn ptr_clone(&self) -> Self {
-        Self(Rc::clone(&self.0))
+        Self(Rc::clone(&self.0), self.1)
     }
 
-    pub fn borrow(&self) -> Ref<Value> {
+    pub fn borrow(&self) -> Ref<'_, Value> {
         self.0.borrow()
     }
 
-    pub fn borrow_mut(&self) -> RefMut<Value> {
+    pub fn borrow_mut(&self) -> RefMut<'_, Value> {
         self.0.borrow_mut()
     }
 
@@ -38,7 +56,7 @@ impl ValuePtr {
         Rc::as_ptr(&self.0).cast()
     }
 
-    pub(crate) fn as_type(&self) -> Option<BuiltinType> {
+    pub fn as_type(&self) -> Option<BuiltinType> {
         if let Value::BuiltinType(typee) = &*self.borrow() {
             Some(typee.clone())
         } else {
@@ -47,7 +65,13 @@ impl ValuePtr {
     }
 
     pub fn deep_clone(&self) -> Self {
-        Self::new(self.0.borrow().deep_clone())
+        self.substituted(&[])
+    }
+
+    /// Like `deep_clone`, but references to the given locals are replaced by
+    /// copies of the values they are paired with.
+    pub fn substituted(&self, substitutions: &[(LocalPtr, ValuePtr)]) -> Self {
+        Self(rcrc(self.0.borrow().substituted(substitutions)), self.1)
     }
 }
 
@@ -1,20 +1,33 @@
-use std::collections::HashMap;
-
-use itertools::Itertools;
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
 
 use super::{
-    type_arithmetic::calculate_type_arithmetic,
-    type_compatibility::type_a_is_compatible_with_type_b, BuiltinOp, BuiltinType, LocalPtr,
+    type_arithmetic::{
+        biggest_common_type, broadcast_common_type, calculate_index_type, calculate_select_type,
+        calculate_type_arithmetic,
+    },
+    type_compatibility::type_a_is_compatible_with_type_b,
+    BuiltinOp, BuiltinType, CompileError, CompileErrorKind, Index, Local, LocalPtr, Span,
     Statement, Value, ValuePtr,
 };
+use crate::util::nd_index_iter;
 
+/// Folds the same way programs are executed: arithmetic wraps on overflow and
+/// dividing by zero, including raising zero to a negative power, gives zero.
 fn int_op(op: BuiltinOp, lhs: i32, rhs: i32) -> Value {
     match op {
-        BuiltinOp::Add => Value::IntLiteral(lhs + rhs),
-        BuiltinOp::Sub => Value::IntLiteral(lhs - rhs),
-        BuiltinOp::Mul => Value::IntLiteral(lhs * rhs),
-        BuiltinOp::Div => Value::IntLiteral(lhs / rhs),
-        BuiltinOp::Rem => Value::IntLiteral(lhs % rhs),
+        BuiltinOp::Add => Value::IntLiteral(lhs.wrapping_add(rhs)),
+        BuiltinOp::Sub => Value::IntLiteral(lhs.wrapping_sub(rhs)),
+        BuiltinOp::Mul => Value::IntLiteral(lhs.wrapping_mul(rhs)),
+        BuiltinOp::Div | BuiltinOp::IntDiv if rhs == 0 => Value::IntLiteral(0),
+        BuiltinOp::Div | BuiltinOp::IntDiv => Value::IntLiteral(lhs.wrapping_div(rhs)),
+        BuiltinOp::Rem if rhs == 0 => Value::IntLiteral(0),
+        BuiltinOp::Rem => Value::IntLiteral(lhs.wrapping_rem(rhs)),
+        BuiltinOp::Pow => Value::IntLiteral(if rhs >= 0 {
+            lhs.wrapping_pow(rhs as u32)
+        } else {
+            1i32.checked_div(lhs.wrapping_pow(rhs.unsigned_abs()))
+                .unwrap_or(0)
+        }),
 
         BuiltinOp::Min => Value::IntLiteral(lhs.min(rhs)),
         BuiltinOp::Max => Value::IntLiteral(lhs.max(rhs)),
@@ -30,7 +43,7 @@ fn int_op(op: BuiltinOp, lhs: i32, rhs: i32) -> Value {
         BuiltinOp::Or => Value::IntLiteral(lhs | rhs),
         BuiltinOp::Xor => Value::IntLiteral(lhs ^ rhs),
 
-        BuiltinOp::Not => unreachable!(),
+        BuiltinOp::Neg | BuiltinOp::Not => unreachable!(),
         BuiltinOp::Typeof => unreachable!(),
         BuiltinOp::Cast => unreachable!(),
     }
@@ -42,7 +55,9 @@ fn float_op(op: BuiltinOp, lhs: f32, rhs: f32) -> Value {
         BuiltinOp::Sub => Value::FloatLiteral(lhs - rhs),
         BuiltinOp::Mul => Value::FloatLiteral(lhs * rhs),
         BuiltinOp::Div => Value::FloatLiteral(lhs / rhs),
+        BuiltinOp::IntDiv => unreachable!(),
         BuiltinOp::Rem => Value::FloatLiteral(lhs % rhs),
+        BuiltinOp::Pow => Value::FloatLiteral(lhs.powf(rhs)),
 
         BuiltinOp::Min => Value::FloatLiteral(lhs.min(rhs)),
         BuiltinOp::Max => Value::FloatLiteral(lhs.max(rhs)),
@@ -58,19 +73,36 @@ fn float_op(op: BuiltinOp, lhs: f32, rhs: f32) -> Value {
         BuiltinOp::Or => unreachable!(),
         BuiltinOp::Xor => unreachable!(),
 
-        BuiltinOp::Not => unreachable!(),
+        BuiltinOp::Neg | BuiltinOp::Not => unreachable!(),
         BuiltinOp::Typeof => unreachable!(),
         BuiltinOp::Cast => unreachable!(),
     }
 }
 
+fn unary_op(op: BuiltinOp, operand: &Value) -> Option<Value> {
+    match (op, operand) {
+        (BuiltinOp::Neg, &Value::IntLiteral(value)) => {
+            Some(Value::IntLiteral(value.wrapping_neg()))
+        }
+        (BuiltinOp::Neg, &Value::FloatLiteral(value)) => Some(Value::FloatLiteral(-value)),
+        (BuiltinOp::Not, &Value::IntLiteral(value)) => Some(Value::IntLiteral(!value)),
+        (BuiltinOp::Not, &Value::BoolLiteral(value)) => Some(Value::BoolLiteral(!value)),
+        (_, Value::BuiltinType(typee)) => {
+            Some(calculate_type_arithmetic(op, std::slice::from_ref(typee)))
+        }
+        _ => None,
+    }
+}
+
 fn bool_op(op: BuiltinOp, lhs: bool, rhs: bool) -> Value {
     match op {
         BuiltinOp::Add => unreachable!(),
         BuiltinOp::Sub => unreachable!(),
         BuiltinOp::Mul => unreachable!(),
         BuiltinOp::Div => unreachable!(),
+        BuiltinOp::IntDiv => unreachable!(),
         BuiltinOp::Rem => unreachable!(),
+        BuiltinOp::Pow => unreachable!(),
 
         BuiltinOp::Min => Value::BoolLiteral(lhs.min(rhs)),
         BuiltinOp::Max => Value::BoolLiteral(lhs.max(rhs)),
@@ -79,23 +111,100 @@ fn bool_op(op: BuiltinOp, lhs: bool, rhs: bool) -> Value {
         BuiltinOp::Lt => unreachable!(),
         BuiltinOp::Gte => unreachable!(),
         BuiltinOp::Lte => unreachable!(),
-        BuiltinOp::Eq => unreachable!(),
-        BuiltinOp::Neq => unreachable!(),
+        BuiltinOp::Eq => Value::BoolLiteral(lhs == rhs),
+        BuiltinOp::Neq => Value::BoolLiteral(lhs != rhs),
 
         BuiltinOp::And => Value::BoolLiteral(lhs & rhs),
         BuiltinOp::Or => Value::BoolLiteral(lhs | rhs),
         BuiltinOp::Xor => Value::BoolLiteral(lhs ^ rhs),
 
-        BuiltinOp::Not => unreachable!(),
+        BuiltinOp::Neg | BuiltinOp::Not => unreachable!(),
         BuiltinOp::Typeof => unreachable!(),
         BuiltinOp::Cast => unreachable!(),
     }
 }
 
+/// How many iterations loops may be unrolled into unless configured otherwise.
+pub const DEFAULT_UNROLL_LIMIT: usize = 4096;
+
+/// How many calls may be inlined inside each other unless configured
+/// otherwise.
+pub const DEFAULT_RECURSION_LIMIT: usize = 64;
+
 #[derive(Clone, Debug)]
 pub struct SimplificationContext {
     previous_blocks: Vec<HashMap<LocalPtr, ValuePtr>>,
     current_block: HashMap<LocalPtr, ValuePtr>,
+    errors: Vec<CompileError>,
+    /// Spans of the calls currently being inlined, outermost first.
+    call_stack: Vec<Span>,
+    /// The most iterations a loop may be unrolled into, counting the
+    /// iterations of the loops it is nested in.
+    pub unroll_limit: usize,
+    /// The most calls which may be inlined inside each other, which stops
+    /// functions which call themselves from being inlined forever.
+    pub recursion_limit: usize,
+    /// How many times the statements currently being simplified will be
+    /// repeated by the loops they are in.
+    enclosing_iterations: usize,
+    /// The template parameters of the functions currently being inlined.
+    templates: Vec<LocalPtr>,
+    /// True while simplifying a function where it is defined rather than where
+    /// it is called, when its inputs aren't known yet.
+    defining_function: bool,
+    /// Function bodies which have already been simplified, shared with every
+    /// context cloned from this one.
+    call_cache: Rc<RefCell<CallCache>>,
+}
+
+/// How often calls were able to reuse a function body which had already been
+/// simplified for another call with the same signature.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CallCacheStats {
+    pub hits: usize,
+    pub misses: usize,
+}
+
+/// What a call's argument contributes to the signature a function body is
+/// simplified for.
+#[derive(Clone, Debug, PartialEq)]
+enum ArgumentKey {
+    /// The argument is known at compile time, so its value can affect what the
+    /// body simplifies to.
+    Known(Value),
+    /// Only the type of the argument is known.
+    Unknown(Value),
+}
+
+/// The outputs of a function body simplified for one signature. Arguments
+/// which aren't known at compile time are stood in for by placeholder locals,
+/// which each call replaces with its own arguments.
+#[derive(Debug)]
+struct Specialization {
+    /// The inputs followed by the outputs of the function, which identify it.
+    function: Vec<LocalPtr>,
+    arguments: Vec<ArgumentKey>,
+    /// The values the caller gave the other locals the body refers to.
+    captures: Vec<(LocalPtr, Option<Value>)>,
+    enclosing_iterations: usize,
+    defining_function: bool,
+    /// Each placeholder and the index of the argument it stands in for.
+    placeholders: Vec<(usize, LocalPtr)>,
+    /// The locals declared by the copy of the body that was simplified.
+    locals: Vec<LocalPtr>,
+    outputs: Vec<ValuePtr>,
+}
+
+#[derive(Debug, Default)]
+struct CallCache {
+    specializations: Vec<Specialization>,
+    stats: CallCacheStats,
+}
+
+impl Default for SimplificationContext {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SimplificationContext {
@@ -103,20 +212,209 @@ impl SimplificationContext {
         Self {
             previous_blocks: Vec::new(),
             current_block: HashMap::new(),
+            errors: Vec::new(),
+            call_stack: Vec::new(),
+            unroll_limit: DEFAULT_UNROLL_LIMIT,
+            recursion_limit: DEFAULT_RECURSION_LIMIT,
+            enclosing_iterations: 1,
+            templates: Vec::new(),
+            defining_function: false,
+            call_cache: Default::default(),
         }
     }
 
+    /// Returns how many calls so far reused a cached function body and how
+    /// many had to simplify one.
+    pub fn call_cache_stats(&self) -> CallCacheStats {
+        self.call_cache.borrow().stats
+    }
+
     fn start_new_block(&mut self) {
         self.previous_blocks
             .push(std::mem::take(&mut self.current_block));
     }
 
-    pub fn finish(mut self) -> Vec<HashMap<LocalPtr, ValuePtr>> {
+    fn error(
+        &mut self,
+        kind: CompileErrorKind,
+        span: Option<Span>,
+        types: Vec<ValuePtr>,
+        locals: Vec<LocalPtr>,
+    ) {
+        let mut error = CompileError::new(kind, span, types, locals);
+        error.call_sites = self.call_stack.iter().rev().copied().collect();
+        self.errors.push(error);
+    }
+
+    /// Removes errors after the first `start` which are identical to an earlier
+    /// one after `start`.
+    fn remove_duplicate_errors(&mut self, start: usize) {
+        for error in self.errors.split_off(start) {
+            if !self.errors[start..].contains(&error) {
+                self.errors.push(error);
+            }
+        }
+    }
+
+    /// Reports each compile-time local among `locals` whose value isn't
+    /// completely known. Locals which haven't been assigned are skipped.
+    fn check_compile_time_values<'a>(&mut self, locals: impl IntoIterator<Item = &'a LocalPtr>) {
+        if self.defining_function {
+            return;
+        }
+        for local in locals {
+            let unknown = match self.current_block.get(local) {
+                Some(value) if local.compile_time_only => {
+                    let value = value.borrow();
+                    *value != Value::Malformed && !is_known(&value)
+                }
+                _ => false,
+            };
+            if unknown {
+                self.error(
+                    CompileErrorKind::UnknownCompileTimeValue,
+                    local.span,
+                    vec![],
+                    vec![local.ptr_clone()],
+                );
+            }
+        }
+    }
+
+    /// Returns the simplified values of every local, or every error that was
+    /// encountered if the program is invalid.
+    pub fn finish(mut self) -> Result<Vec<HashMap<LocalPtr, ValuePtr>>, Vec<CompileError>> {
+        let mut locals = self
+            .current_block
+            .keys()
+            .map(LocalPtr::ptr_clone)
+            .collect::<Vec<_>>();
+        locals.sort_by_key(|local| local.span.map(|span| (span.file, span.start)));
+        self.check_compile_time_values(&locals);
+        if !self.errors.is_empty() {
+            return Err(self.errors);
+        }
         self.start_new_block();
-        self.previous_blocks
+        Ok(self.previous_blocks)
+    }
+}
+
+/// Describes what happens when the elements of an array literal which are
+/// themselves array literals are merged into their parent.
+pub enum FlattenedArrayLiteral {
+    /// There is nothing to merge, or it is not yet known whether every element
+    /// will turn out to be an array literal.
+    Unchanged,
+    /// Every element was an array literal of the same shape.
+    Flattened {
+        elements: Vec<ValuePtr>,
+        dims: Vec<ValuePtr>,
+    },
+    /// Some elements have a different shape than others.
+    Ragged,
+}
+
+/// Merges nested array literals like `[[1, 2], [3, 4]]` into a single literal
+/// whose dims list the innermost dimension first.
+pub fn flatten_array_literal(elements: &[ValuePtr], dims: &[ValuePtr]) -> FlattenedArrayLiteral {
+    let mut inner_dims: Option<Vec<ValuePtr>> = None;
+    let mut new_elements = Vec::new();
+    let mut has_scalar_literal = false;
+    let mut has_unknown = false;
+    for element in elements {
+        match &*element.borrow() {
+            Value::ArrayLiteral {
+                elements: sub_elements,
+                dims: sub_dims,
+            } => {
+                if let Some(inner_dims) = &inner_dims {
+                    if inner_dims != sub_dims {
+                        return FlattenedArrayLiteral::Ragged;
+                    }
+                } else {
+                    inner_dims = Some(sub_dims.clone());
+                }
+                new_elements.extend(sub_elements.iter().map(ValuePtr::ptr_clone));
+            }
+            Value::IntLiteral(..) | Value::FloatLiteral(..) | Value::BoolLiteral(..) => {
+                has_scalar_literal = true
+            }
+            _ => has_unknown = true,
+        }
+    }
+    match inner_dims {
+        Some(..) if has_scalar_literal => FlattenedArrayLiteral::Ragged,
+        Some(inner_dims) if !has_unknown => FlattenedArrayLiteral::Flattened {
+            elements: new_elements,
+            dims: [inner_dims, dims.to_vec()].concat(),
+        },
+        _ => FlattenedArrayLiteral::Unchanged,
     }
 }
 
+fn is_malformed(typee: &ValuePtr) -> bool {
+    *typee.borrow() == Value::BuiltinType(BuiltinType::Malformed)
+}
+
+/// Returns true if the type refers to a local matching the predicate.
+fn refers_to(typee: &ValuePtr, predicate: &impl Fn(&LocalPtr) -> bool) -> bool {
+    let refers = |value: &ValuePtr| refers_to(value, predicate);
+    match &*typee.borrow() {
+        Value::Local(local) => predicate(local),
+        Value::BuiltinType(BuiltinType::Array { eltype, dims }) => {
+            refers(eltype) || dims.iter().any(refers)
+        }
+        Value::BuiltinType(BuiltinType::InSet { eltype, .. }) => refers(eltype),
+        Value::FunctionCall(base, args, _) => refers(base) || args.iter().any(refers),
+        _ => false,
+    }
+}
+
+/// Returns true if the type refers to a local whose value isn't known, such as
+/// a template parameter outside of a call.
+fn is_generic(typee: &ValuePtr) -> bool {
+    refers_to(typee, &|_| true)
+}
+
+/// Returns a simplified copy of a type in which any template parameters the
+/// calls being inlined have inferred are replaced by their values.
+fn resolve_type(ctx: &SimplificationContext, typee: &ValuePtr) -> ValuePtr {
+    let mut sub_ctx = SimplificationContext::new();
+    for (local, value) in &ctx.current_block {
+        if local.compile_time_only {
+            sub_ctx
+                .current_block
+                .insert(local.ptr_clone(), value.ptr_clone());
+        }
+    }
+    let typee = typee.deep_clone();
+    typee.check_and_simplify(&mut sub_ctx);
+    typee
+}
+
+/// Returns the locals that the given values directly refer to, so that errors
+/// about those values can mention them.
+fn referenced_locals(values: &[ValuePtr]) -> Vec<LocalPtr> {
+    values
+        .iter()
+        .filter_map(|value| match &*value.borrow() {
+            Value::Local(local) => Some(local.ptr_clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The type of a builtin operator taking values of the given types.
+fn builtin_function_type(inputs: &[BuiltinType], output: BuiltinType) -> Value {
+    Value::BuiltinType(BuiltinType::Function {
+        inputs: inputs
+            .iter()
+            .map(|input| ValuePtr::new(Value::BuiltinType(input.clone())))
+            .collect(),
+        outputs: vec![ValuePtr::new(Value::BuiltinType(output))],
+    })
+}
+
 impl ValuePtr {
     pub fn typee(&self) -> Value {
         match &*self.0.borrow() {
@@ -137,7 +435,9 @@ impl ValuePtr {
                 | BuiltinOp::Sub
                 | BuiltinOp::Mul
                 | BuiltinOp::Div
+                | BuiltinOp::IntDiv
                 | BuiltinOp::Rem
+                | BuiltinOp::Pow
                 | BuiltinOp::Min
                 | BuiltinOp::Max
                 | BuiltinOp::Gt
@@ -148,29 +448,67 @@ impl ValuePtr {
                 | BuiltinOp::Neq
                 | BuiltinOp::And
                 | BuiltinOp::Or
-                | BuiltinOp::Xor => todo!(),
-                BuiltinOp::Not | BuiltinOp::Typeof | BuiltinOp::Cast => todo!(),
+                | BuiltinOp::Xor => {
+                    builtin_function_type(&[BuiltinType::Any, BuiltinType::Any], BuiltinType::Any)
+                }
+                // Unary operators take one value of any type and give back
+                // a value whose type depends on it.
+                BuiltinOp::Neg | BuiltinOp::Not => {
+                    builtin_function_type(&[BuiltinType::Any], BuiltinType::Any)
+                }
+                BuiltinOp::Typeof => builtin_function_type(&[BuiltinType::Any], BuiltinType::Type),
+                // The type of a cast is its first argument, which is not
+                // known here.
+                BuiltinOp::Cast => {
+                    builtin_function_type(&[BuiltinType::Type, BuiltinType::Any], BuiltinType::Any)
+                }
             },
-            Value::ArrayLiteral {
-                elements: _,
-                dims: _,
-            } => todo!(),
+            Value::ArrayLiteral { elements, dims } => {
+                let mut eltype = Value::BuiltinType(BuiltinType::Bool);
+                for element in elements {
+                    eltype = biggest_common_type(&eltype, &element.typee());
+                }
+                // Elements which are arrays themselves add their own
+                // dimensions inside ours.
+                if let Value::BuiltinType(BuiltinType::Array {
+                    eltype: inner_eltype,
+                    dims: inner_dims,
+                }) = &eltype
+                {
+                    Value::BuiltinType(BuiltinType::Array {
+                        eltype: inner_eltype.ptr_clone(),
+                        dims: [inner_dims.clone(), dims.clone()].concat(),
+                    })
+                } else {
+                    Value::BuiltinType(BuiltinType::Array {
+                        eltype: ValuePtr::new(eltype),
+                        dims: dims.clone(),
+                    })
+                }
+            }
             Value::FloatLiteral(_) => Value::BuiltinType(BuiltinType::Float),
             Value::IntLiteral(_) => Value::BuiltinType(BuiltinType::Int),
             Value::BoolLiteral(_) => Value::BuiltinType(BuiltinType::Bool),
             Value::Local(local) => (local.typee.borrow()).clone(),
             Value::Function {
-                inputs: _,
-                outputs: _,
-                locals: _,
-                body: _,
-            } => todo!(),
-            Value::FunctionCall(base, args, _) => match &*base.borrow() {
+                inputs, outputs, ..
+            } => Value::BuiltinType(BuiltinType::Function {
+                inputs: inputs.iter().map(|input| input.typee.ptr_clone()).collect(),
+                outputs: outputs
+                    .iter()
+                    .map(|output| output.typee.ptr_clone())
+                    .collect(),
+            }),
+            Value::FunctionCall(base, args, output) => match &*base.borrow() {
                 Value::BuiltinOp(BuiltinOp::Add)
                 | Value::BuiltinOp(BuiltinOp::Sub)
                 | Value::BuiltinOp(BuiltinOp::Mul)
                 | Value::BuiltinOp(BuiltinOp::Div)
+                | Value::BuiltinOp(BuiltinOp::IntDiv)
                 | Value::BuiltinOp(BuiltinOp::Rem)
+                | Value::BuiltinOp(BuiltinOp::Pow)
+                | Value::BuiltinOp(BuiltinOp::Min)
+                | Value::BuiltinOp(BuiltinOp::Max)
                 | Value::BuiltinOp(BuiltinOp::Gt)
                 | Value::BuiltinOp(BuiltinOp::Lt)
                 | Value::BuiltinOp(BuiltinOp::Gte)
@@ -179,14 +517,43 @@ impl ValuePtr {
                 | Value::BuiltinOp(BuiltinOp::Neq)
                 | Value::BuiltinOp(BuiltinOp::And)
                 | Value::BuiltinOp(BuiltinOp::Or)
-                | Value::BuiltinOp(BuiltinOp::Xor) => Value::FunctionCall(
+                | Value::BuiltinOp(BuiltinOp::Xor)
+                | Value::BuiltinOp(BuiltinOp::Neg)
+                | Value::BuiltinOp(BuiltinOp::Not) => Value::FunctionCall(
                     base.ptr_clone(),
                     args.iter().map(|x| ValuePtr::new(x.typee())).collect(),
                     0,
                 ),
-                Value::BuiltinOp(BuiltinOp::Cast) => args[0].borrow().clone(),
-                _ => todo!(),
+                Value::BuiltinOp(BuiltinOp::Typeof) => Value::BuiltinType(BuiltinType::Type),
+                Value::BuiltinOp(BuiltinOp::Cast) => args
+                    .first()
+                    .map_or(Value::BuiltinType(BuiltinType::Malformed), |typee| {
+                        typee.borrow().clone()
+                    }),
+                _ => match base.typee() {
+                    Value::BuiltinType(BuiltinType::Function { outputs, .. }) => outputs
+                        .get(*output)
+                        .map_or(Value::BuiltinType(BuiltinType::Malformed), |typee| {
+                            typee.borrow().clone()
+                        }),
+                    base_type @ Value::BuiltinType(BuiltinType::Array { .. }) => {
+                        let index_types: Vec<_> = args.iter().map(ValuePtr::typee).collect();
+                        calculate_index_type(&base_type, &index_types)
+                    }
+                    // Until the function being called is known, neither is
+                    // what it returns.
+                    _ => Value::BuiltinType(BuiltinType::Any),
+                },
             },
+            Value::ElementRead { base, indices } => {
+                let index_types: Vec<_> = indices.iter().map(ValuePtr::typee).collect();
+                calculate_index_type(&base.typee(), &index_types)
+            }
+            Value::Select {
+                condition,
+                if_true,
+                if_false,
+            } => calculate_select_type(&condition.typee(), &if_true.typee(), &if_false.typee()),
         }
     }
 
@@ -227,32 +594,63 @@ impl ValuePtr {
             Value::ArrayLiteral { elements, dims } => {
                 elements.iter().for_each(|x| x.check_and_simplify(ctx));
                 dims.iter().for_each(|x| x.check_and_simplify(ctx));
-                None
+                match flatten_array_literal(elements, dims) {
+                    FlattenedArrayLiteral::Unchanged => None,
+                    FlattenedArrayLiteral::Flattened { elements, dims } => {
+                        Some(Value::ArrayLiteral { elements, dims })
+                    }
+                    FlattenedArrayLiteral::Ragged => {
+                        ctx.error(
+                            CompileErrorKind::RaggedArrayLiteral,
+                            self.span(),
+                            vec![],
+                            vec![],
+                        );
+                        Some(Value::Malformed)
+                    }
+                }
             }
             Value::Function {
                 inputs,
                 outputs,
                 locals,
+                templates,
                 body,
             } => {
                 let mut new_body = body.clone();
                 let mut new_ctx = ctx.clone();
+                new_ctx.defining_function = true;
                 for statement in body {
                     statement.check_and_simplify(&mut new_ctx);
                 }
+                ctx.errors = std::mem::take(&mut new_ctx.errors);
                 for (target, base) in &new_ctx.current_block {
-                    if !ctx.current_block.contains_key(target) {
+                    if !ctx.current_block.contains_key(target)
+                        && last_assignment(body, target) != Some(base.as_ptr())
+                    {
                         let base_type = ValuePtr::new(base.typee());
                         let mut sub_ctx = SimplificationContext::new();
                         base_type.check_and_simplify(&mut sub_ctx);
                         target.typee.check_and_simplify(&mut sub_ctx);
-                        if !type_a_is_compatible_with_type_b(&base_type, &target.typee) {
-                            panic!("Invalid assignment");
+                        // Types involving template parameters can only be
+                        // checked once a call tells us what they are.
+                        if !is_malformed(&base_type)
+                            && !is_generic(&base_type)
+                            && !is_generic(&target.typee)
+                            && !type_a_is_compatible_with_type_b(&base_type, &target.typee)
+                        {
+                            ctx.error(
+                                CompileErrorKind::InvalidAssignment,
+                                base.span(),
+                                vec![base_type, target.typee.ptr_clone()],
+                                vec![target.ptr_clone()],
+                            );
                         }
                         new_body.push(Statement::Assignment {
                             base: base.ptr_clone(),
                             index: None,
                             target: target.ptr_clone(),
+                            span: base.span(),
                         });
                     }
                 }
@@ -260,6 +658,7 @@ impl ValuePtr {
                     inputs: inputs.clone(),
                     outputs: outputs.clone(),
                     locals: locals.clone(),
+                    templates: templates.clone(),
                     body: new_body,
                 })
             }
@@ -267,7 +666,22 @@ impl ValuePtr {
                 base.check_and_simplify(ctx);
                 args.iter().for_each(|x| x.check_and_simplify(ctx));
                 if let Value::BuiltinOp(op) = &*base.borrow() {
-                    let new_value = if args.len() == 2 {
+                    let arity = match op {
+                        BuiltinOp::Neg | BuiltinOp::Not | BuiltinOp::Typeof => 1,
+                        _ => 2,
+                    };
+                    let new_value = if args.len() != arity {
+                        ctx.error(
+                            CompileErrorKind::WrongArgumentCount {
+                                expected: arity,
+                                found: args.len(),
+                            },
+                            self.span(),
+                            vec![],
+                            vec![],
+                        );
+                        Some(Value::Malformed)
+                    } else if args.len() == 2 {
                         let mut sub_ctx = SimplificationContext::new();
                         let mut args = args.clone().into_iter();
                         let lhs = args.next().unwrap();
@@ -278,16 +692,27 @@ impl ValuePtr {
                         rhs.check_and_simplify(ctx);
                         let rhs_type = ValuePtr::new(rhs.typee());
                         rhs_type.check_and_simplify(&mut sub_ctx);
+                        let mut valid = true;
                         if op == &BuiltinOp::Cast {
                             if !type_a_is_compatible_with_type_b(
                                 &lhs,
                                 &ValuePtr::new(Value::BuiltinType(BuiltinType::Type)),
                             ) {
-                                println!("{:#?}", lhs);
-                                panic!("lhs is not a type");
-                            }
-                            if !type_a_is_compatible_with_type_b(&rhs_type, &lhs) {
-                                panic!("rhs is not castable to lhs");
+                                ctx.error(
+                                    CompileErrorKind::CastToNonType,
+                                    self.span(),
+                                    vec![lhs_type.ptr_clone()],
+                                    referenced_locals(&[lhs.ptr_clone()]),
+                                );
+                                valid = false;
+                            } else if !type_a_is_compatible_with_type_b(&rhs_type, &lhs) {
+                                ctx.error(
+                                    CompileErrorKind::InvalidCast,
+                                    self.span(),
+                                    vec![lhs.ptr_clone(), rhs_type.ptr_clone()],
+                                    referenced_locals(&[rhs.ptr_clone()]),
+                                );
+                                valid = false;
                             }
                         } else {
                             let combined_type = if let (
@@ -308,38 +733,81 @@ impl ValuePtr {
                             };
                             let mut sub_ctx = SimplificationContext::new();
                             combined_type.check_and_simplify(&mut sub_ctx);
-                            if &*combined_type.borrow()
-                                == &Value::BuiltinType(BuiltinType::Malformed)
-                            {
-                                panic!("Invalid binary operation");
+                            if is_malformed(&combined_type) {
+                                // Operands which are already malformed have
+                                // had their errors reported elsewhere.
+                                if !is_malformed(&lhs_type) && !is_malformed(&rhs_type) {
+                                    ctx.error(
+                                        CompileErrorKind::InvalidOperation,
+                                        self.span(),
+                                        vec![lhs_type.ptr_clone(), rhs_type.ptr_clone()],
+                                        referenced_locals(&[lhs.ptr_clone(), rhs.ptr_clone()]),
+                                    );
+                                }
+                                valid = false;
                             }
                         }
-                        let res = match (&*lhs.borrow(), &*rhs.borrow()) {
-                            (&Value::IntLiteral(lhs), &Value::IntLiteral(rhs)) => {
-                                Some(int_op(*op, lhs, rhs))
-                            }
-                            (&Value::FloatLiteral(lhs), &Value::IntLiteral(rhs)) => {
-                                Some(float_op(*op, lhs, rhs as f32))
-                            }
-                            (&Value::IntLiteral(lhs), &Value::FloatLiteral(rhs)) => {
-                                Some(float_op(*op, lhs as f32, rhs))
-                            }
-                            (&Value::FloatLiteral(lhs), &Value::FloatLiteral(rhs)) => {
-                                Some(float_op(*op, lhs, rhs))
-                            }
-                            (&Value::BoolLiteral(lhs), &Value::BoolLiteral(rhs)) => {
-                                Some(bool_op(*op, lhs, rhs))
-                            }
-                            (Value::BuiltinType(lhs), Value::BuiltinType(rhs)) => {
-                                Some(calculate_type_arithmetic(*op, &[lhs.clone(), rhs.clone()]))
+                        if !valid {
+                            Some(Value::Malformed)
+                        } else {
+                            match (&*lhs.borrow(), &*rhs.borrow()) {
+                                (&Value::IntLiteral(lhs), &Value::IntLiteral(rhs)) => {
+                                    Some(int_op(*op, lhs, rhs))
+                                }
+                                (&Value::FloatLiteral(lhs), &Value::IntLiteral(rhs)) => {
+                                    Some(float_op(*op, lhs, rhs as f32))
+                                }
+                                (&Value::IntLiteral(lhs), &Value::FloatLiteral(rhs)) => {
+                                    Some(float_op(*op, lhs as f32, rhs))
+                                }
+                                (&Value::FloatLiteral(lhs), &Value::FloatLiteral(rhs)) => {
+                                    Some(float_op(*op, lhs, rhs))
+                                }
+                                (&Value::BoolLiteral(lhs), &Value::BoolLiteral(rhs)) => {
+                                    Some(bool_op(*op, lhs, rhs))
+                                }
+                                (Value::BuiltinType(lhs), Value::BuiltinType(rhs)) => Some(
+                                    calculate_type_arithmetic(*op, &[lhs.clone(), rhs.clone()]),
+                                ),
+                                _ => None,
                             }
-                            _ => None,
+                        }
+                    } else if args.len() == 1 && (op == &BuiltinOp::Neg || op == &BuiltinOp::Not) {
+                        let operand = &args[0];
+                        let operand_type = ValuePtr::new(operand.typee());
+                        operand_type.check_and_simplify(&mut SimplificationContext::new());
+                        let result_type = if let Value::BuiltinType(typee) = &*operand_type.borrow()
+                        {
+                            calculate_type_arithmetic(*op, std::slice::from_ref(typee))
+                        } else {
+                            Value::FunctionCall(
+                                ValuePtr::new(Value::BuiltinOp(*op)),
+                                vec![operand_type.ptr_clone()],
+                                0,
+                            )
                         };
-                        res
+                        let result_type = ValuePtr::new(result_type);
+                        result_type.check_and_simplify(&mut SimplificationContext::new());
+                        if is_malformed(&result_type) {
+                            if !is_malformed(&operand_type) {
+                                ctx.error(
+                                    CompileErrorKind::InvalidOperation,
+                                    self.span(),
+                                    vec![operand_type.ptr_clone()],
+                                    referenced_locals(&[operand.ptr_clone()]),
+                                );
+                            }
+                            Some(Value::Malformed)
+                        } else {
+                            unary_op(*op, &operand.borrow())
+                        }
                     } else if op == &BuiltinOp::Typeof {
                         let mut args = args.clone().into_iter();
                         let base = args.next().unwrap();
-                        Some(base.typee())
+                        let typee = ValuePtr::new(base.typee());
+                        typee.check_and_simplify(&mut SimplificationContext::new());
+                        let typee = typee.borrow().clone();
+                        Some(typee)
                     } else {
                         None
                     };
@@ -351,57 +819,69 @@ impl ValuePtr {
                 } else if let Value::Function {
                     inputs,
                     outputs,
+                    locals,
+                    templates,
                     body,
-                    ..
                 } = &*base.borrow()
                 {
-                    let mut new_ctx = ctx.clone();
-                    assert_eq!(args.len(), inputs.len(), "Incorrect number of arguments.");
-                    for (target, arg) in inputs.iter().zip(args.iter()) {
-                        let arg_type = ValuePtr::new(arg.typee());
-                        arg_type.check_and_simplify(&mut new_ctx);
-                        let target_type = target.typee.deep_clone();
-                        target_type.check_and_simplify(&mut new_ctx);
-                        if !type_a_is_compatible_with_type_b(&arg_type, &target_type) {
-                            println!("{:#?}", arg_type);
-                            println!("{:#?}", target_type);
-                            panic!("Invalid argument.");
-                        }
-                        new_ctx
-                            .current_block
-                            .insert(target.ptr_clone(), arg.ptr_clone());
-                    }
-                    for statement in body {
-                        statement.check_and_simplify(&mut new_ctx);
-                    }
-                    let mut results = Vec::new();
-                    for output in outputs {
-                        if let Some(result) = new_ctx.current_block.get(output) {
-                            results.push(result.ptr_clone());
-                        } else {
-                            panic!("Output not assigned in function body.");
-                        }
-                    }
-                    Some(
-                        results
-                            .into_iter()
-                            .skip(*output)
-                            .next()
-                            .unwrap()
-                            .borrow()
-                            .clone(),
-                    )
+                    let function = FunctionBody {
+                        inputs,
+                        outputs,
+                        locals,
+                        templates,
+                        body,
+                    };
+                    let call = Call {
+                        span: self.span(),
+                        args,
+                        output: *output,
+                    };
+                    Some(inline_call(ctx, &function, call))
+                } else if is_array(base) {
+                    // Calling an array reads from it.
+                    Some(simplify_element_read(ctx, self.span(), base, args))
                 } else {
                     Some(Value::FunctionCall(base.ptr_clone(), args.clone(), *output))
                 }
             }
-            Value::Local(local) => {
-                if let Some(value) = ctx.current_block.get(local) {
-                    Some(value.borrow().clone())
+            Value::ElementRead { base, indices } => {
+                base.check_and_simplify(ctx);
+                indices.iter().for_each(|x| x.check_and_simplify(ctx));
+                Some(simplify_element_read(ctx, self.span(), base, indices))
+            }
+            Value::Select {
+                condition,
+                if_true,
+                if_false,
+            } => {
+                condition.check_and_simplify(ctx);
+                let known_condition = match &*condition.borrow() {
+                    &Value::BoolLiteral(condition) => Some(condition),
+                    _ => None,
+                };
+                if let Some(known_condition) = known_condition {
+                    // Like the `if` macro, only the branch which is taken is
+                    // compiled.
+                    let taken = if known_condition { if_true } else { if_false };
+                    taken.check_and_simplify(ctx);
+                    let value = taken.borrow().clone();
+                    Some(value)
                 } else {
-                    None
+                    if_true.check_and_simplify(ctx);
+                    if_false.check_and_simplify(ctx);
+                    Some(simplify_select(
+                        ctx,
+                        self.span(),
+                        condition,
+                        if_true,
+                        if_false,
+                    ))
                 }
             }
+            Value::Local(local) => ctx
+                .current_block
+                .get(local)
+                .map(|value| value.borrow().clone()),
         };
         if let Some(new_val) = new_val {
             *self.borrow_mut() = new_val;
@@ -409,6 +889,886 @@ impl ValuePtr {
     }
 }
 
+/// Returns the value the last statement of a body which assigns to `target`
+/// gives it, if that statement assigns the whole local.
+fn last_assignment(body: &[Statement], target: &LocalPtr) -> Option<*const ()> {
+    body.iter().rev().find_map(|statement| match statement {
+        Statement::Assignment {
+            base,
+            index,
+            target: assigned,
+            ..
+        } if assigned == target => Some(index.as_ref().map_or(Some(base.as_ptr()), |_| None)),
+        _ => None,
+    })?
+}
+
+fn is_array(value: &ValuePtr) -> bool {
+    matches!(&*value.borrow(), Value::ArrayLiteral { .. })
+        || matches!(value.typee(), Value::BuiltinType(BuiltinType::Array { .. }))
+}
+
+/// Checks that the indices are valid for the array and reads the element or
+/// sub-array if the array and all the indices are known.
+fn simplify_element_read(
+    ctx: &mut SimplificationContext,
+    span: Option<Span>,
+    base: &ValuePtr,
+    indices: &[ValuePtr],
+) -> Value {
+    let base_type = ValuePtr::new(base.typee());
+    base_type.check_and_simplify(&mut SimplificationContext::new());
+    let dims = match &*base_type.borrow() {
+        Value::BuiltinType(BuiltinType::Array { dims, .. }) => dims.clone(),
+        Value::BuiltinType(BuiltinType::Any) => {
+            return Value::ElementRead {
+                base: base.ptr_clone(),
+                indices: indices.to_vec(),
+            }
+        }
+        Value::BuiltinType(BuiltinType::Malformed) => return Value::Malformed,
+        _ => {
+            ctx.error(
+                CompileErrorKind::IndexedNonArray,
+                span,
+                vec![base_type.ptr_clone()],
+                referenced_locals(&[base.ptr_clone()]),
+            );
+            return Value::Malformed;
+        }
+    };
+    if indices.len() > dims.len() {
+        ctx.error(
+            CompileErrorKind::TooManyIndices {
+                expected: dims.len(),
+                found: indices.len(),
+            },
+            span,
+            vec![base_type.ptr_clone()],
+            referenced_locals(&[base.ptr_clone()]),
+        );
+        return Value::Malformed;
+    }
+    let int = ValuePtr::new(Value::BuiltinType(BuiltinType::Int));
+    let mut known_indices = Vec::new();
+    // The first index selects along the last dimension.
+    for (index, dim) in indices.iter().zip(dims.iter().rev()) {
+        let index_type = ValuePtr::new(index.typee());
+        index_type.check_and_simplify(&mut SimplificationContext::new());
+        if is_malformed(&index_type) {
+            return Value::Malformed;
+        }
+        // Arrays of indices are used to gather several elements at once.
+        let scalar_index_type = match &*index_type.borrow() {
+            Value::BuiltinType(BuiltinType::Array { eltype, .. }) => eltype.ptr_clone(),
+            _ => index_type.ptr_clone(),
+        };
+        // Indices whose type isn't known yet, like template parameters, are
+        // checked once it is.
+        let unknown = *scalar_index_type.borrow() == Value::BuiltinType(BuiltinType::Any);
+        if !unknown && !type_a_is_compatible_with_type_b(&scalar_index_type, &int) {
+            ctx.error(
+                CompileErrorKind::InvalidIndex,
+                index.span(),
+                vec![index_type],
+                referenced_locals(&[index.ptr_clone()]),
+            );
+            return Value::Malformed;
+        }
+        if let (&Value::IntLiteral(index_value), &Value::IntLiteral(size)) =
+            (&*index.borrow(), &*dim.borrow())
+        {
+            if index_value < 0 || index_value >= size {
+                ctx.error(
+                    CompileErrorKind::IndexOutOfBounds {
+                        index: index_value,
+                        size: size as usize,
+                    },
+                    index.span(),
+                    vec![],
+                    vec![],
+                );
+                return Value::Malformed;
+            }
+            known_indices.push(index_value as usize);
+        }
+    }
+    let index_types: Vec<_> = indices.iter().map(ValuePtr::typee).collect();
+    let result_type = ValuePtr::new(calculate_index_type(&base_type.borrow(), &index_types));
+    result_type.check_and_simplify(&mut SimplificationContext::new());
+    if is_malformed(&result_type) {
+        // The only remaining way for this to happen is for arrays of indices
+        // to have shapes that can't be broadcast together.
+        ctx.error(
+            CompileErrorKind::InvalidIndex,
+            span,
+            index_types.into_iter().map(ValuePtr::new).collect(),
+            referenced_locals(indices),
+        );
+        return Value::Malformed;
+    }
+    if let Value::ArrayLiteral { elements, dims } = &*base.borrow() {
+        if let (Some(known_dims), true) = (known_dims(dims), known_indices.len() == indices.len()) {
+            let remaining = known_dims.len() - indices.len();
+            let size: usize = known_dims[..remaining].iter().product();
+            let mut offset = 0;
+            let mut stride = size;
+            for (index, dim) in known_indices.iter().rev().zip(&known_dims[remaining..]) {
+                offset += index * stride;
+                stride *= dim;
+            }
+            return if remaining == 0 {
+                elements[offset].borrow().clone()
+            } else {
+                Value::ArrayLiteral {
+                    elements: elements[offset..offset + size].to_vec(),
+                    dims: dims[..remaining].to_vec(),
+                }
+            };
+        }
+    }
+    Value::ElementRead {
+        base: base.ptr_clone(),
+        indices: indices.to_vec(),
+    }
+}
+
+/// Returns the size of every dimension if they are all known.
+fn known_dims(dims: &[ValuePtr]) -> Option<Vec<usize>> {
+    dims.iter()
+        .map(|dim| match &*dim.borrow() {
+            &Value::IntLiteral(dim) => Some(dim as usize),
+            _ => None,
+        })
+        .collect()
+}
+
+fn zero_of_type(typee: &Value) -> Option<Value> {
+    match typee {
+        Value::BuiltinType(BuiltinType::Int) => Some(Value::IntLiteral(0)),
+        Value::BuiltinType(BuiltinType::Float) => Some(Value::FloatLiteral(0.0)),
+        Value::BuiltinType(BuiltinType::Bool) => Some(Value::BoolLiteral(false)),
+        _ => None,
+    }
+}
+
+/// Reads every element of an array whose dims are known, innermost first.
+fn array_elements(array: &ValuePtr, dims: &[usize]) -> Vec<ValuePtr> {
+    if let Value::ArrayLiteral { elements, .. } = &*array.borrow() {
+        return elements.clone();
+    }
+    nd_index_iter(dims.iter().copied().rev().collect())
+        .map(|position| {
+            let indices = position
+                .into_iter()
+                .map(|index| ValuePtr::new(Value::IntLiteral(index as i32)))
+                .collect();
+            ValuePtr::new(Value::ElementRead {
+                base: array.ptr_clone(),
+                indices,
+            })
+        })
+        .collect()
+}
+
+/// Applies an indexed assignment to the value the target currently holds and
+/// returns the updated array. Elements which have never been written to are
+/// zero. When the index is wider than one element, the last index selects
+/// the first of that many consecutive elements, which are written from an
+/// array with one value for each or from a single value repeated.
+fn simplify_indexed_assignment(
+    ctx: &mut SimplificationContext,
+    span: Option<Span>,
+    target: &LocalPtr,
+    index: &Index,
+    base: &ValuePtr,
+) -> Value {
+    index.indices.iter().for_each(|x| x.check_and_simplify(ctx));
+    let width = index.width;
+    let current = ctx.current_block.get(target).map(ValuePtr::ptr_clone);
+    let array_type = if *target.typee.borrow() == Value::BuiltinType(BuiltinType::Any) {
+        ValuePtr::new(
+            current
+                .as_ref()
+                .map_or(Value::BuiltinType(BuiltinType::Any), ValuePtr::typee),
+        )
+    } else {
+        target.typee.ptr_clone()
+    };
+    array_type.check_and_simplify(&mut SimplificationContext::new());
+    let (eltype, dims) = match &*array_type.borrow() {
+        Value::BuiltinType(BuiltinType::Array { eltype, dims }) => {
+            (eltype.ptr_clone(), dims.clone())
+        }
+        Value::BuiltinType(BuiltinType::Malformed) => return Value::Malformed,
+        Value::BuiltinType(BuiltinType::Any) => {
+            ctx.error(
+                CompileErrorKind::DynamicIndexedAssignment,
+                span,
+                vec![],
+                vec![target.ptr_clone()],
+            );
+            return Value::Malformed;
+        }
+        _ => {
+            ctx.error(
+                CompileErrorKind::IndexedNonArray,
+                span,
+                vec![array_type.ptr_clone()],
+                vec![target.ptr_clone()],
+            );
+            return Value::Malformed;
+        }
+    };
+    let found = index.indices.len();
+    if found != dims.len() {
+        let expected = dims.len();
+        let kind = if found < expected {
+            CompileErrorKind::NotEnoughIndices { expected, found }
+        } else {
+            CompileErrorKind::TooManyIndices { expected, found }
+        };
+        ctx.error(
+            kind,
+            span,
+            vec![array_type.ptr_clone()],
+            vec![target.ptr_clone()],
+        );
+        return Value::Malformed;
+    }
+
+    let written_type = if width > 1 {
+        ValuePtr::new(Value::BuiltinType(BuiltinType::Array {
+            eltype: eltype.ptr_clone(),
+            dims: vec![ValuePtr::new(Value::IntLiteral(width as i32))],
+        }))
+    } else {
+        eltype.ptr_clone()
+    };
+    let base_type = ValuePtr::new(base.typee());
+    base_type.check_and_simplify(&mut SimplificationContext::new());
+    if is_malformed(&base_type) {
+        return Value::Malformed;
+    }
+    if !type_a_is_compatible_with_type_b(&base_type, &written_type) {
+        ctx.error(
+            CompileErrorKind::InvalidAssignment,
+            span,
+            vec![base_type, written_type],
+            vec![target.ptr_clone()],
+        );
+        return Value::Malformed;
+    }
+
+    let int = ValuePtr::new(Value::BuiltinType(BuiltinType::Int));
+    for index in &index.indices {
+        let index_type = ValuePtr::new(index.typee());
+        index_type.check_and_simplify(&mut SimplificationContext::new());
+        if is_malformed(&index_type) {
+            return Value::Malformed;
+        }
+        if !type_a_is_compatible_with_type_b(&index_type, &int) {
+            ctx.error(
+                CompileErrorKind::InvalidIndex,
+                index.span(),
+                vec![index_type],
+                referenced_locals(&[index.ptr_clone()]),
+            );
+            return Value::Malformed;
+        }
+    }
+    let (sizes, zero) = match (known_dims(&dims), zero_of_type(&eltype.borrow())) {
+        (Some(sizes), Some(zero)) => (sizes, zero),
+        _ => {
+            ctx.error(
+                CompileErrorKind::DynamicIndexedAssignment,
+                span,
+                vec![array_type.ptr_clone()],
+                vec![target.ptr_clone()],
+            );
+            return Value::Malformed;
+        }
+    };
+    let mut offset = 0;
+    let mut stride = 1;
+    // The last index selects along the first (innermost) dimension.
+    for (position, (index, &size)) in index.indices.iter().rev().zip(&sizes).enumerate() {
+        let index_value = if let &Value::IntLiteral(value) = &*index.borrow() {
+            value
+        } else {
+            ctx.error(
+                CompileErrorKind::DynamicIndexedAssignment,
+                index.span(),
+                vec![],
+                referenced_locals(&[index.ptr_clone()]),
+            );
+            return Value::Malformed;
+        };
+        let last_written = if position == 0 {
+            index_value.saturating_add(width as i32 - 1)
+        } else {
+            index_value
+        };
+        if index_value < 0 || last_written >= size as i32 {
+            ctx.error(
+                CompileErrorKind::IndexOutOfBounds {
+                    index: if index_value < 0 {
+                        index_value
+                    } else {
+                        last_written
+                    },
+                    size,
+                },
+                index.span(),
+                vec![],
+                vec![],
+            );
+            return Value::Malformed;
+        }
+        offset += index_value as usize * stride;
+        stride *= size;
+    }
+
+    let mut elements = if let Some(current) = &current {
+        array_elements(current, &sizes)
+    } else {
+        let size = sizes.iter().product();
+        (0..size).map(|_| ValuePtr::new(zero.clone())).collect()
+    };
+    if width > 1 {
+        let lanes = match &*base_type.borrow() {
+            Value::BuiltinType(BuiltinType::Array { dims, .. }) => {
+                let base_dims = known_dims(dims).unwrap_or_default();
+                array_elements(base, &base_dims)
+            }
+            _ => vec![base.ptr_clone()],
+        };
+        for lane in 0..width {
+            // A single value is repeated across every lane.
+            elements[offset + lane] = lanes[lane % lanes.len()].ptr_clone();
+        }
+    } else {
+        elements[offset] = base.ptr_clone();
+    }
+    Value::ArrayLiteral { elements, dims }
+}
+
+/// Checks that the condition is a Bool (or an array of them) and that the
+/// branches can be combined.
+fn simplify_select(
+    ctx: &mut SimplificationContext,
+    span: Option<Span>,
+    condition: &ValuePtr,
+    if_true: &ValuePtr,
+    if_false: &ValuePtr,
+) -> Value {
+    let condition_type = ValuePtr::new(condition.typee());
+    condition_type.check_and_simplify(&mut SimplificationContext::new());
+    let true_type = ValuePtr::new(if_true.typee());
+    true_type.check_and_simplify(&mut SimplificationContext::new());
+    let false_type = ValuePtr::new(if_false.typee());
+    false_type.check_and_simplify(&mut SimplificationContext::new());
+    if is_malformed(&condition_type) || is_malformed(&true_type) || is_malformed(&false_type) {
+        return Value::Malformed;
+    }
+    let condition_eltype = match &*condition_type.borrow() {
+        Value::BuiltinType(BuiltinType::Array { eltype, .. }) => eltype.ptr_clone(),
+        _ => condition_type.ptr_clone(),
+    };
+    let bool_type = ValuePtr::new(Value::BuiltinType(BuiltinType::Bool));
+    if !type_a_is_compatible_with_type_b(&condition_eltype, &bool_type) {
+        ctx.error(
+            CompileErrorKind::InvalidCondition,
+            condition.span(),
+            vec![condition_type],
+            referenced_locals(&[condition.ptr_clone()]),
+        );
+        return Value::Malformed;
+    }
+    let result_type = calculate_select_type(
+        &condition_type.borrow(),
+        &true_type.borrow(),
+        &false_type.borrow(),
+    );
+    if result_type == Value::BuiltinType(BuiltinType::Malformed) {
+        ctx.error(
+            CompileErrorKind::IncompatibleBranches,
+            span,
+            vec![true_type, false_type],
+            referenced_locals(&[if_true.ptr_clone(), if_false.ptr_clone()]),
+        );
+        return Value::Malformed;
+    }
+    Value::Select {
+        condition: condition.ptr_clone(),
+        if_true: if_true.ptr_clone(),
+        if_false: if_false.ptr_clone(),
+    }
+}
+
+struct Call<'a> {
+    span: Option<Span>,
+    args: &'a [ValuePtr],
+    output: usize,
+}
+
+/// Substitutes the arguments into the body of a function and returns the value
+/// of the requested output. Bodies are only simplified once for each signature
+/// the function is called with; later calls reuse the outputs of the first.
+fn inline_call(ctx: &mut SimplificationContext, function: &FunctionBody, call: Call) -> Value {
+    let inputs = function.inputs;
+    let args = call.args;
+    if args.len() != inputs.len() {
+        ctx.error(
+            CompileErrorKind::WrongArgumentCount {
+                expected: inputs.len(),
+                found: args.len(),
+            },
+            call.span,
+            vec![],
+            vec![],
+        );
+        return Value::Malformed;
+    }
+    if ctx.call_stack.len() >= ctx.recursion_limit {
+        let limit = ctx.recursion_limit;
+        ctx.error(
+            CompileErrorKind::RecursionLimitExceeded { limit },
+            call.span,
+            vec![],
+            vec![],
+        );
+        return Value::Malformed;
+    }
+    let arg_types = args
+        .iter()
+        .map(|arg| {
+            let arg_type = ValuePtr::new(arg.typee());
+            arg_type.check_and_simplify(&mut SimplificationContext::new());
+            arg_type
+        })
+        .collect::<Vec<_>>();
+    let arguments = args
+        .iter()
+        .zip(&arg_types)
+        .map(|(arg, arg_type)| {
+            if is_known(&arg.borrow()) {
+                ArgumentKey::Known(arg.borrow().clone())
+            } else {
+                ArgumentKey::Unknown(arg_type.borrow().clone())
+            }
+        })
+        .collect::<Vec<_>>();
+    let identity = inputs
+        .iter()
+        .chain(function.outputs)
+        .map(LocalPtr::ptr_clone)
+        .collect::<Vec<_>>();
+    let captures = captured_values(ctx, function);
+
+    let cache = Rc::clone(&ctx.call_cache);
+    let cached = cache
+        .borrow()
+        .specializations
+        .iter()
+        .find_map(|specialization| {
+            if specialization.function == identity
+                && specialization.arguments == arguments
+                && specialization.captures == captures
+                && specialization.enclosing_iterations == ctx.enclosing_iterations
+                && specialization.defining_function == ctx.defining_function
+            {
+                Some((
+                    specialization.outputs[call.output].ptr_clone(),
+                    specialization.placeholders.clone(),
+                    specialization.locals.clone(),
+                ))
+            } else {
+                None
+            }
+        });
+    if let Some((output, placeholders, locals)) = cached {
+        cache.borrow_mut().stats.hits += 1;
+        return instantiate(ctx, &output, &placeholders, &locals, args);
+    }
+    cache.borrow_mut().stats.misses += 1;
+
+    let mut placeholders = Vec::new();
+    let mut bound = Vec::new();
+    for (index, ((input, arg), key)) in inputs.iter().zip(args).zip(&arguments).enumerate() {
+        if let ArgumentKey::Unknown(_) = key {
+            let placeholder = LocalPtr::new(Local {
+                compile_time_only: false,
+                name: input.name.clone(),
+                typee: arg_types[index].ptr_clone(),
+                span: input.span,
+                doc: None,
+            });
+            bound.push(ValuePtr::new(Value::Local(placeholder.ptr_clone())));
+            placeholders.push((index, placeholder));
+        } else {
+            bound.push(arg.ptr_clone());
+        }
+    }
+    let previous_errors = ctx.errors.len();
+    let (results, locals) = simplify_body(ctx, function, &call, &arg_types, &bound);
+    if ctx.errors.len() > previous_errors {
+        if placeholders.is_empty() {
+            return results[call.output].borrow().clone();
+        }
+        // Simplify the body again with the real arguments so that the errors
+        // describe them rather than the placeholders.
+        ctx.errors.truncate(previous_errors);
+        let (results, _) = simplify_body(ctx, function, &call, &arg_types, args);
+        let value = results[call.output].borrow().clone();
+        return value;
+    }
+    let output = results[call.output].ptr_clone();
+    cache.borrow_mut().specializations.push(Specialization {
+        function: identity,
+        arguments,
+        captures,
+        enclosing_iterations: ctx.enclosing_iterations,
+        defining_function: ctx.defining_function,
+        placeholders: placeholders.clone(),
+        locals: locals.clone(),
+        outputs: results,
+    });
+    instantiate(ctx, &output, &placeholders, &locals, args)
+}
+
+/// The parts of a function needed to inline a call to it.
+struct FunctionBody<'a> {
+    inputs: &'a [LocalPtr],
+    outputs: &'a [LocalPtr],
+    locals: &'a [LocalPtr],
+    templates: &'a [LocalPtr],
+    body: &'a [Statement],
+}
+
+/// Returns every local the function declares, including its inputs, outputs
+/// and template parameters and the locals of loops and functions in its body.
+fn declared_locals(function: &FunctionBody) -> Vec<LocalPtr> {
+    let mut declared: Vec<LocalPtr> = Vec::new();
+    let mut declare = |local: &LocalPtr| {
+        if !declared.contains(local) {
+            declared.push(local.ptr_clone());
+        }
+    };
+    function
+        .inputs
+        .iter()
+        .chain(function.outputs)
+        .chain(function.locals)
+        .chain(function.templates)
+        .for_each(&mut declare);
+    for statement in function.body {
+        statement.visit_declared_locals(&mut declare);
+    }
+    declared
+}
+
+/// Simplifies a copy of a function body with its inputs bound to `bound`,
+/// returning the values of its outputs along with the locals the copy
+/// declares. Errors are reported against the arguments of the call.
+fn simplify_body(
+    ctx: &mut SimplificationContext,
+    function: &FunctionBody,
+    call: &Call,
+    arg_types: &[ValuePtr],
+    bound: &[ValuePtr],
+) -> (Vec<ValuePtr>, Vec<LocalPtr>) {
+    // Each call simplifies its own copy of the body, since what it simplifies
+    // to depends on the arguments. The copy declares fresh locals so that what
+    // they are bound to during this call can't be mistaken for what another
+    // call, possibly one nested inside this one, binds them to.
+    let renames = LocalPtr::fresh_copies(&declared_locals(function));
+    let rename = |locals: &[LocalPtr]| {
+        locals
+            .iter()
+            .map(|local| local.substituted(&renames))
+            .collect::<Vec<_>>()
+    };
+    let inputs = rename(function.inputs);
+    let outputs = rename(function.outputs);
+    let templates = rename(function.templates);
+    let locals = renames
+        .iter()
+        .map(|(local, _)| local.substituted(&renames))
+        .collect::<Vec<_>>();
+    let mut new_ctx = ctx.clone();
+    for template in &templates {
+        let value = match infer_template(template, &inputs, arg_types) {
+            Ok(Some(value)) => value,
+            // A parameter which no input mentions stays unknown.
+            Ok(None) => continue,
+            Err((kind, candidates)) => {
+                ctx.error(kind, call.span, candidates, vec![template.ptr_clone()]);
+                let results = vec![ValuePtr::new(Value::Malformed); outputs.len()];
+                return (results, locals);
+            }
+        };
+        new_ctx
+            .current_block
+            .insert(template.ptr_clone(), ValuePtr::new(value));
+    }
+    new_ctx.templates.extend(templates);
+    for ((target, arg), value) in inputs.iter().zip(call.args).zip(bound) {
+        let arg_type = ValuePtr::new(arg.typee());
+        arg_type.check_and_simplify(&mut new_ctx);
+        // Inputs are fresh copies, so their types can be resolved in place.
+        // This replaces locals holding types, including earlier inputs and
+        // template parameters, with their values for the rest of the body.
+        let target_type = target.typee.ptr_clone();
+        target_type.check_and_simplify(&mut new_ctx);
+        if !is_malformed(&arg_type) && !type_a_is_compatible_with_type_b(&arg_type, &target_type) {
+            new_ctx.error(
+                CompileErrorKind::InvalidArgument,
+                arg.span(),
+                vec![arg_type, target_type],
+                vec![target.ptr_clone()],
+            );
+        }
+        let unknown = *arg.borrow() != Value::Malformed && !is_known(&arg.borrow());
+        if target.compile_time_only && unknown && !new_ctx.defining_function {
+            new_ctx.error(
+                CompileErrorKind::UnknownCompileTimeValue,
+                arg.span(),
+                vec![],
+                vec![target.ptr_clone()],
+            );
+        }
+        check_compile_time_only_value(&mut new_ctx, target, arg, arg.span());
+        new_ctx
+            .current_block
+            .insert(target.ptr_clone(), value.ptr_clone());
+    }
+    new_ctx.call_stack.extend(call.span);
+    // Copies of values the body shares between statements find the same
+    // problems, which are only reported once.
+    let previous_errors = new_ctx.errors.len();
+    for statement in function.body {
+        statement
+            .substituted(&renames)
+            .check_and_simplify(&mut new_ctx);
+    }
+    new_ctx.check_compile_time_values(locals.iter().filter(|local| !inputs.contains(local)));
+    new_ctx.remove_duplicate_errors(previous_errors);
+    let mut results = Vec::new();
+    for output_local in &outputs {
+        if let Some(result) = new_ctx.current_block.get(output_local) {
+            results.push(result.ptr_clone());
+        } else {
+            new_ctx.error(
+                CompileErrorKind::OutputNotAssigned,
+                output_local.span,
+                vec![],
+                vec![output_local.ptr_clone()],
+            );
+            results.push(ValuePtr::new(Value::Malformed));
+        }
+    }
+    ctx.errors = new_ctx.errors;
+    (results, locals)
+}
+
+/// Reports a problem if a value which only exists at compile time is being
+/// stored in a local which isn't compile-time only.
+fn check_compile_time_only_value(
+    ctx: &mut SimplificationContext,
+    target: &LocalPtr,
+    value: &ValuePtr,
+    span: Option<Span>,
+) {
+    if ctx.defining_function || target.compile_time_only || !is_compile_time_only(&value.borrow()) {
+        return;
+    }
+    let value_type = ValuePtr::new(value.typee());
+    value_type.check_and_simplify(&mut SimplificationContext::new());
+    ctx.error(
+        CompileErrorKind::CompileTimeOnlyValue,
+        span,
+        vec![value_type],
+        vec![target.ptr_clone()],
+    );
+}
+
+/// Returns true if values like this one only exist at compile time.
+fn is_compile_time_only(value: &Value) -> bool {
+    matches!(
+        value,
+        Value::BuiltinType(_) | Value::BuiltinOp(_) | Value::Function { .. }
+    )
+}
+
+/// Returns true if the value is completely known at compile time, so that a
+/// function body can be simplified for that specific value.
+fn is_known(value: &Value) -> bool {
+    let all_known = |values: &[ValuePtr]| values.iter().all(|value| is_known(&value.borrow()));
+    match value {
+        Value::BuiltinType(BuiltinType::Array { eltype, dims }) => {
+            is_known(&eltype.borrow()) && all_known(dims)
+        }
+        Value::BuiltinType(BuiltinType::InSet { eltype, elements }) => {
+            is_known(&eltype.borrow()) && all_known(elements)
+        }
+        Value::BuiltinType(BuiltinType::Function { inputs, outputs }) => {
+            all_known(inputs) && all_known(outputs)
+        }
+        Value::BuiltinType(_)
+        | Value::BuiltinOp(_)
+        | Value::FloatLiteral(_)
+        | Value::IntLiteral(_)
+        | Value::BoolLiteral(_)
+        | Value::Function { .. } => true,
+        Value::ArrayLiteral { elements, .. } => all_known(elements),
+        _ => false,
+    }
+}
+
+/// Returns the caller's values for the locals a function body refers to other
+/// than its own inputs and template parameters, since they can change what the
+/// body simplifies to.
+fn captured_values(
+    ctx: &SimplificationContext,
+    function: &FunctionBody,
+) -> Vec<(LocalPtr, Option<Value>)> {
+    let inputs = function.inputs;
+    let templates = function.templates;
+    let mut referenced: Vec<LocalPtr> = Vec::new();
+    let mut visit = |local: &LocalPtr| {
+        if !referenced.contains(local) && !inputs.contains(local) && !templates.contains(local) {
+            referenced.push(local.ptr_clone());
+        }
+    };
+    for statement in function.body {
+        statement.visit_locals(&mut visit);
+    }
+    for local in inputs.iter().chain(function.outputs) {
+        local.typee.borrow().visit_locals(&mut visit);
+    }
+    referenced
+        .into_iter()
+        .map(|local| {
+            let value = ctx
+                .current_block
+                .get(&local)
+                .map(|value| value.borrow().clone());
+            (local, value)
+        })
+        .collect()
+}
+
+/// Makes a copy of a cached output for a particular call by replacing the
+/// placeholders in it with the call's arguments. Any of the body's locals it
+/// still refers to are given fresh copies, as if the body had been inlined
+/// again.
+fn instantiate(
+    ctx: &mut SimplificationContext,
+    output: &ValuePtr,
+    placeholders: &[(usize, LocalPtr)],
+    locals: &[LocalPtr],
+    args: &[ValuePtr],
+) -> Value {
+    let mut substitutions = placeholders
+        .iter()
+        .map(|(index, placeholder)| (placeholder.ptr_clone(), args[*index].ptr_clone()))
+        .collect::<Vec<_>>();
+    let has_arguments = !substitutions.is_empty();
+    substitutions.extend(LocalPtr::fresh_copies(locals));
+    let value = output.substituted(&substitutions);
+    if has_arguments {
+        // Reading an element from an argument which is an array literal, for
+        // example, can only be simplified now that the argument is in place.
+        value.check_and_simplify(ctx);
+    }
+    let value = value.borrow().clone();
+    value
+}
+
+/// Works out the value of a template parameter from the arguments given for
+/// the inputs whose types mention it. Types are combined the same way the
+/// operands of an arithmetic operator are broadcast together, while sizes must
+/// all be the same. If the candidates disagree, the error to report is
+/// returned along with every candidate.
+fn infer_template(
+    template: &LocalPtr,
+    inputs: &[LocalPtr],
+    arg_types: &[ValuePtr],
+) -> Result<Option<Value>, (CompileErrorKind, Vec<ValuePtr>)> {
+    let mut candidates = Vec::new();
+    for (input, arg_type) in inputs.iter().zip(arg_types) {
+        match_template(template, &input.typee, arg_type, &mut candidates);
+    }
+    let first = match candidates.first() {
+        Some(first) => first.borrow().clone(),
+        None => return Ok(None),
+    };
+    if let Value::BuiltinType(_) = first {
+        let mut value = first;
+        for candidate in &candidates[1..] {
+            value = broadcast_common_type(&value, &candidate.borrow());
+        }
+        if value == Value::BuiltinType(BuiltinType::Malformed) {
+            return Err((CompileErrorKind::TemplateConflict, candidates));
+        }
+        Ok(Some(value))
+    } else if candidates
+        .iter()
+        .all(|candidate| *candidate.borrow() == first)
+    {
+        Ok(Some(first))
+    } else {
+        let mut sizes = Vec::new();
+        for candidate in &candidates {
+            if let &Value::IntLiteral(size) = &*candidate.borrow() {
+                if !sizes.contains(&size) {
+                    sizes.push(size);
+                }
+            }
+        }
+        Err((CompileErrorKind::TemplateSizeConflict { sizes }, candidates))
+    }
+}
+
+/// Adds the parts of `actual` which line up with uses of the template
+/// parameter in `pattern` to the candidates for its value.
+fn match_template(
+    template: &LocalPtr,
+    pattern: &ValuePtr,
+    actual: &ValuePtr,
+    candidates: &mut Vec<ValuePtr>,
+) {
+    match (&*pattern.borrow(), &*actual.borrow()) {
+        (Value::Local(local), _) if local == template => candidates.push(actual.ptr_clone()),
+        (
+            Value::BuiltinType(BuiltinType::Array { eltype, dims }),
+            Value::BuiltinType(BuiltinType::Array {
+                eltype: actual_eltype,
+                dims: actual_dims,
+            }),
+        ) if actual_dims.len() >= dims.len() => {
+            // The dims of the pattern line up with the outermost (last) dims
+            // of the argument. Any dims inside those are part of the element
+            // type.
+            let inner = actual_dims.len() - dims.len();
+            for (dim, actual_dim) in dims.iter().zip(&actual_dims[inner..]) {
+                match_template(template, dim, actual_dim, candidates);
+            }
+            let actual_eltype = if inner == 0 {
+                actual_eltype.ptr_clone()
+            } else {
+                ValuePtr::new(Value::BuiltinType(BuiltinType::Array {
+                    eltype: actual_eltype.ptr_clone(),
+                    dims: actual_dims[..inner].to_vec(),
+                }))
+            };
+            match_template(template, eltype, &actual_eltype, candidates);
+        }
+        _ => (),
+    }
+}
+
 impl Statement {
     pub fn check_and_simplify(&self, ctx: &mut SimplificationContext) {
         match self {
@@ -416,36 +1776,113 @@ impl Statement {
                 base,
                 index,
                 target,
+                span,
             } => {
                 base.check_and_simplify(ctx);
-                if &*target.typee.borrow() != &Value::BuiltinType(BuiltinType::Any) {
-                    let base_type = ValuePtr::new(base.typee());
-                    let mut sub_ctx = SimplificationContext::new();
-                    base_type.check_and_simplify(&mut sub_ctx);
-                    target.typee.check_and_simplify(&mut sub_ctx);
-                    let target_typee = if let Some(index) = index {
-                        match &*target.typee.borrow() {
-                            Value::BuiltinType(BuiltinType::Array { eltype, dims }) => {
-                                if index.indices.len() < dims.len() {
-                                    panic!("Not enough indices in assignment");
-                                }
-                                eltype.ptr_clone()
-                            }
-                            _ => todo!(),
+                let value = if let Some(index) = index {
+                    ValuePtr::new(simplify_indexed_assignment(ctx, *span, target, index, base))
+                } else {
+                    if *target.typee.borrow() != Value::BuiltinType(BuiltinType::Any) {
+                        let base_type = ValuePtr::new(base.typee());
+                        base_type.check_and_simplify(&mut SimplificationContext::new());
+                        let target_type = resolve_type(ctx, &target.typee);
+                        if !is_malformed(&base_type)
+                            && !is_generic(&base_type)
+                            && !is_generic(&target_type)
+                            && !type_a_is_compatible_with_type_b(&base_type, &target_type)
+                        {
+                            ctx.error(
+                                CompileErrorKind::InvalidAssignment,
+                                *span,
+                                vec![base_type, target_type],
+                                vec![target.ptr_clone()],
+                            );
                         }
-                    } else {
-                        target.typee.ptr_clone()
-                    };
-                    if !type_a_is_compatible_with_type_b(&base_type, &target_typee) {
-                        println!("{:#?} => {:#?}", base_type, target.typee);
-                        panic!("Invalid assignment");
                     }
+                    check_compile_time_only_value(ctx, target, base, base.span());
+                    base.ptr_clone()
+                };
+                ctx.current_block.insert(target.ptr_clone(), value);
+            }
+            Self::Declaration(local, _) => {
+                // Types mentioning template parameters are resolved separately
+                // for each call, so they are kept as they are.
+                if !refers_to(&local.typee, &|local| ctx.templates.contains(local)) {
+                    local.typee.check_and_simplify(ctx);
                 }
-                ctx.current_block
-                    .insert(target.ptr_clone(), base.ptr_clone());
             }
-            Self::Declaration(local) => {
-                local.typee.check_and_simplify(ctx);
+            Self::Repeat {
+                count,
+                counter,
+                locals,
+                body,
+                span,
+            } => {
+                count.check_and_simplify(ctx);
+                let iterations = match &*count.borrow() {
+                    &Value::IntLiteral(negative) if negative < 0 => {
+                        ctx.error(
+                            CompileErrorKind::NegativeRepeatCount { count: negative },
+                            count.span(),
+                            vec![],
+                            vec![],
+                        );
+                        return;
+                    }
+                    &Value::IntLiteral(count) => count as usize,
+                    Value::Malformed => return,
+                    _ => {
+                        let count_type = ValuePtr::new(count.typee());
+                        count_type.check_and_simplify(&mut SimplificationContext::new());
+                        ctx.error(
+                            CompileErrorKind::DynamicRepeatCount,
+                            count.span(),
+                            vec![count_type],
+                            referenced_locals(&[count.ptr_clone()]),
+                        );
+                        return;
+                    }
+                };
+                let enclosing_iterations = ctx.enclosing_iterations;
+                let total_iterations = iterations.saturating_mul(enclosing_iterations);
+                if total_iterations > ctx.unroll_limit {
+                    let limit = ctx.unroll_limit;
+                    ctx.error(
+                        CompileErrorKind::UnrollLimitExceeded {
+                            limit,
+                            iterations: total_iterations,
+                        },
+                        *span,
+                        vec![],
+                        vec![],
+                    );
+                    return;
+                }
+                ctx.enclosing_iterations = total_iterations;
+                let previous_errors = ctx.errors.len();
+                for iteration in 0..iterations {
+                    for local in locals {
+                        ctx.current_block.remove(local);
+                    }
+                    if let Some(counter) = counter {
+                        let value = ValuePtr::new(Value::IntLiteral(iteration as i32));
+                        ctx.current_block.insert(counter.ptr_clone(), value);
+                    }
+                    // Simplifying modifies values in place, so each iteration
+                    // needs its own copy of the body.
+                    for statement in body {
+                        statement.deep_clone().check_and_simplify(ctx);
+                    }
+                    ctx.check_compile_time_values(locals);
+                }
+                ctx.enclosing_iterations = enclosing_iterations;
+                // Each iteration finds the same problems with the body, so
+                // only report each one once.
+                ctx.remove_duplicate_errors(previous_errors);
+                // Nothing declared in the loop is visible after it.
+                for local in locals.iter().chain(counter) {
+                    ctx.current_block.remove(local);
+                }
             }
             Self::Noop => (),
         };
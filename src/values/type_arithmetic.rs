@@ -14,6 +14,22 @@ fn call(op: BuiltinOp, args: Vec<ValuePtr>) -> ValuePtr {
     ptr
 }
 
+fn is_comparison(op: BuiltinOp) -> bool {
+    matches!(
+        op,
+        BuiltinOp::Gt
+            | BuiltinOp::Lt
+            | BuiltinOp::Gte
+            | BuiltinOp::Lte
+            | BuiltinOp::Eq
+            | BuiltinOp::Neq
+    )
+}
+
+fn is_logical(op: BuiltinOp) -> bool {
+    matches!(op, BuiltinOp::And | BuiltinOp::Or | BuiltinOp::Xor)
+}
+
 pub fn calculate_type_arithmetic(op: BuiltinOp, values: &[BuiltinType]) -> Value {
     fn binary(values: &[BuiltinType]) -> (&BuiltinType, &BuiltinType) {
         assert_eq!(values.len(), 2);
@@ -27,7 +43,9 @@ pub fn calculate_type_arithmetic(op: BuiltinOp, values: &[BuiltinType]) -> Value
         | BuiltinOp::Sub
         | BuiltinOp::Mul
         | BuiltinOp::Div
+        | BuiltinOp::IntDiv
         | BuiltinOp::Rem
+        | BuiltinOp::Pow
         | BuiltinOp::Min
         | BuiltinOp::Max
         | BuiltinOp::Gt
@@ -43,9 +61,32 @@ pub fn calculate_type_arithmetic(op: BuiltinOp, values: &[BuiltinType]) -> Value
             match (lhs, rhs) {
                 (BuiltinType::Any, _) => BuiltinType::Any,
                 (_, BuiltinType::Any) => BuiltinType::Any,
+                (BuiltinType::Bool, BuiltinType::Bool) if is_logical(op) => BuiltinType::Bool,
+                (BuiltinType::Bool, BuiltinType::Bool)
+                    if op == BuiltinOp::Eq || op == BuiltinOp::Neq =>
+                {
+                    BuiltinType::Bool
+                }
+                (BuiltinType::Int, BuiltinType::Int) if is_comparison(op) => BuiltinType::Bool,
                 (BuiltinType::Int, BuiltinType::Int) => BuiltinType::Int,
                 (BuiltinType::Float, BuiltinType::Float)
                 | (BuiltinType::Int, BuiltinType::Float)
+                | (BuiltinType::Float, BuiltinType::Int)
+                    if is_comparison(op) =>
+                {
+                    BuiltinType::Bool
+                }
+                // Integer division and bitwise operations are only defined for
+                // integers.
+                (BuiltinType::Float, BuiltinType::Float)
+                | (BuiltinType::Int, BuiltinType::Float)
+                | (BuiltinType::Float, BuiltinType::Int)
+                    if op == BuiltinOp::IntDiv || is_logical(op) =>
+                {
+                    BuiltinType::Malformed
+                }
+                (BuiltinType::Float, BuiltinType::Float)
+                | (BuiltinType::Int, BuiltinType::Float)
                 | (BuiltinType::Float, BuiltinType::Int) => BuiltinType::Float,
                 (_, BuiltinType::Bool) | (BuiltinType::Bool, _) => BuiltinType::Malformed,
                 (_, BuiltinType::Function { .. }) | (BuiltinType::Function { .. }, _) => {
@@ -146,11 +187,41 @@ pub fn calculate_type_arithmetic(op: BuiltinOp, values: &[BuiltinType]) -> Value
                 (BuiltinType::Type, _) | (_, BuiltinType::Type) => BuiltinType::Malformed,
             }
         }
-        BuiltinOp::Not => {
+        BuiltinOp::Neg | BuiltinOp::Not => {
             assert_eq!(values.len(), 1);
-            let mut values = values.iter();
-            let base = values.next().unwrap();
-            base.clone()
+            match &values[0] {
+                BuiltinType::Any => BuiltinType::Any,
+                BuiltinType::Type => BuiltinType::Type,
+                BuiltinType::Int => BuiltinType::Int,
+                BuiltinType::Float if op == BuiltinOp::Neg => BuiltinType::Float,
+                BuiltinType::Bool if op == BuiltinOp::Not => BuiltinType::Bool,
+                BuiltinType::Float | BuiltinType::Bool => BuiltinType::Malformed,
+                // The operator is applied to every element.
+                BuiltinType::Array { eltype, dims } => {
+                    let eltype = call(op, vec![eltype.ptr_clone()]);
+                    if *eltype.borrow() == Value::BuiltinType(BuiltinType::Malformed) {
+                        return Value::BuiltinType(BuiltinType::Malformed);
+                    }
+                    BuiltinType::Array {
+                        eltype,
+                        dims: dims.clone(),
+                    }
+                }
+                BuiltinType::InSet { eltype, elements } => {
+                    let eltype = call(op, vec![eltype.ptr_clone()]);
+                    if *eltype.borrow() == Value::BuiltinType(BuiltinType::Malformed) {
+                        return Value::BuiltinType(BuiltinType::Malformed);
+                    }
+                    BuiltinType::InSet {
+                        eltype,
+                        elements: elements
+                            .iter()
+                            .map(|element| call(op, vec![element.ptr_clone()]))
+                            .collect(),
+                    }
+                }
+                BuiltinType::Function { .. } | BuiltinType::Malformed => BuiltinType::Malformed,
+            }
         }
         BuiltinOp::Typeof => BuiltinType::Type,
         BuiltinOp::Cast => {
@@ -160,6 +231,88 @@ pub fn calculate_type_arithmetic(op: BuiltinOp, values: &[BuiltinType]) -> Value
     Value::BuiltinType(typ)
 }
 
+/// Returns the type of the value produced by indexing into a value of the
+/// given type with indices of the given types, or `Malformed` if that is not
+/// possible. Indices which are arrays gather an element (or sub-array) for
+/// each of their elements, so their dimensions are added outside the
+/// dimensions of whatever is being read.
+pub fn calculate_index_type(base_type: &Value, index_types: &[Value]) -> Value {
+    let (eltype, dims) = match base_type {
+        Value::BuiltinType(BuiltinType::Array { eltype, dims }) => (eltype, dims),
+        Value::BuiltinType(BuiltinType::Any) => return Value::BuiltinType(BuiltinType::Any),
+        _ => return Value::BuiltinType(BuiltinType::Malformed),
+    };
+    if index_types.len() > dims.len() {
+        return Value::BuiltinType(BuiltinType::Malformed);
+    }
+    let mut gather_dims = Vec::new();
+    for index_type in index_types {
+        if let Value::BuiltinType(BuiltinType::Array {
+            dims: index_dims, ..
+        }) = index_type
+        {
+            if let Some(new_dims) = broadcast_array_dims(&gather_dims, index_dims) {
+                gather_dims = new_dims;
+            } else {
+                return Value::BuiltinType(BuiltinType::Malformed);
+            }
+        }
+    }
+    let result_dims = [&dims[..dims.len() - index_types.len()], &gather_dims[..]].concat();
+    if result_dims.is_empty() {
+        eltype.borrow().clone()
+    } else {
+        Value::BuiltinType(BuiltinType::Array {
+            eltype: eltype.ptr_clone(),
+            dims: result_dims,
+        })
+    }
+}
+
+/// Returns the simplest type that values of either type can be converted to,
+/// following the same rules as `type_a_is_compatible_with_type_b`.
+pub fn biggest_common_type(a: &Value, b: &Value) -> Value {
+    fn rank(typ: &BuiltinType) -> Option<u8> {
+        match typ {
+            BuiltinType::Bool => Some(0),
+            BuiltinType::Int => Some(1),
+            BuiltinType::Float => Some(2),
+            _ => None,
+        }
+    }
+    if a == b {
+        return a.clone();
+    }
+    let (a_type, b_type) = if let (Value::BuiltinType(a), Value::BuiltinType(b)) = (a, b) {
+        (a, b)
+    } else {
+        // We can't say anything useful about types that aren't known yet.
+        return Value::BuiltinType(BuiltinType::Any);
+    };
+    let typ = match (a_type, b_type) {
+        (BuiltinType::Any, _) | (_, BuiltinType::Any) => BuiltinType::Any,
+        (
+            BuiltinType::Array {
+                eltype: a_eltype,
+                dims: a_dims,
+            },
+            BuiltinType::Array {
+                eltype: b_eltype,
+                dims: b_dims,
+            },
+        ) if a_dims == b_dims => BuiltinType::Array {
+            eltype: ValuePtr::new(biggest_common_type(&a_eltype.borrow(), &b_eltype.borrow())),
+            dims: a_dims.clone(),
+        },
+        (a_type, b_type) => match (rank(a_type), rank(b_type)) {
+            (Some(a_rank), Some(b_rank)) if a_rank >= b_rank => a_type.clone(),
+            (Some(_), Some(_)) => b_type.clone(),
+            _ => BuiltinType::Malformed,
+        },
+    };
+    Value::BuiltinType(typ)
+}
+
 fn broadcast_array_type(
     eltype: ValuePtr,
     left_dims: &[ValuePtr],
@@ -188,12 +341,8 @@ pub fn broadcast_array_dims(
         right_dims.push(&one);
     }
     let mut new_dims = Vec::new();
-    for (left, right) in left_dims.into_iter().zip(right_dims.into_iter()) {
-        if let Some(dim) = broadcast_dim(left, right) {
-            new_dims.push(dim);
-        } else {
-            return None;
-        }
+    for (left, right) in left_dims.into_iter().zip(right_dims) {
+        new_dims.push(broadcast_dim(left, right)?);
     }
     Some(new_dims)
 }
@@ -214,13 +363,13 @@ fn broadcast_dim(left_value: &ValuePtr, right_value: &ValuePtr) -> Option<ValueP
                 (
                     Value::BuiltinType(BuiltinType::Int),
                     Value::BuiltinType(BuiltinType::InSet { elements, .. }),
-                ) if elements.len() == 1 && &*elements[0].borrow() == &Value::IntLiteral(1) => {
+                ) if elements.len() == 1 && *elements[0].borrow() == Value::IntLiteral(1) => {
                     Some(left_value.ptr_clone())
                 }
                 (
                     Value::BuiltinType(BuiltinType::InSet { elements, .. }),
                     Value::BuiltinType(BuiltinType::Int),
-                ) if elements.len() == 1 && &*elements[0].borrow() == &Value::IntLiteral(1) => {
+                ) if elements.len() == 1 && *elements[0].borrow() == Value::IntLiteral(1) => {
                     Some(right_value.ptr_clone())
                 }
                 (
@@ -287,3 +436,60 @@ fn broadcast_dim(left_value: &ValuePtr, right_value: &ValuePtr) -> Option<ValueP
         }
     }
 }
+
+/// Splits a type into its element type and dims, treating scalars as arrays
+/// with no dims. Returns `None` for `Any`, whose shape isn't known.
+fn split_array_type(typ: &Value) -> Option<(Value, Vec<ValuePtr>)> {
+    match typ {
+        Value::BuiltinType(BuiltinType::Array { eltype, dims }) => {
+            Some((eltype.borrow().clone(), dims.clone()))
+        }
+        Value::BuiltinType(BuiltinType::Any) => None,
+        other => Some((other.clone(), Vec::new())),
+    }
+}
+
+/// The inverse of `split_array_type`. Dims of `None` mean they couldn't be
+/// broadcast together, which makes the type malformed.
+fn join_array_type(eltype: Value, dims: Option<Vec<ValuePtr>>) -> Value {
+    match dims {
+        None => Value::BuiltinType(BuiltinType::Malformed),
+        Some(dims) if dims.is_empty() => eltype,
+        Some(dims) => Value::BuiltinType(BuiltinType::Array {
+            eltype: ValuePtr::new(eltype),
+            dims,
+        }),
+    }
+}
+
+/// Returns the type values of both types can be converted to once they are
+/// broadcast together, or `Malformed` if there is no such type.
+pub fn broadcast_common_type(a: &Value, b: &Value) -> Value {
+    let ((a_eltype, a_dims), (b_eltype, b_dims)) = match (split_array_type(a), split_array_type(b))
+    {
+        (Some(a), Some(b)) => (a, b),
+        _ => return Value::BuiltinType(BuiltinType::Any),
+    };
+    let eltype = biggest_common_type(&a_eltype, &b_eltype);
+    if eltype == Value::BuiltinType(BuiltinType::Malformed) {
+        return eltype;
+    }
+    join_array_type(eltype, broadcast_array_dims(&a_dims, &b_dims))
+}
+
+/// Returns the type of `if condition then if_true else if_false` given the
+/// types of its parts, or `Malformed` if the branches can't be combined. The
+/// condition and branches are broadcast together, so an array of conditions
+/// chooses each element separately.
+pub fn calculate_select_type(condition: &Value, if_true: &Value, if_false: &Value) -> Value {
+    let condition_dims = match split_array_type(condition) {
+        Some((_, dims)) => dims,
+        None => return Value::BuiltinType(BuiltinType::Any),
+    };
+    let branches = broadcast_common_type(if_true, if_false);
+    let (eltype, dims) = match split_array_type(&branches) {
+        Some((Value::BuiltinType(BuiltinType::Malformed), _)) | None => return branches,
+        Some(parts) => parts,
+    };
+    join_array_type(eltype, broadcast_array_dims(&dims, &condition_dims))
+}